@@ -0,0 +1,135 @@
+//! Who may use the control socket.
+//!
+//! The peer of a connection is known by SO_PEERCRED. Each command belongs to a [Class],
+//! and `[socket.allow]` lists the users and groups per class.
+//! Root and the user we run as may do anything.
+use crate::config::Allow;
+use crate::event::Control;
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::unistd::{getgrouplist, getuid, Gid, Group, Uid, User};
+use serde::Deserialize;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::net::UnixStream;
+
+/// What a command does
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Class {
+    /// look at the state
+    Read,
+    /// volume and mute
+    Volume,
+    /// outlets and inputs
+    Power,
+    /// send any CEC message
+    Cec,
+}
+
+impl Class {
+    pub fn of(c: &Control) -> Class {
+        match c {
+            Control::Volume(_) | Control::Mute(_) => Class::Volume,
+            Control::Outlet(..)
+            | Control::OutletTimer(..)
+            | Control::OutletCancel(_)
+            | Control::ActiveSource(_)
+            | Control::Input(_) => Class::Power,
+            Control::OutletStatus(_) | Control::Outlets | Control::State => Class::Read,
+            Control::Cec { .. } => Class::Cec,
+        }
+    }
+}
+
+/// the other end of a connection
+#[derive(Debug)]
+pub struct Peer {
+    pub uid: u32,
+    pub pid: i32,
+    /// primary and supplementary groups
+    pub gids: Vec<u32>,
+}
+
+impl Peer {
+    pub fn of(stream: &UnixStream) -> io::Result<Peer> {
+        let cred = getsockopt(stream, PeerCredentials)?;
+        Ok(Peer::new(cred.uid(), Gid::from_raw(cred.gid()), cred.pid()))
+    }
+    /// a process of user `uid`, like a D-Bus caller. In the primary group of the user
+    #[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+    pub fn of_uid(uid: u32, pid: i32) -> Peer {
+        let gid = User::from_uid(Uid::from_raw(uid))
+            .ok()
+            .flatten()
+            .map_or(Gid::from_raw(uid), |u| u.gid);
+        Peer::new(uid, gid, pid)
+    }
+    fn new(uid: u32, gid: Gid, pid: i32) -> Peer {
+        let gids = User::from_uid(Uid::from_raw(uid))
+            .ok()
+            .flatten()
+            .and_then(|u| getgrouplist(&CString::new(u.name).ok()?, gid).ok())
+            .unwrap_or_else(|| vec![gid]);
+        Peer {
+            uid,
+            pid,
+            gids: gids.into_iter().map(Gid::as_raw).collect(),
+        }
+    }
+    /// may it do things of class `c`?
+    pub fn may(&self, c: Class, allow: &Allow) -> bool {
+        if self.uid == 0 || self.uid == getuid().as_raw() {
+            return true;
+        }
+        let list = match c {
+            Class::Read => &allow.read,
+            Class::Volume => &allow.volume,
+            Class::Power => &allow.power,
+            Class::Cec => &allow.cec,
+        };
+        list.iter().any(|p| self.is(p))
+    }
+    /// `*`, a user or `@group`, by name or number
+    fn is(&self, principal: &str) -> bool {
+        if principal == "*" {
+            return true;
+        }
+        match principal.strip_prefix('@') {
+            Some(g) => g
+                .parse()
+                .ok()
+                .or_else(|| Some(Group::from_name(g).ok()??.gid.as_raw()))
+                .is_some_and(|g| self.gids.contains(&g)),
+            None => principal
+                .parse()
+                .ok()
+                .or_else(|| Some(User::from_name(principal).ok()??.uid.as_raw()))
+                .is_some_and(|u| u == self.uid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn principals() {
+        let allow = Allow {
+            read: vec!["*".into()],
+            volume: vec!["@4242".into()],
+            power: vec!["4343".into()],
+            cec: vec![],
+        };
+        let peer = |uid, gids| Peer { uid, pid: 1, gids };
+        let guest = peer(4444, vec![4444]);
+        assert!(guest.may(Class::Read, &allow));
+        assert!(!guest.may(Class::Volume, &allow));
+        let member = peer(4444, vec![4444, 4242]);
+        assert!(member.may(Class::Volume, &allow));
+        assert!(!member.may(Class::Power, &allow));
+        assert!(peer(4343, vec![]).may(Class::Power, &allow));
+        assert!(!peer(4343, vec![]).may(Class::Cec, &allow));
+        assert!(peer(0, vec![]).may(Class::Cec, &allow));
+    }
+}
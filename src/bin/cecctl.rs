@@ -0,0 +1,309 @@
+//! Command line client for the control socket of cecremote.
+//!
+//! Speaks the line protocol (see `src/protocol.rs`) and prints the answers
+//! for humans or, with `--json`, as JSON.
+use cecremote::SOCKET_PATH;
+use serde_json::Value;
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process::ExitCode;
+
+/// protocol version we speak
+const VERSION: u32 = 1;
+
+const USAGE: &str = "usage: cecctl [-s SOCKET] [-j] COMMAND
+
+options:
+  -s, --socket PATH   control socket. Default: $CECREMOTE_SOCKET or /tmp/cec
+  -j, --json          print JSON
+
+commands:
+  volume <1-100>                  set the AVR volume
+  mute [on|off|toggle]            mute or unmute the AVR
+  outlet [<n|name> [on|off|toggle] [in <time>]]
+                                  switch an outlet now or in a time like 30s, 10m or 2h,
+                                  or show its status. All of them without arguments
+  outlet <n|name> cancel          forget the timer of an outlet
+  source <port|name|address>      show port x of the AVR (3.x.0.0), an input of the config
+                                  or the device at an address like 3.2.0.0
+  state [field]                   what the daemon believes
+  follow [event...]               print events as they happen
+  cec <to> <opcode> [byte...] [reply <opcode> [ms]]
+                                  send a CEC message and maybe wait for the reply,
+                                  like: cec 5 GiveAudioStatus reply ReportAudioStatus";
+
+fn main() -> ExitCode {
+    let socket = env::var("CECREMOTE_SOCKET").unwrap_or_else(|_| SOCKET_PATH.to_string());
+    let args = match parse_args(env::args().skip(1), socket) {
+        Ok(a) => a,
+        Err(Usage::Help) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(Usage::Wrong) => return usage(),
+    };
+    let out = Output { json: args.json };
+    match run(&args.socket, &args.line, args.follow, &out) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Daemon(e)) => {
+            out.error(&e);
+            ExitCode::FAILURE
+        }
+        Err(Error::Io(e)) => {
+            out.error(&format!("{}: {}", args.socket, e));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// What the command line asks for
+#[derive(Debug, PartialEq)]
+struct Args {
+    socket: String,
+    json: bool,
+    /// to send
+    line: String,
+    /// print the events after the answer
+    follow: bool,
+}
+#[derive(Debug, PartialEq)]
+enum Usage {
+    Help,
+    Wrong,
+}
+
+/// the arguments without the program name. `socket` unless one is given
+fn parse_args(mut args: impl Iterator<Item = String>, mut socket: String) -> Result<Args, Usage> {
+    let mut json = false;
+    let mut command = Vec::new();
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "-s" | "--socket" if command.is_empty() => {
+                socket = args.next().ok_or(Usage::Wrong)?;
+            }
+            "-j" | "--json" if command.is_empty() => json = true,
+            "-h" | "--help" if command.is_empty() => return Err(Usage::Help),
+            _ => command.push(a),
+        }
+    }
+    let line = match command.first().map(String::as_str) {
+        Some("volume" | "mute" | "outlet" | "source" | "state" | "cec") => command.join(" "),
+        Some("follow") => ["subscribe"]
+            .into_iter()
+            .chain(command[1..].iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => return Err(Usage::Wrong),
+    };
+    Ok(Args {
+        socket,
+        json,
+        line,
+        follow: command[0] == "follow",
+    })
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::from(2)
+}
+
+#[derive(Debug)]
+enum Error {
+    /// the daemon answered with an error
+    Daemon(String),
+    Io(io::Error),
+}
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// say hello, send `line` and print the answer. Then the events if `follow`
+fn run(socket: &str, line: &str, follow: bool, out: &Output) -> Result<(), Error> {
+    let stream = UnixStream::connect(socket)?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+    writeln!(writer, "hello {}", VERSION)?;
+    response(&mut reader)?;
+    writeln!(writer, "{}", line)?;
+    let result = response(&mut reader)?;
+    if !follow {
+        out.result(&result);
+        return Ok(());
+    }
+    let mut event = String::new();
+    loop {
+        event.clear();
+        if reader.read_line(&mut event)? == 0 {
+            return Ok(());
+        }
+        if let Some((name, value)) = event
+            .trim_end()
+            .strip_prefix("event ")
+            .and_then(|e| e.split_once(' '))
+        {
+            out.event(name, value);
+        }
+    }
+}
+
+/// read `ok [result]` or `error <reason>`
+fn response(reader: &mut impl BufRead) -> Result<String, Error> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let line = line.trim_end();
+    match line.split_once(' ').unwrap_or((line, "")) {
+        ("ok", r) => Ok(r.to_string()),
+        ("error", e) => Err(Error::Daemon(e.to_string())),
+        _ => Err(Error::Daemon(format!("unexpected answer: {}", line))),
+    }
+}
+
+struct Output {
+    json: bool,
+}
+impl Output {
+    fn result(&self, r: &str) {
+        if !r.is_empty() {
+            println!("{}", self.format_result(r));
+        }
+    }
+    fn event(&self, name: &str, value: &str) {
+        println!("{}", self.format_event(name, value));
+    }
+    fn error(&self, e: &str) {
+        if self.json {
+            println!("{}", serde_json::json!({ "error": e }));
+        } else {
+            eprintln!("error: {}", e);
+        }
+    }
+    /// an object is a line per field
+    fn format_result(&self, r: &str) -> String {
+        let v = parse(r);
+        if self.json {
+            return v.to_string();
+        }
+        match v {
+            Value::Object(fields) => fields
+                .iter()
+                .map(|(k, v)| format!("{}: {}", k, human(v)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            v => human(&v),
+        }
+    }
+    fn format_event(&self, name: &str, value: &str) -> String {
+        let v = parse(value);
+        if self.json {
+            serde_json::json!({ "event": name, "value": v }).to_string()
+        } else {
+            format!("{}: {}", name, human(&v))
+        }
+    }
+}
+
+/// JSON, or text like `on`
+fn parse(s: &str) -> Value {
+    serde_json::from_str(s).unwrap_or_else(|_| Value::String(s.to_string()))
+}
+
+/// strings without quotes, `-` for unknown
+fn human(v: &Value) -> String {
+    match v {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Object(o) => o
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, human(v)))
+            .collect::<Vec<_>>()
+            .join(" "),
+        v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Args, Usage> {
+        parse_args(
+            line.split_whitespace().map(String::from),
+            SOCKET_PATH.into(),
+        )
+    }
+
+    #[test]
+    fn arguments() {
+        assert_eq!(
+            args("volume 30"),
+            Ok(Args {
+                socket: SOCKET_PATH.into(),
+                json: false,
+                line: "volume 30".into(),
+                follow: false,
+            })
+        );
+        assert_eq!(
+            args("-j --socket /run/cec follow state tv"),
+            Ok(Args {
+                socket: "/run/cec".into(),
+                json: true,
+                line: "subscribe state tv".into(),
+                follow: true,
+            })
+        );
+        // options only before the command
+        assert_eq!(args("outlet fan -j").unwrap().line, "outlet fan -j");
+        assert_eq!(args("follow").unwrap().line, "subscribe");
+        assert_eq!(args("-h"), Err(Usage::Help));
+        assert_eq!(args("-s"), Err(Usage::Wrong));
+        assert_eq!(args("-j"), Err(Usage::Wrong));
+        assert_eq!(args("reboot"), Err(Usage::Wrong));
+        assert_eq!(args(""), Err(Usage::Wrong));
+    }
+
+    #[test]
+    fn responses() {
+        let read = |s: &str| response(&mut io::Cursor::new(s.as_bytes()));
+        assert_eq!(read("ok 40\n").unwrap(), "40");
+        assert_eq!(read("ok\n").unwrap(), "");
+        assert!(matches!(read("error busy\n"), Err(Error::Daemon(e)) if e == "busy"));
+        assert!(matches!(read("what\n"), Err(Error::Daemon(e)) if e == "unexpected answer: what"));
+        assert!(matches!(read(""), Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn output() {
+        let human = Output { json: false };
+        let json = Output { json: true };
+        let state = r#"{"tv":true,"state":"Playing","phys_addr":null}"#;
+        assert_eq!(
+            human.format_result(state),
+            "phys_addr: -\nstate: Playing\ntv: true"
+        );
+        assert_eq!(
+            json.format_result(state),
+            r#"{"phys_addr":null,"state":"Playing","tv":true}"#
+        );
+        assert_eq!(human.format_result("on"), "on");
+        assert_eq!(json.format_result("on"), r#""on""#);
+        assert_eq!(
+            human.format_result(r#"{"3":{"name":"fan","on":true}}"#),
+            "3: name=fan on=true"
+        );
+        assert_eq!(
+            human.format_event("state", r#""Playing""#),
+            "state: Playing"
+        );
+        assert_eq!(
+            json.format_event("tv", "true"),
+            r#"{"event":"tv","value":true}"#
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use cec_linux::{
+    CecDevice, CecEvent, CecLogAddrs, CecLogicalAddress, CecModeFollower, CecModeInitiator, CecMsg,
+    CecOpcode, CecPhysicalAddress, CecUserControlCode, PollFlags, PollTimeout,
+};
+use nix::sys::eventfd::EventFd;
+use std::convert::TryFrom;
+use std::ops::Deref;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// how long the kernel waits for a reply by default
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A CEC message as seen on the bus
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// header, opcode and parameters
+    msg: Vec<u8>,
+}
+impl Frame {
+    pub fn new(from: CecLogicalAddress, to: CecLogicalAddress, opcode: u8, params: &[u8]) -> Frame {
+        let mut msg = Vec::with_capacity(2 + params.len());
+        msg.push(u8::from(from) << 4 | u8::from(to));
+        msg.push(opcode);
+        msg.extend_from_slice(params);
+        Frame { msg }
+    }
+    /// message with len=1
+    pub fn poll(from: CecLogicalAddress, to: CecLogicalAddress) -> Frame {
+        Frame {
+            msg: vec![u8::from(from) << 4 | u8::from(to)],
+        }
+    }
+    pub fn initiator(&self) -> CecLogicalAddress {
+        CecLogicalAddress::try_from(self.msg[0] >> 4).unwrap() // all values have a variant
+    }
+    pub fn destination(&self) -> CecLogicalAddress {
+        CecLogicalAddress::try_from(self.msg[0] & 0xf).unwrap() // all values have a variant
+    }
+    /// None for poll, Err for opcodes unknown to cec_linux
+    pub fn opcode(&self) -> Option<Result<CecOpcode, u8>> {
+        self.msg
+            .get(1)
+            .map(|&o| CecOpcode::try_from(o).map_err(|_| o))
+    }
+    pub fn parameters(&self) -> &[u8] {
+        self.msg.get(2..).unwrap_or_default()
+    }
+    pub fn is_broadcast(&self) -> bool {
+        self.destination() == CecLogicalAddress::UnregisteredBroadcast
+    }
+}
+impl From<CecMsg> for Frame {
+    fn from(m: CecMsg) -> Self {
+        match m.opcode() {
+            None => Frame::poll(m.initiator(), m.destination()),
+            Some(o) => Frame::new(
+                m.initiator(),
+                m.destination(),
+                o.map_or_else(|e| e.number, u8::from),
+                m.parameters(),
+            ),
+        }
+    }
+}
+
+/// Access to a CEC adapter.
+///
+/// Implemented by [Device] and the [virtual bus](crate::vbus)
+pub trait CecBus: Send {
+    fn set_mode(
+        &self,
+        initiator: CecModeInitiator,
+        follower: CecModeFollower,
+    ) -> std::io::Result<()>;
+    /// claim logical addresses. See [CecDevice::set_log]
+    fn set_log(&self, log: CecLogAddrs) -> std::io::Result<()>;
+    /// the claimed logical addresses
+    fn get_log(&self) -> std::io::Result<Vec<CecLogicalAddress>>;
+    /// set our physical address. Only works if the adapter has the `PHYS_ADDR` capability
+    fn set_phys(&self, phys_addr: CecPhysicalAddress) -> std::io::Result<()>;
+    fn transmit(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: CecOpcode,
+    ) -> std::io::Result<()> {
+        self.transmit_data(from, to, opcode, b"")
+    }
+    fn transmit_data(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: CecOpcode,
+        data: &[u8],
+    ) -> std::io::Result<()>;
+    /// send and wait for a reply with opcode `wait_for`. See [CecDevice::request_data]
+    fn request_data(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: CecOpcode,
+        data: &[u8],
+        wait_for: CecOpcode,
+    ) -> std::io::Result<Vec<u8>>;
+    /// send an opcode that cec_linux does not know, like `<Set Audio Volume Level>`
+    fn transmit_raw(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: u8,
+        data: &[u8],
+    ) -> std::io::Result<()>;
+    /// send an opcode that cec_linux does not know and wait up to `timeout` for a reply with opcode `wait_for`.
+    /// Unlike [request_data](CecBus::request_data) the whole reply is returned, as it may be a FeatureAbort
+    fn request_raw(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: u8,
+        data: &[u8],
+        wait_for: u8,
+        timeout: Duration,
+    ) -> std::io::Result<Frame>;
+    /// send a button press to a remote cec device
+    fn keypress(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        key: CecUserControlCode,
+    ) -> std::io::Result<()> {
+        self.transmit_data(from, to, CecOpcode::UserControlPressed, &[key.into()])?;
+        self.transmit(from, to, CecOpcode::UserControlReleased)
+    }
+    /// wake a remote cec device from standby
+    fn turn_on(&self, from: CecLogicalAddress, to: CecLogicalAddress) -> std::io::Result<()> {
+        if to == CecLogicalAddress::Tv {
+            self.transmit(from, to, CecOpcode::ImageViewOn)
+        } else {
+            self.keypress(from, to, CecUserControlCode::Power)
+        }
+    }
+    fn poll(&self, events: PollFlags, timeout: PollTimeout) -> std::io::Result<PollFlags>;
+    /// something that makes [poll](CecBus::poll) return with no flags, now and from then on. For stopping
+    fn waker(&self) -> std::io::Result<Waker>;
+    fn get_event(&self) -> std::io::Result<CecEvent>;
+    /// receive a single message. Block forever
+    fn rec(&self) -> std::io::Result<Frame>;
+}
+
+/// see [CecBus::waker]
+pub type Waker = Box<dyn FnOnce() + Send>;
+
+/// A [CecDevice] whose [poll](CecBus::poll) can be woken
+pub struct Device {
+    dev: CecDevice,
+    /// polled along with the device
+    wake: Arc<EventFd>,
+}
+impl Device {
+    pub fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<Device> {
+        Ok(Device {
+            dev: CecDevice::open(path)?,
+            wake: Arc::new(EventFd::new()?),
+        })
+    }
+}
+impl Deref for Device {
+    type Target = CecDevice;
+    fn deref(&self) -> &CecDevice {
+        &self.dev
+    }
+}
+
+impl CecBus for Device {
+    fn set_mode(
+        &self,
+        initiator: CecModeInitiator,
+        follower: CecModeFollower,
+    ) -> std::io::Result<()> {
+        CecDevice::set_mode(self, initiator, follower)
+    }
+    fn set_log(&self, log: CecLogAddrs) -> std::io::Result<()> {
+        CecDevice::set_log(self, log)
+    }
+    fn get_log(&self) -> std::io::Result<Vec<CecLogicalAddress>> {
+        CecDevice::get_log(self).map(|l| l.addresses().to_vec())
+    }
+    fn set_phys(&self, phys_addr: CecPhysicalAddress) -> std::io::Result<()> {
+        CecDevice::set_phys(self, phys_addr)
+    }
+    fn transmit(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: CecOpcode,
+    ) -> std::io::Result<()> {
+        CecDevice::transmit(self, from, to, opcode)
+    }
+    fn transmit_data(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: CecOpcode,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        CecDevice::transmit_data(self, from, to, opcode, data)
+    }
+    fn request_data(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: CecOpcode,
+        data: &[u8],
+        wait_for: CecOpcode,
+    ) -> std::io::Result<Vec<u8>> {
+        CecDevice::request_data(self, from, to, opcode, data, wait_for)
+    }
+    fn transmit_raw(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: u8,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        raw::transmit(self, from, to, opcode, data, 0, Duration::ZERO).map(|_| ())
+    }
+    fn request_raw(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: u8,
+        data: &[u8],
+        wait_for: u8,
+        timeout: Duration,
+    ) -> std::io::Result<Frame> {
+        raw::transmit(self, from, to, opcode, data, wait_for, timeout)
+    }
+    fn poll(&self, events: PollFlags, timeout: PollTimeout) -> std::io::Result<PollFlags> {
+        use nix::poll::{PollFd, PollFlags as Flags};
+        // cec_linux uses another version of nix
+        // SAFETY: the fd lives as long as self.dev
+        let dev = unsafe { BorrowedFd::borrow_raw(self.dev.as_raw_fd()) };
+        let mut fds = [
+            PollFd::new(dev, Flags::from_bits_truncate(events.bits())),
+            PollFd::new(self.wake.as_fd(), Flags::POLLIN),
+        ];
+        let timeout = nix::poll::PollTimeout::try_from(i32::from(timeout)).unwrap();
+        nix::poll::poll(&mut fds, timeout)?;
+        if fds[1].any() == Some(true) {
+            return Ok(PollFlags::empty());
+        }
+        Ok(PollFlags::from_bits_truncate(
+            fds[0].revents().map_or(0, |f| f.bits()),
+        ))
+    }
+    fn waker(&self) -> std::io::Result<Waker> {
+        let wake = Arc::clone(&self.wake);
+        // never read, so it stays readable
+        Ok(Box::new(move || {
+            let _ = wake.write(1);
+        }))
+    }
+    fn get_event(&self) -> std::io::Result<CecEvent> {
+        CecDevice::get_event(self)
+    }
+    fn rec(&self) -> std::io::Result<Frame> {
+        CecDevice::rec(self).map(Frame::from)
+    }
+}
+
+/// `CEC_TRANSMIT` with any opcode. [CecMsg] only takes a [CecOpcode]
+mod raw {
+    use super::Frame;
+    use cec_linux::{CecDevice, CecLogicalAddress, TxStatus};
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::time::Duration;
+
+    /// `struct cec_msg`
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct Msg {
+        tx_ts: u64,
+        rx_ts: u64,
+        len: u32,
+        timeout: u32,
+        sequence: u32,
+        flags: u32,
+        msg: [u8; 16],
+        reply: u8,
+        rx_status: u8,
+        tx_status: u8,
+        tx_arb_lost_cnt: u8,
+        tx_nack_cnt: u8,
+        tx_low_drive_cnt: u8,
+        tx_error_cnt: u8,
+    }
+    // the size is part of the ioctl number
+    const _: () = assert!(std::mem::size_of::<Msg>() == std::mem::size_of::<cec_linux::CecMsg>());
+    const RX_STATUS_OK: u8 = 1 << 0;
+    const RX_STATUS_FEATURE_ABORT: u8 = 1 << 2;
+
+    nix::ioctl_readwrite!(cec_transmit, b'a', 5, Msg);
+
+    /// send and, if `wait_for` is not 0, wait for the reply
+    pub fn transmit(
+        dev: &CecDevice,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: u8,
+        data: &[u8],
+        wait_for: u8,
+        timeout: Duration,
+    ) -> io::Result<Frame> {
+        if data.len() > 14 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let mut m = Msg {
+            len: 2 + data.len() as u32,
+            reply: wait_for,
+            // 0 would be the kernel default of 1s
+            timeout: (timeout.as_millis() as u32).max(1),
+            ..Default::default()
+        };
+        m.msg[0] = u8::from(from) << 4 | u8::from(to);
+        m.msg[1] = opcode;
+        m.msg[2..2 + data.len()].copy_from_slice(data);
+        unsafe { cec_transmit(dev.as_raw_fd(), &mut m) }?;
+        let tx = TxStatus::from_bits_truncate(m.tx_status);
+        if !tx.contains(TxStatus::OK) {
+            return Err(io::Error::other(format!("transmit failed: {:?}", tx)));
+        }
+        if wait_for != 0 && m.rx_status & (RX_STATUS_OK | RX_STATUS_FEATURE_ABORT) == 0 {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        Ok(Frame {
+            msg: m.msg[..m.len as usize].to_vec(),
+        })
+    }
+}
@@ -0,0 +1,695 @@
+use crate::access::Class;
+use cec_linux::CecPhysicalAddress;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// used if no path is given on the command line
+const DEFAULT_PATH: &str = "/etc/cecremote.toml";
+
+/// Everything that describes the setup this runs in
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub cec: Cec,
+    pub power: Power,
+    pub outlets: Outlets,
+    pub snapcast: Snapcast,
+    pub log: Log,
+    pub shutdown: Shutdown,
+    pub socket: Socket,
+    /// None: no HTTP API
+    pub http: Option<Http>,
+    /// None: no MQTT
+    pub mqtt: Option<Mqtt>,
+    /// None: no D-Bus service
+    pub dbus: Option<Dbus>,
+    /// named inputs for `source`: the physical address of the device to show
+    pub inputs: BTreeMap<String, PhysAddr>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cec {
+    /// CEC adapter to use
+    pub device: PathBuf,
+    /// name shown on the TV
+    pub osd_name: String,
+    /// physical address of this device. Set by the HDMI port we are connected to.
+    /// None: learn it from the kernel and the EDID
+    pub phys_addr: Option<PhysAddr>,
+    /// EDID to read our physical address from. None: search `/sys/class/drm`
+    pub edid: Option<PathBuf>,
+}
+impl Default for Cec {
+    fn default() -> Self {
+        Self {
+            device: PathBuf::from("/dev/cec0"),
+            osd_name: "pi4".to_string(),
+            phys_addr: None,
+            edid: None,
+        }
+    }
+}
+
+/// how outlets are switched
+#[derive(Deserialize, Debug, Default)]
+#[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Power {
+    /// Gembird SIS-PM USB outlets
+    #[default]
+    Sispm,
+    /// relays on GPIO lines
+    Gpio {
+        /// e.g. `/dev/gpiochip0`
+        chip: PathBuf,
+        /// line offsets. The first one is outlet 1
+        lines: Vec<u32>,
+        #[serde(default)]
+        active_low: bool,
+    },
+    /// smart plug with HTTP API
+    Http {
+        flavor: HttpFlavor,
+        /// `host` or `host:port`, `[v6]:port` for IPv6 addresses
+        host: String,
+        /// number of relays
+        #[serde(default = "one")]
+        outlets: u8,
+    },
+    /// nothing is switched
+    Mock {
+        #[serde(default = "four")]
+        outlets: u8,
+    },
+}
+fn one() -> u8 {
+    1
+}
+fn four() -> u8 {
+    4
+}
+impl Power {
+    /// number of outlets the backend has
+    pub fn outlets(&self) -> u8 {
+        match self {
+            Power::Sispm => 4,
+            Power::Gpio { lines, .. } => lines.len().min(u8::MAX as usize) as u8,
+            Power::Http { outlets, .. } | Power::Mock { outlets } => *outlets,
+        }
+    }
+}
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpFlavor {
+    /// `/cm?cmnd=Power1%20On`
+    Tasmota,
+    /// `/relay/0?turn=on`
+    Shelly,
+}
+
+/// numbers of the power sockets
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Outlets {
+    /// switched with the TV
+    pub light: u8,
+    /// powers the AVR
+    pub avr: u8,
+    /// more names for the control socket
+    pub names: BTreeMap<String, u8>,
+}
+impl Default for Outlets {
+    fn default() -> Self {
+        Self {
+            light: 1,
+            avr: 2,
+            names: BTreeMap::new(),
+        }
+    }
+}
+impl Outlets {
+    /// number of `light`, `avr` or one of [Outlets::names]
+    pub fn number(&self, name: &str) -> Option<u8> {
+        match name {
+            "light" => Some(self.light),
+            "avr" => Some(self.avr),
+            n => self.names.get(n).copied(),
+        }
+    }
+    /// the name of outlet `n`, if it has one
+    pub fn name(&self, n: u8) -> Option<&str> {
+        if n == self.light {
+            return Some("light");
+        }
+        if n == self.avr {
+            return Some("avr");
+        }
+        self.names
+            .iter()
+            .find(|(_, &m)| m == n)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Snapcast {
+    /// snapserver to connect to
+    pub server: SocketAddr,
+    /// snapclient binary
+    pub client: PathBuf,
+    /// additional arguments for snapclient.
+    /// `-h` and `-p` (`--host`, `--port`) are set to the MITM
+    pub args: Vec<String>,
+}
+impl Default for Snapcast {
+    fn default() -> Self {
+        Self {
+            server: SocketAddr::from(([127, 0, 0, 1], 1704)),
+            client: PathBuf::from("snapclient"),
+            args: ["--logsink", "system", "-s", "14", "--mixer", "none"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+/// what to leave running on exit
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Shutdown {
+    /// end the system audio mode
+    pub audio_mode_off: bool,
+    /// put the AVR in standby before its outlet is switched off
+    pub avr_standby: bool,
+    /// switch the outlet of the AVR off
+    pub avr_outlet_off: bool,
+    /// switch the light off
+    pub light_off: bool,
+    /// also do all this while the TV is on. It loses its sound then
+    pub when_tv_on: bool,
+}
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            audio_mode_off: true,
+            avr_standby: true,
+            avr_outlet_off: true,
+            light_off: false,
+            when_tv_on: false,
+        }
+    }
+}
+
+/// the control socket
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Socket {
+    /// user owning the socket file, by name or number. Only if we create it
+    pub owner: Option<String>,
+    /// group of the socket file
+    pub group: Option<String>,
+    /// permissions of the socket file. Connecting needs write permission.
+    /// None: as created, which depends on the umask
+    pub mode: Option<u32>,
+    pub allow: Allow,
+}
+
+/// Who may use which commands: `*`, users and `@groups`, by name or number.
+/// Root and the user we run as may do anything
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Allow {
+    /// `state`, `subscribe` and outlet status
+    pub read: Vec<String>,
+    /// `volume` and `mute`
+    pub volume: Vec<String>,
+    /// switch outlets and inputs
+    pub power: Vec<String>,
+    /// send any CEC message
+    pub cec: Vec<String>,
+}
+impl Default for Allow {
+    fn default() -> Self {
+        Self {
+            read: vec!["*".to_string()],
+            volume: vec!["*".to_string()],
+            power: Vec::new(),
+            cec: Vec::new(),
+        }
+    }
+}
+
+/// The HTTP API and the remote page. Needs the `http` feature
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Http {
+    /// `address:port` like `127.0.0.1:8080`, or the path of a Unix socket
+    pub listen: String,
+    /// what may be done. HTTP clients are not known, so this is for everyone
+    #[serde(default = "Http::default_allow")]
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub allow: Vec<Class>,
+    /// group of the Unix socket
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub group: Option<String>,
+    /// permissions of the Unix socket. None: as created, which depends on the umask
+    pub mode: Option<u32>,
+}
+impl Http {
+    fn default_allow() -> Vec<Class> {
+        vec![Class::Read, Class::Volume]
+    }
+    /// a Unix socket?
+    pub fn is_unix(&self) -> bool {
+        self.listen.starts_with('/')
+    }
+}
+
+/// MQTT with Home Assistant discovery. Needs the `mqtt` feature
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub struct Mqtt {
+    /// host of the broker
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// our client id. Also names the device in Home Assistant
+    pub client_id: String,
+    /// the topics are below this
+    pub prefix: String,
+    /// Home Assistant discovery prefix. Empty: no discovery
+    pub discovery: String,
+    /// what the command topics may do. The broker decides who may publish there
+    pub allow: Vec<Class>,
+}
+impl Default for Mqtt {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1883,
+            user: None,
+            password: None,
+            client_id: "cecremote".to_string(),
+            prefix: "cecremote".to_string(),
+            discovery: "homeassistant".to_string(),
+            allow: vec![Class::Volume, Class::Power],
+        }
+    }
+}
+
+/// The D-Bus service. Needs the `dbus` feature
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub struct Dbus {
+    pub bus: Bus,
+    /// well-known name to own. The system bus needs a policy that allows it
+    pub name: String,
+}
+impl Default for Dbus {
+    fn default() -> Self {
+        Self {
+            bus: Bus::System,
+            name: "org.cecremote".to_string(),
+        }
+    }
+}
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub enum Bus {
+    System,
+    Session,
+}
+
+/// log levels. Reloaded on SIGHUP
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    /// for the categories without an own level
+    pub level: LogLevel,
+    pub cec: Option<LogLevel>,
+    pub state: Option<LogLevel>,
+    pub snapcast: Option<LogLevel>,
+    pub socket: Option<LogLevel>,
+    pub power: Option<LogLevel>,
+}
+impl Log {
+    /// the level of each category
+    pub fn levels(&self) -> [(&'static str, LevelFilter); 5] {
+        [
+            ("cec", self.cec),
+            ("state", self.state),
+            ("snapcast", self.snapcast),
+            ("socket", self.socket),
+            ("power", self.power),
+        ]
+        .map(|(c, l)| (c, l.unwrap_or(self.level).0))
+    }
+}
+
+/// `error`, `warn`, `info`, `debug`, `trace` or `off`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct LogLevel(pub LevelFilter);
+impl Default for LogLevel {
+    fn default() -> Self {
+        LogLevel(LevelFilter::Info)
+    }
+}
+impl TryFrom<String> for LogLevel {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+            .map(LogLevel)
+            .map_err(|_| format!("\"{s}\" is not one of error, warn, info, debug, trace or off"))
+    }
+}
+
+/// A physical address like `3.3.0.0`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct PhysAddr(pub CecPhysicalAddress);
+impl TryFrom<String> for PhysAddr {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let mut num = 0u16;
+        let mut parts = 0;
+        for p in s.split('.') {
+            // from_str_radix would take a sign
+            let n = match p.chars().next().and_then(|c| c.to_digit(16)) {
+                Some(n) if p.len() == 1 => n,
+                _ => return Err(format!("\"{s}\" is not a physical address like 3.3.0.0")),
+            };
+            num = num << 4 | n as u16;
+            parts += 1;
+        }
+        if parts != 4 {
+            return Err(format!("\"{s}\" is not a physical address like 3.3.0.0"));
+        }
+        Ok(PhysAddr(CecPhysicalAddress::from_num(num)))
+    }
+}
+impl fmt::Display for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n = self.0.to_num();
+        write!(
+            f,
+            "{:x}.{:x}.{:x}.{:x}",
+            n >> 12,
+            n >> 8 & 0xf,
+            n >> 4 & 0xf,
+            n & 0xf
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Read(p, e) => write!(f, "could not read {}: {}", p.display(), e),
+            Error::Parse(p, e) => write!(f, "{}: {}", p.display(), e),
+            Error::Invalid(p, e) => write!(f, "{}: {}", p.display(), e),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+impl Config {
+    /// Load the config file given as first argument.
+    ///
+    /// Without an argument `/etc/cecremote.toml` is used - or the defaults if it does not exist.
+    pub fn load() -> Result<Config, Error> {
+        match std::env::args_os().nth(1) {
+            Some(p) => Self::from_file(Path::new(&p)),
+            None => match Self::from_file(Path::new(DEFAULT_PATH)) {
+                Err(Error::Read(_, e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                    log::info!("no {DEFAULT_PATH}, using defaults");
+                    Ok(Config::default())
+                }
+                r => r,
+            },
+        }
+    }
+    pub fn from_file(path: &Path) -> Result<Config, Error> {
+        let s = std::fs::read_to_string(path).map_err(|e| Error::Read(path.into(), e))?;
+        let cfg: Config = toml::from_str(&s).map_err(|e| Error::Parse(path.into(), e))?;
+        cfg.validate().map_err(|e| Error::Invalid(path.into(), e))?;
+        Ok(cfg)
+    }
+    /// the name of the input at an address like `3.2.0.0`
+    #[cfg_attr(not(any(feature = "mqtt", feature = "dbus")), allow(dead_code))]
+    pub fn input_name(&self, addr: &str) -> Option<&str> {
+        self.inputs
+            .iter()
+            .find(|(_, a)| a.to_string() == addr)
+            .map(|(name, _)| name.as_str())
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.cec.osd_name.is_empty() || self.cec.osd_name.len() > 14 {
+            return Err("cec.osd_name must have 1 to 14 characters".to_string());
+        }
+        if !self.cec.osd_name.is_ascii() {
+            return Err("cec.osd_name must be ASCII".to_string());
+        }
+        if self.cec.phys_addr == Some(PhysAddr(CecPhysicalAddress::INVALID)) {
+            return Err("cec.phys_addr f.f.f.f is invalid".to_string());
+        }
+        for (name, a) in &self.inputs {
+            if name.parse::<u8>().is_ok() || name.contains('.') {
+                return Err(format!(
+                    "inputs.{name}: names must not look like ports or addresses"
+                ));
+            }
+            if a.0 == CecPhysicalAddress::INVALID {
+                return Err(format!("inputs.{name}: f.f.f.f is invalid"));
+            }
+        }
+        let max = self.power.outlets();
+        for (name, n) in [("light", self.outlets.light), ("avr", self.outlets.avr)] {
+            if !(1..=max).contains(&n) {
+                return Err(format!(
+                    "outlets.{name} must be between 1 and {max}, not {n}"
+                ));
+            }
+        }
+        if self.outlets.light == self.outlets.avr {
+            return Err("outlets.light and outlets.avr must differ".to_string());
+        }
+        for (name, &n) in &self.outlets.names {
+            if name.parse::<u8>().is_ok() || matches!(name.as_str(), "light" | "avr") {
+                return Err(format!(
+                    "outlets.names.{name}: names must not be numbers, light or avr"
+                ));
+            }
+            if !(1..=max).contains(&n) {
+                return Err(format!(
+                    "outlets.names.{name} must be between 1 and {max}, not {n}"
+                ));
+            }
+        }
+        if self.socket.mode.is_some_and(|m| m > 0o777) {
+            return Err("socket.mode must be like 0o660".to_string());
+        }
+        let a = &self.socket.allow;
+        if let Some(p) = [&a.read, &a.volume, &a.power, &a.cec]
+            .into_iter()
+            .flatten()
+            .find(|p| p.is_empty() || p.as_str() == "@")
+        {
+            return Err(format!("socket.allow: \"{p}\" is not a user or group"));
+        }
+        if let Some(h) = self.http.as_ref().filter(|h| !h.is_unix()) {
+            if h.listen.parse::<SocketAddr>().is_err() {
+                return Err(format!(
+                    "http.listen: {} is not like 127.0.0.1:8080 or /run/cecremote.http",
+                    h.listen
+                ));
+            }
+            if h.group.is_some() || h.mode.is_some() {
+                return Err("http.group and http.mode are for Unix sockets".to_string());
+            }
+        }
+        if let Some(mode) = self.http.as_ref().and_then(|h| h.mode) {
+            if mode > 0o777 {
+                return Err("http.mode must be like 0o660".to_string());
+            }
+        }
+        if let Some(m) = &self.mqtt {
+            for (name, t) in [
+                ("client_id", &m.client_id),
+                ("prefix", &m.prefix),
+                ("discovery", &m.discovery),
+            ] {
+                if (t.is_empty() && name != "discovery")
+                    || t.contains(['+', '#'])
+                    || t.starts_with('/')
+                    || t.ends_with('/')
+                    || (name == "client_id" && t.contains('/'))
+                {
+                    return Err(format!("mqtt.{name}: \"{t}\" can not be used in topics"));
+                }
+            }
+        }
+        if let Some(a) = self.snapcast.args.iter().find(|a| sets_server(a)) {
+            return Err(format!(
+                "snapcast.args must not contain {a}. It is set to the MITM"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `-h`, `--host` or the port, also with the value like `-hX` or `--port=1705`.
+/// Not `--hostID`
+fn sets_server(arg: &str) -> bool {
+    arg.starts_with("-h")
+        || arg.starts_with("-p")
+        || ["--host", "--port"].iter().any(|o| {
+            arg.strip_prefix(o)
+                .is_some_and(|v| v.is_empty() || v.starts_with('='))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// parse and validate a config file
+    fn check(toml: &str) -> Result<Config, String> {
+        let cfg: Config = toml::from_str(toml).map_err(|e| e.message().to_string())?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    #[test]
+    fn phys_addr() {
+        let a = |s: &str| PhysAddr::try_from(s.to_string()).map(|a| a.to_string());
+        assert_eq!(a("3.3.0.0").as_deref(), Ok("3.3.0.0"));
+        assert_eq!(a("A.b.0.F").as_deref(), Ok("a.b.0.f"));
+        for bad in [
+            "",
+            "3.0.0",
+            "3.0.0.0.0",
+            "10.0.0.0",
+            "+3.0.0.0",
+            "3.-0.0.0",
+            "3..0.0",
+            "g.0.0.0",
+            "3.0.0.0 ",
+        ] {
+            assert!(a(bad).is_err(), "{bad:?}");
+        }
+        assert!(check("[cec]\nphys_addr = \"+3.0.0.0\"").is_err());
+        assert!(check("[cec]\nphys_addr = \"f.f.f.f\"").is_err());
+        assert!(check("[inputs]\npc = \"f.f.f.f\"").is_err());
+        let cfg = check("[cec]\nphys_addr = \"2.0.0.0\"\n[inputs]\npc = \"3.1.0.0\"").unwrap();
+        assert_eq!(cfg.input_name("3.1.0.0"), Some("pc"));
+    }
+
+    #[test]
+    fn outlets() {
+        assert!(check("").is_ok());
+        assert!(check("[outlets]\nlight = 4\navr = 3").is_ok());
+        let e = check("[outlets]\nlight = 5").unwrap_err();
+        assert_eq!(e, "outlets.light must be between 1 and 4, not 5");
+        assert!(check("[outlets]\navr = 0").is_err());
+        assert!(check("[outlets]\nlight = 2").is_err(), "same as avr");
+        assert!(check("[outlets.names]\nfan = 4").is_ok());
+        assert!(check("[outlets.names]\nfan = 5").is_err());
+        assert!(check("[outlets.names]\nfan = 0").is_err());
+        assert!(check("[outlets.names]\n3 = 3").is_err());
+        assert!(check("[outlets.names]\nlight = 3").is_err());
+        // the range follows the backend
+        assert!(
+            check("[power]\nbackend = \"mock\"\noutlets = 8\n[outlets.names]\nfan = 8").is_ok()
+        );
+        let two =
+            "[power]\nbackend = \"http\"\nflavor = \"shelly\"\nhost = \"plug\"\noutlets = 2\n";
+        assert!(check(two).is_ok());
+        assert!(check(&format!("{two}[outlets.names]\nfan = 3")).is_err());
+        let one = "[power]\nbackend = \"http\"\nflavor = \"tasmota\"\nhost = \"plug\"\n";
+        assert!(check(one).is_err(), "light and avr do not fit");
+    }
+
+    #[test]
+    fn log() {
+        let cfg = check("[log]\nlevel = \"warn\"\npower = \"debug\"").unwrap();
+        let levels = cfg.log.levels();
+        assert!(levels.contains(&("power", LevelFilter::Debug)));
+        assert!(levels.contains(&("cec", LevelFilter::Warn)));
+        assert!(
+            check("[log]\nmonitor = \"debug\"").is_err(),
+            "unknown category"
+        );
+        assert!(check("[log]\ncec = \"loud\"").is_err());
+    }
+
+    #[test]
+    fn socket() {
+        assert_eq!(
+            check("[socket]\nmode = 0o660").unwrap().socket.mode,
+            Some(0o660)
+        );
+        assert!(check("[socket]\nmode = 0o1777").is_err());
+        assert!(check("[socket]\nmode = -1").is_err());
+        assert!(check("[socket.allow]\npower = [\"@\"]").is_err());
+        assert!(check("[socket.allow]\npower = [\"\"]").is_err());
+        assert!(check("[socket.allow]\npower = [\"@audio\", \"pi\"]").is_ok());
+    }
+
+    #[test]
+    fn http() {
+        let unix = "[http]\nlisten = \"/run/cecremote.http\"\n";
+        let cfg = check(&format!("{unix}group = \"www-data\"\nmode = 0o660")).unwrap();
+        assert_eq!(cfg.http.unwrap().mode, Some(0o660));
+        assert!(check(&format!("{unix}mode = 0o1777")).is_err());
+        let tcp = "[http]\nlisten = \"127.0.0.1:8080\"\n";
+        assert!(check(tcp).is_ok());
+        assert!(check(&format!("{tcp}mode = 0o660")).is_err());
+        assert!(check(&format!("{tcp}group = \"www-data\"")).is_err());
+        assert!(check("[http]\nlisten = \"localhost\"").is_err());
+    }
+
+    #[test]
+    fn snapcast() {
+        let args = |a: &str| check(&format!("[snapcast]\nargs = [{a}]"));
+        assert!(args("\"--hostID\", \"pi\", \"--player\", \"alsa\"").is_ok());
+        for a in [
+            "-h",
+            "-h1.2.3.4",
+            "--host",
+            "--host=1.2.3.4",
+            "-p",
+            "-p1705",
+            "--port=1705",
+        ] {
+            let e = args(&format!("\"{a}\"")).unwrap_err();
+            assert_eq!(
+                e,
+                format!("snapcast.args must not contain {a}. It is set to the MITM")
+            );
+        }
+    }
+
+    #[test]
+    fn osd_name() {
+        assert!(check("[cec]\nosd_name = \"\"").is_err());
+        assert!(check("[cec]\nosd_name = \"fifteen chars!!\"").is_err());
+        assert!(check("[cec]\nosd_name = \"fourteen chars\"").is_ok());
+        assert!(check("[cec]\nosd_name = \"pi\u{e4}\"").is_err());
+    }
+}
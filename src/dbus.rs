@@ -0,0 +1,396 @@
+//! D-Bus service. Needs the `dbus` feature.
+//!
+//! `/org/cecremote` has the interface `org.cecremote.Remote1`. Its methods are the commands
+//! of the control socket, and `[socket.allow]` applies to the user of the caller.
+//! The properties follow [crate::feed] and send PropertiesChanged. Those that are not known
+//! yet are false, 0 or empty.
+use crate::access::{Class, Peer};
+use crate::config::{Bus, Config, Dbus};
+use crate::event::Control;
+use crate::feed;
+use crate::protocol::{self, Request};
+use crate::sock::Server;
+use log::{debug, info};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use zbus::blocking::connection::Builder;
+use zbus::blocking::object_server::InterfaceRef;
+use zbus::message::Header;
+use zbus::{fdo, interface, Connection};
+
+const PATH: &str = "/org/cecremote";
+
+/// What the properties show
+#[derive(Default, Debug, PartialEq)]
+struct Props {
+    state: String,
+    tv: bool,
+    avr_on: bool,
+    muted: bool,
+    volume: u8,
+    playing: bool,
+    snapclient_volume: u8,
+    active_source: String,
+    input: String,
+    phys_addr: String,
+    outlets: HashMap<u8, bool>,
+}
+impl Props {
+    /// Apply an event. Returns the properties that changed
+    fn update(&mut self, name: &str, v: &Value, cfg: &Config) -> Vec<&'static str> {
+        let text = || v.as_str().unwrap_or_default().to_string();
+        let percent = || v.as_u64().map_or(0, |v| v.min(100) as u8);
+        let changed = match name {
+            "state" => vec![replace(&mut self.state, text()).then_some("State")],
+            "tv" => vec![replace(&mut self.tv, v == true).then_some("Tv")],
+            "avr_standby" => vec![replace(&mut self.avr_on, v == false).then_some("AvrOn")],
+            "muted" => vec![replace(&mut self.muted, v == true).then_some("Muted")],
+            "volume" => vec![replace(&mut self.volume, percent()).then_some("Volume")],
+            "playing" => vec![replace(&mut self.playing, v == true).then_some("Playing")],
+            "snapclient_volume" => {
+                vec![replace(&mut self.snapclient_volume, percent()).then_some("SnapclientVolume")]
+            }
+            "active_source" => {
+                let input = v.as_str().and_then(|a| cfg.input_name(a));
+                let input = input.map_or_else(text, str::to_string);
+                vec![
+                    replace(&mut self.active_source, text()).then_some("ActiveSource"),
+                    replace(&mut self.input, input).then_some("Input"),
+                ]
+            }
+            "phys_addr" => vec![replace(&mut self.phys_addr, text()).then_some("PhysAddr")],
+            "outlet" => match (v["outlet"].as_u64(), v["on"].as_bool()) {
+                (Some(n), Some(on)) => {
+                    vec![(self.outlets.insert(n as u8, on) != Some(on)).then_some("Outlets")]
+                }
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        changed.into_iter().flatten().collect()
+    }
+    /// from the state and the outlets
+    fn load(&mut self, srv: &Server) {
+        if let Ok(state) = srv.state(None) {
+            let state: Value = serde_json::from_str(&state).unwrap_or_default();
+            for name in feed::NAMES {
+                if let Some(v) = state.get(name) {
+                    self.update(name, v, &srv.cfg);
+                }
+            }
+        }
+        if let Ok(outlets) = srv.control(Control::Outlets) {
+            let outlets: Value = serde_json::from_str(&outlets).unwrap_or_default();
+            for (n, o) in outlets.as_object().into_iter().flatten() {
+                if let (Ok(n), Some(on)) = (n.parse::<u8>(), o["on"].as_bool()) {
+                    self.outlets.insert(n, on);
+                }
+            }
+        }
+    }
+}
+
+/// set `field` to `v`. True if that changed it
+fn replace<T: PartialEq>(field: &mut T, v: T) -> bool {
+    if *field == v {
+        return false;
+    }
+    *field = v;
+    true
+}
+
+struct Remote {
+    srv: Arc<Server>,
+    props: Props,
+}
+
+#[interface(name = "org.cecremote.Remote1")]
+impl Remote {
+    /// Returns the volume reached
+    #[zbus(out_args("volume"))]
+    async fn set_volume(
+        &self,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        percent: u8,
+    ) -> fdo::Result<u8> {
+        let v = self.run(&hdr, conn, format!("volume {}", percent)).await?;
+        v.parse().map_err(|_| fdo::Error::Failed(v))
+    }
+    /// Returns whether the AVR is muted now
+    #[zbus(out_args("muted"))]
+    async fn set_mute(
+        &self,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        mute: bool,
+    ) -> fdo::Result<bool> {
+        let line = format!("mute {}", if mute { "on" } else { "off" });
+        Ok(self.run(&hdr, conn, line).await? == "muted")
+    }
+    #[zbus(out_args("muted"))]
+    async fn toggle_mute(
+        &self,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+    ) -> fdo::Result<bool> {
+        Ok(self.run(&hdr, conn, "mute toggle".into()).await? == "muted")
+    }
+    /// A port of the AVR, a name from `[inputs]` or an address. Returns the address
+    #[zbus(out_args("address"))]
+    async fn select_input(
+        &self,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        input: &str,
+    ) -> fdo::Result<String> {
+        self.run(&hdr, conn, format!("source {}", word(input)?))
+            .await
+    }
+    /// An outlet by number or name. Returns whether it is on now
+    #[zbus(out_args("on"))]
+    async fn set_outlet(
+        &self,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        outlet: &str,
+        on: bool,
+    ) -> fdo::Result<bool> {
+        let line = format!("outlet {} {}", word(outlet)?, if on { "on" } else { "off" });
+        Ok(self.run(&hdr, conn, line).await? == "on")
+    }
+    #[zbus(out_args("on"))]
+    async fn toggle_outlet(
+        &self,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        outlet: &str,
+    ) -> fdo::Result<bool> {
+        let line = format!("outlet {} toggle", word(outlet)?);
+        Ok(self.run(&hdr, conn, line).await? == "on")
+    }
+    /// `switch` is on, off or toggle
+    async fn switch_outlet_in(
+        &self,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        outlet: &str,
+        switch: &str,
+        seconds: u32,
+    ) -> fdo::Result<()> {
+        let line = format!("outlet {} {} in {}", word(outlet)?, word(switch)?, seconds);
+        self.run(&hdr, conn, line).await.map(drop)
+    }
+    async fn cancel_outlet_timer(
+        &self,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        outlet: &str,
+    ) -> fdo::Result<()> {
+        let line = format!("outlet {} cancel", word(outlet)?);
+        self.run(&hdr, conn, line).await.map(drop)
+    }
+
+    /// the `MediaState`
+    #[zbus(property)]
+    fn state(&self) -> String {
+        self.props.state.clone()
+    }
+    #[zbus(property)]
+    fn tv(&self) -> bool {
+        self.props.tv
+    }
+    /// on and not in standby
+    #[zbus(property)]
+    fn avr_on(&self) -> bool {
+        self.props.avr_on
+    }
+    #[zbus(property)]
+    fn muted(&self) -> bool {
+        self.props.muted
+    }
+    /// of the AVR, once set
+    #[zbus(property)]
+    fn volume(&self) -> u8 {
+        self.props.volume
+    }
+    /// snapclient plays
+    #[zbus(property)]
+    fn playing(&self) -> bool {
+        self.props.playing
+    }
+    #[zbus(property)]
+    fn snapclient_volume(&self) -> u8 {
+        self.props.snapclient_volume
+    }
+    /// physical address, like `3.2.0.0`
+    #[zbus(property)]
+    fn active_source(&self) -> String {
+        self.props.active_source.clone()
+    }
+    /// name of the active source in `[inputs]`, or its address
+    #[zbus(property)]
+    fn input(&self) -> String {
+        self.props.input.clone()
+    }
+    /// our physical address
+    #[zbus(property)]
+    fn phys_addr(&self) -> String {
+        self.props.phys_addr.clone()
+    }
+    /// on or off by number
+    #[zbus(property)]
+    fn outlets(&self) -> HashMap<u8, bool> {
+        self.props.outlets.clone()
+    }
+}
+
+impl Remote {
+    /// Execute a line of the protocol for the caller of `hdr`
+    async fn run(&self, hdr: &Header<'_>, conn: &Connection, line: String) -> fdo::Result<String> {
+        let c = match protocol::parse(&line, &self.srv.cfg) {
+            Ok(Request::Control(c)) => c,
+            Ok(_) => return Err(fdo::Error::InvalidArgs(line)),
+            Err(e) => return Err(fdo::Error::InvalidArgs(e)),
+        };
+        let peer = caller(hdr, conn).await?;
+        if !peer.may(Class::of(&c), &self.srv.cfg.socket.allow) {
+            info!(uid = peer.uid, pid = peer.pid; "D-Bus {} denied", line);
+            return Err(fdo::Error::AccessDenied("permission denied".into()));
+        }
+        debug!(uid = peer.uid, line; "D-Bus call");
+        let srv = Arc::clone(&self.srv);
+        // the AVR may take seconds
+        blocking::unblock(move || srv.control(c))
+            .await
+            .map_err(fdo::Error::Failed)
+    }
+}
+
+/// who sent the message, as the bus tells
+async fn caller(hdr: &Header<'_>, conn: &Connection) -> fdo::Result<Peer> {
+    let sender = hdr
+        .sender()
+        .ok_or_else(|| fdo::Error::AccessDenied("no sender".into()))?;
+    let bus = fdo::DBusProxy::new(conn).await?;
+    let uid = bus.get_connection_unix_user(sender.clone().into()).await?;
+    let pid = bus
+        .get_connection_unix_process_id(sender.clone().into())
+        .await?;
+    Ok(Peer::of_uid(uid, pid as i32))
+}
+
+/// a single word of a command
+fn word(s: &str) -> fdo::Result<&str> {
+    match s {
+        "" => Err(fdo::Error::InvalidArgs("empty".into())),
+        s if s.contains(char::is_whitespace) => {
+            Err(fdo::Error::InvalidArgs(format!("not a single word: {}", s)))
+        }
+        s => Ok(s),
+    }
+}
+
+/// Own the name on the bus and serve until stopped
+pub fn serve(d: &Dbus, srv: Arc<Server>) -> zbus::Result<()> {
+    let builder = match d.bus {
+        Bus::System => Builder::system()?,
+        Bus::Session => Builder::session()?,
+    };
+    serve_on(builder, d, srv)
+}
+
+/// Serve on the bus of `builder`
+pub fn serve_on(builder: Builder, d: &Dbus, srv: Arc<Server>) -> zbus::Result<()> {
+    let events = srv.feed.subscribe(vec![]);
+    let mut props = Props::default();
+    props.load(&srv);
+    let remote = Remote {
+        srv: Arc::clone(&srv),
+        props,
+    };
+    let conn = builder
+        .name(d.name.as_str())?
+        .serve_at(PATH, remote)?
+        .build()?;
+    info!("D-Bus name {} on the {:?} bus", d.name, d.bus);
+    let iface: InterfaceRef<Remote> = conn.object_server().interface(PATH)?;
+    // the feed ends on stop
+    for line in events {
+        let Some((name, value)) = line
+            .trim_end()
+            .strip_prefix("event ")
+            .and_then(|e| e.split_once(' '))
+        else {
+            continue;
+        };
+        let value = serde_json::from_str(value).unwrap_or(Value::Null);
+        let changed = iface.get_mut().props.update(name, &value, &srv.cfg);
+        let remote = iface.get();
+        let e = iface.signal_emitter();
+        for prop in changed {
+            zbus::block_on(async {
+                match prop {
+                    "State" => remote.state_changed(e).await,
+                    "Tv" => remote.tv_changed(e).await,
+                    "AvrOn" => remote.avr_on_changed(e).await,
+                    "Muted" => remote.muted_changed(e).await,
+                    "Volume" => remote.volume_changed(e).await,
+                    "Playing" => remote.playing_changed(e).await,
+                    "SnapclientVolume" => remote.snapclient_volume_changed(e).await,
+                    "ActiveSource" => remote.active_source_changed(e).await,
+                    "Input" => remote.input_changed(e).await,
+                    "PhysAddr" => remote.phys_addr_changed(e).await,
+                    "Outlets" => remote.outlets_changed(e).await,
+                    _ => Ok(()),
+                }
+            })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PhysAddr;
+    use serde_json::json;
+
+    #[test]
+    fn properties() {
+        let mut cfg = Config::default();
+        cfg.inputs.insert(
+            "deck".into(),
+            PhysAddr::try_from("3.5.0.0".to_string()).unwrap(),
+        );
+        let mut p = Props::default();
+        assert_eq!(p.update("state", &json!("Playing"), &cfg), ["State"]);
+        assert_eq!(p.update("state", &json!("Playing"), &cfg), [] as [&str; 0]);
+        assert_eq!(p.update("avr_standby", &json!(false), &cfg), ["AvrOn"]);
+        assert!(p.avr_on);
+        // unknown
+        assert_eq!(p.update("avr_standby", &Value::Null, &cfg), ["AvrOn"]);
+        assert!(!p.avr_on);
+        assert_eq!(
+            p.update("active_source", &json!("3.5.0.0"), &cfg),
+            ["ActiveSource", "Input"]
+        );
+        assert_eq!(p.input, "deck");
+        p.update("active_source", &json!("3.6.0.0"), &cfg);
+        assert_eq!(p.input, "3.6.0.0");
+        let outlet = json!({ "outlet": 3, "on": true });
+        assert_eq!(p.update("outlet", &outlet, &cfg), ["Outlets"]);
+        assert_eq!(p.update("outlet", &outlet, &cfg), [] as [&str; 0]);
+        assert!(p.outlets[&3]);
+        assert_eq!(p.update("volume", &json!(45), &cfg), ["Volume"]);
+        assert_eq!(p.update("nope", &json!(1), &cfg), [] as [&str; 0]);
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(word("fan").unwrap(), "fan");
+        assert!(word("fan off").is_err());
+        assert!(word("").is_err());
+    }
+}
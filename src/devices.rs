@@ -0,0 +1,311 @@
+//! Who is on the bus.
+//!
+//! [Registry] keeps what the devices report about themselves.
+//! [scanner] asks them: all at startup and on hotplug, or a single new one.
+use crate::cec::CecBus;
+use crate::event::Event;
+use cec_linux::{CecLogicalAddress, CecOpcode, CecPhysicalAddress, CecPowerStatus};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::mpsc::{Receiver, Sender};
+
+/// What is known about a device
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Device {
+    pub phys_addr: Option<CecPhysicalAddress>,
+    /// primary device type. 0 TV, 1 recorder, 3 tuner, 4 playback, 5 audio system
+    pub prim_type: Option<u8>,
+    pub vendor: Option<u32>,
+    pub osd_name: Option<String>,
+    /// 4 is 1.3a, 5 is 1.4, 6 is 2.0
+    pub version: Option<u8>,
+    pub power: Option<CecPowerStatus>,
+}
+
+/// Something a device told about itself
+#[derive(Debug, Clone, PartialEq)]
+pub enum Report {
+    /// physical address and primary device type
+    PhysAddr(CecPhysicalAddress, u8),
+    Vendor(u32),
+    OsdName(String),
+    Version(u8),
+    Power(CecPowerStatus),
+}
+
+/// Request for the [scanner]
+#[derive(Debug, Clone, Copy)]
+pub enum Scan {
+    /// ask every logical address. From ours
+    All(CecLogicalAddress),
+    /// ask a single device
+    One(CecLogicalAddress, CecLogicalAddress),
+}
+
+/// All devices on the bus, by logical address
+#[derive(Default)]
+pub struct Registry {
+    devices: BTreeMap<u8, Device>,
+}
+impl Registry {
+    /// Apply a report. Returns true if the device was unknown
+    pub fn update(&mut self, from: CecLogicalAddress, report: Report) -> bool {
+        let new = !self.devices.contains_key(&u8::from(from));
+        let d = self.devices.entry(from.into()).or_default();
+        match report {
+            Report::PhysAddr(p, t) => {
+                d.phys_addr = Some(p);
+                d.prim_type = Some(t);
+            }
+            Report::Vendor(v) => d.vendor = Some(v),
+            Report::OsdName(n) => d.osd_name = Some(n),
+            Report::Version(v) => d.version = Some(v),
+            Report::Power(p) => d.power = Some(p),
+        }
+        new
+    }
+    /// replace what is known about a device. None if it is gone
+    pub fn set(&mut self, addr: CecLogicalAddress, device: Option<Device>) {
+        match device {
+            Some(d) => self.devices.insert(addr.into(), d),
+            None => self.devices.remove(&u8::from(addr)),
+        };
+    }
+    pub fn clear(&mut self) {
+        self.devices.clear();
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (CecLogicalAddress, &Device)> {
+        self.devices
+            .iter()
+            .filter_map(|(&a, d)| Some((CecLogicalAddress::try_from(a).ok()?, d)))
+    }
+    /// The devices as HDMI tree. One line per device, indented by depth
+    pub fn tree(&self) -> String {
+        let mut devices: Vec<_> = self.iter().collect();
+        devices.sort_by_key(|(_, d)| d.phys_addr.map_or(u16::MAX, |p| p.to_num()));
+        let mut s = String::new();
+        for (addr, d) in devices {
+            let depth = d.phys_addr.map_or(0, depth);
+            let _ = write!(s, "{:indent$}", "", indent = depth * 2);
+            match d.phys_addr {
+                Some(p) => {
+                    let _ = write!(s, "{:?}", p);
+                }
+                None => s.push('?'),
+            }
+            let _ = write!(s, " {:?}", addr);
+            if let Some(n) = &d.osd_name {
+                let _ = write!(s, " \"{}\"", n);
+            }
+            if let Some(v) = d.vendor {
+                match vendor_name(v) {
+                    Some(n) => {
+                        let _ = write!(s, " {}", n);
+                    }
+                    None => {
+                        let _ = write!(s, " vendor {:06x}", v);
+                    }
+                }
+            }
+            if let Some(v) = d.version {
+                let _ = write!(s, " CEC {}", version_name(v));
+            }
+            if let Some(p) = d.power {
+                let _ = write!(s, " {:?}", p);
+            }
+            s.push('\n');
+        }
+        s
+    }
+}
+
+/// 0.0.0.0 is 0, 3.0.0.0 is 1, 3.3.0.0 is 2
+fn depth(p: CecPhysicalAddress) -> usize {
+    let n = p.to_num();
+    (0..4).take_while(|i| n & (0xf000 >> (i * 4)) != 0).count()
+}
+fn version_name(v: u8) -> &'static str {
+    match v {
+        1 => "1.2",
+        2 => "1.2a",
+        3 => "1.3",
+        4 => "1.3a",
+        5 => "1.4",
+        6 => "2.0",
+        _ => "?",
+    }
+}
+fn vendor_name(v: u32) -> Option<&'static str> {
+    Some(match v {
+        0x000039 => "Toshiba",
+        0x0000f0 => "Samsung",
+        0x0005cd => "Denon",
+        0x0009b0 => "Onkyo",
+        0x008045 => "Panasonic",
+        0x00903e => "Philips",
+        0x00a0de => "Yamaha",
+        0x00e036 => "Pioneer",
+        0x00e091 => "LG",
+        0x080046 => "Sony",
+        _ => return None,
+    })
+}
+
+/// Answer [Scan] requests. The results are sent as [Event::Scanned]
+pub fn scanner(cec: Box<dyn CecBus>, requests: Receiver<Scan>, events: Sender<Event>) {
+    for r in requests {
+        let (from, targets) = match r {
+            // all but broadcast
+            Scan::All(from) => (
+                from,
+                (0..15)
+                    .filter_map(|a| CecLogicalAddress::try_from(a).ok())
+                    .collect(),
+            ),
+            Scan::One(from, to) => (from, vec![to]),
+        };
+        for to in targets.into_iter().filter(|&t| t != from) {
+            let d = ask(&*cec, from, to);
+            if events.send(Event::Scanned(to, d)).is_err() {
+                return;
+            }
+        }
+        if matches!(r, Scan::All(_)) && events.send(Event::ScanDone).is_err() {
+            return;
+        }
+    }
+}
+
+/// ask a device about itself. None if it does not answer
+fn ask(cec: &dyn CecBus, from: CecLogicalAddress, to: CecLogicalAddress) -> Option<Device> {
+    let request = |opcode, wait_for| cec.request_data(from, to, opcode, b"", wait_for).ok();
+    // anyone on the bus answers this
+    let p = request(CecOpcode::GivePhysicalAddr, CecOpcode::ReportPhysicalAddr)?;
+    let mut d = Device::default();
+    if let [a, b, t] = p[..] {
+        d.phys_addr = Some(CecPhysicalAddress::from_bytes([a, b]));
+        d.prim_type = Some(t);
+    }
+    d.vendor = request(CecOpcode::GiveDeviceVendorId, CecOpcode::DeviceVendorId)
+        .and_then(|v| Some(u32::from_be_bytes([0, *v.first()?, *v.get(1)?, *v.get(2)?])));
+    d.osd_name = request(CecOpcode::GiveOsdName, CecOpcode::SetOsdName)
+        .map(|n| String::from_utf8_lossy(&n).into_owned());
+    d.version = request(CecOpcode::GetCecVersion, CecOpcode::CecVersion)
+        .filter(|v| v.len() == 1)
+        .map(|v| v[0]);
+    d.power = request(
+        CecOpcode::GiveDevicePowerStatus,
+        CecOpcode::ReportPowerStatus,
+    )
+    .filter(|v| v.len() == 1)
+    .and_then(|v| CecPowerStatus::try_from(v[0]).ok());
+    Some(d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cec::Frame;
+    use crate::monitor;
+    use std::sync::mpsc::channel;
+    use CecLogicalAddress::*;
+    use CecOpcode as Op;
+
+    /// what the monitor makes of a broadcast frame. true if a device was new
+    fn feed(r: &mut Registry, from: CecLogicalAddress, opcode: Op, params: &[u8]) -> bool {
+        let (tx, rx) = channel();
+        monitor::command(
+            Frame::new(from, UnregisteredBroadcast, opcode.into(), params),
+            &tx,
+        );
+        drop(tx);
+        let mut new = false;
+        for e in rx {
+            if let Event::Device(a, report) = e {
+                new |= r.update(a, report);
+            }
+        }
+        new
+    }
+
+    #[test]
+    fn update() {
+        let mut r = Registry::default();
+        assert!(feed(
+            &mut r,
+            Audiosystem,
+            Op::ReportPhysicalAddr,
+            &[0x30, 0, 5]
+        ));
+        assert!(!feed(
+            &mut r,
+            Audiosystem,
+            Op::DeviceVendorId,
+            &[0x00, 0x05, 0xcd]
+        ));
+        assert!(!feed(&mut r, Audiosystem, Op::SetOsdName, b"AVR-X540BT"));
+        assert!(!feed(&mut r, Audiosystem, Op::CecVersion, &[5]));
+        assert!(!feed(&mut r, Audiosystem, Op::ReportPowerStatus, &[1]));
+        // too short, nothing to report
+        assert!(!feed(&mut r, Tv, Op::ReportPhysicalAddr, &[0, 0]));
+        assert!(!feed(&mut r, Tv, Op::CecVersion, &[]));
+        let expected = Device {
+            phys_addr: Some(CecPhysicalAddress::from_num(0x3000)),
+            prim_type: Some(5),
+            vendor: Some(0x0005cd),
+            osd_name: Some("AVR-X540BT".to_string()),
+            version: Some(5),
+            power: Some(CecPowerStatus::Standby),
+        };
+        assert_eq!(r.iter().collect::<Vec<_>>(), [(Audiosystem, &expected)]);
+        // later reports replace earlier ones
+        feed(&mut r, Audiosystem, Op::ReportPowerStatus, &[0]);
+        assert_eq!(r.iter().next().unwrap().1.power, Some(CecPowerStatus::On));
+    }
+
+    #[test]
+    fn tree() {
+        let mut r = Registry::default();
+        feed(&mut r, Playback1, Op::ReportPhysicalAddr, &[0x33, 0, 4]);
+        feed(&mut r, Playback1, Op::SetOsdName, b"pi4");
+        feed(&mut r, Record1, Op::SetOsdName, b"rec");
+        feed(&mut r, Audiosystem, Op::ReportPhysicalAddr, &[0x30, 0, 5]);
+        feed(&mut r, Audiosystem, Op::DeviceVendorId, &[0x12, 0x34, 0x56]);
+        feed(&mut r, Tv, Op::ReportPhysicalAddr, &[0, 0, 0]);
+        feed(&mut r, Tv, Op::DeviceVendorId, &[0x08, 0x00, 0x46]);
+        feed(&mut r, Tv, Op::CecVersion, &[6]);
+        feed(&mut r, Tv, Op::ReportPowerStatus, &[0]);
+        feed(&mut r, Playback2, Op::ReportPhysicalAddr, &[0x31, 0, 4]);
+        // sorted by physical address, unknown last by logical address
+        assert_eq!(
+            r.tree(),
+            "0.0.0.0 Tv Sony CEC 2.0 On
+  3.0.0.0 Audiosystem vendor 123456
+    3.1.0.0 Playback2
+    3.3.0.0 Playback1 \"pi4\"
+? Record1 \"rec\"
+"
+        );
+        r.set(Audiosystem, None);
+        r.set(Playback2, Some(Device::default()));
+        assert_eq!(
+            r.tree(),
+            "0.0.0.0 Tv Sony CEC 2.0 On
+    3.3.0.0 Playback1 \"pi4\"
+? Record1 \"rec\"
+? Playback2
+"
+        );
+        r.clear();
+        assert_eq!(r.tree(), "");
+    }
+
+    #[test]
+    fn depths() {
+        let d = |n| depth(CecPhysicalAddress::from_num(n));
+        assert_eq!(
+            [d(0), d(0x1000), d(0x1200), d(0x1230), d(0x1234)],
+            [0, 1, 2, 3, 4]
+        );
+    }
+}
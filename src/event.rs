@@ -0,0 +1,131 @@
+//! What the main loop reacts to.
+//!
+//! The other threads send [Event]s over a channel instead of sharing state.
+use crate::devices::{Device, Report};
+use crate::protocol::Response;
+use cec_linux::{CecLogicalAddress, CecPhysicalAddress};
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// our addresses changed. None if we have no logical address.
+    /// The physical address is INVALID if we are disconnected
+    Addr(CecPhysicalAddress, Option<CecLogicalAddress>),
+    /// TV turned off
+    TvOff,
+    /// An input became active. The TV is on.
+    /// 0xffff if the address is unknown
+    ActiveSource(u16),
+    /// AVR power status. true==standby
+    AvrStandby(Option<bool>),
+    /// AVR reported its audio status. true==muted
+    AvrMuted(bool),
+    /// a device told something about itself
+    Device(CecLogicalAddress, Report),
+    /// result of a scan. None if nothing answers at the address
+    Scanned(CecLogicalAddress, Option<Device>),
+    /// scan of all addresses is complete
+    ScanDone,
+    /// snapclient started or stopped playing
+    Playing(bool),
+    /// snapclient volume changed
+    SnapVolume(u8),
+    /// snapclient is muted
+    SnapMute(bool),
+    /// an outlet was switched by a client or a timer. true==on
+    OutletSwitched(u8, bool),
+    /// request from the control socket. The result goes back if there is a sender
+    Control(Control, Option<Sender<Response>>),
+    /// terminate
+    Stop,
+}
+
+/// Requests from the control socket
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    /// AVR volume 1-100
+    Volume(u8),
+    /// mute, unmute or toggle (None)
+    Mute(Option<bool>),
+    /// switch an outlet on, off or toggle it (None)
+    Outlet(u8, Option<bool>),
+    /// switch an outlet later. Replaces what was planned for it
+    OutletTimer(u8, Option<bool>, Duration),
+    /// forget what was planned for an outlet
+    OutletCancel(u8),
+    /// is an outlet on?
+    OutletStatus(u8),
+    /// names, status and timers of all outlets, as JSON
+    Outlets,
+    /// request active source to be port x of the AVR: 3.x.0.0
+    ActiveSource(u8),
+    /// request active source to be the device at this address
+    Input(CecPhysicalAddress),
+    /// send a message from our logical address
+    Cec {
+        to: CecLogicalAddress,
+        /// may be unknown to cec_linux
+        opcode: u8,
+        data: Vec<u8>,
+        /// wait this long for a reply with this opcode
+        reply: Option<(u8, Duration)>,
+    },
+    /// what the daemon believes, as JSON
+    State,
+}
+
+/// Wait for the next event until `deadline`. None if the deadline passed
+pub fn recv(
+    events: &Receiver<Event>,
+    deadline: Option<Instant>,
+) -> Result<Option<Event>, RecvError> {
+    match deadline {
+        None => events.recv().map(Some),
+        Some(d) => match events.recv_timeout(d.saturating_duration_since(Instant::now())) {
+            Ok(e) => Ok(Some(e)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(RecvError),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn deadline() {
+        let (tx, rx) = mpsc::channel();
+        // queued events come first, even if the deadline passed
+        tx.send(Event::ScanDone).unwrap();
+        let passed = Instant::now();
+        assert!(matches!(recv(&rx, Some(passed)), Ok(Some(Event::ScanDone))));
+        assert!(matches!(recv(&rx, Some(passed)), Ok(None)));
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert!(matches!(recv(&rx, Some(deadline)), Ok(None)));
+        assert!(Instant::now() >= deadline);
+
+        // an event before the deadline ends the wait
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                tx.send(Event::ScanDone).unwrap();
+            });
+            let start = Instant::now();
+            let e = recv(&rx, Some(start + Duration::from_secs(10)));
+            assert!(matches!(e, Ok(Some(Event::ScanDone))));
+            assert!(start.elapsed() < Duration::from_secs(5));
+        });
+        // without a deadline
+        tx.send(Event::Stop).unwrap();
+        assert!(matches!(recv(&rx, None), Ok(Some(Event::Stop))));
+
+        drop(tx);
+        assert!(recv(&rx, None).is_err());
+        assert!(recv(&rx, Some(Instant::now() + Duration::from_secs(10))).is_err());
+    }
+}
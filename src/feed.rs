@@ -0,0 +1,129 @@
+//! Live events for control socket clients.
+//!
+//! The main loop publishes what changed, [Feed::subscribe] gets a channel with the lines.
+//! Each is `event <name> <json>`, like `event state "Playing"` or `event tv true`.
+use crate::power::PowerSwitch;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+pub const NAMES: [&str; 10] = [
+    "state",
+    "tv",
+    "active_source",
+    "avr_standby",
+    "muted",
+    "volume",
+    "playing",
+    "snapclient_volume",
+    "outlet",
+    "phys_addr",
+];
+
+struct Subscriber {
+    /// None: all events
+    names: Option<Vec<String>>,
+    lines: Sender<String>,
+}
+
+pub struct Feed {
+    /// None when closed
+    subscribers: Mutex<Option<Vec<Subscriber>>>,
+}
+impl Default for Feed {
+    fn default() -> Self {
+        Feed {
+            subscribers: Mutex::new(Some(Vec::new())),
+        }
+    }
+}
+impl Feed {
+    /// Get the events with one of `names`, all if empty
+    #[cfg_attr(not(any(feature = "http", feature = "dbus")), allow(dead_code))]
+    pub fn subscribe(&self, names: Vec<String>) -> Receiver<String> {
+        let (lines, rx) = mpsc::channel();
+        self.subscribe_to(names, lines);
+        rx
+    }
+    /// Send the events with one of `names` to `lines`. For a channel that gets other lines, too
+    pub fn subscribe_to(&self, names: Vec<String>, lines: Sender<String>) {
+        let names = Some(names).filter(|n| !n.is_empty());
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(s) = subscribers.as_mut() {
+            s.push(Subscriber { names, lines });
+        }
+    }
+    /// Drop the subscribers, which ends their channels. For stopping
+    pub fn close(&self) {
+        *self.subscribers.lock().unwrap() = None;
+    }
+    /// send an event to everyone interested. Subscribers that are gone are dropped
+    pub fn publish(&self, name: &str, value: serde_json::Value) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let subscribers = match subscribers.as_mut() {
+            Some(s) if !s.is_empty() => s,
+            _ => return,
+        };
+        let line = format!("event {} {}\n", name, value);
+        subscribers.retain(|s| {
+            if s.names.as_ref().is_some_and(|n| !n.iter().any(|n| n == name)) {
+                return true;
+            }
+            s.lines.send(line.clone()).is_ok()
+        });
+    }
+}
+
+/// A [PowerSwitch] that publishes what it switched
+pub struct Published {
+    inner: Box<dyn PowerSwitch>,
+    feed: Arc<Feed>,
+}
+impl Published {
+    pub fn new(inner: Box<dyn PowerSwitch>, feed: Arc<Feed>) -> Published {
+        Published { inner, feed }
+    }
+}
+impl PowerSwitch for Published {
+    fn set_status(&self, num: u8, on: bool) -> std::io::Result<()> {
+        self.inner.set_status(num, on)?;
+        self.feed
+            .publish("outlet", serde_json::json!({"outlet": num, "on": on}));
+        Ok(())
+    }
+    fn get_status(&self, num: u8) -> std::io::Result<bool> {
+        self.inner.get_status(num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn filter() {
+        let feed = Feed::default();
+        let all = feed.subscribe(vec![]);
+        let tv = feed.subscribe(vec!["tv".into()]);
+        feed.publish("state", json!("Playing"));
+        feed.publish("tv", json!(true));
+        assert_eq!(all.try_recv().unwrap(), "event state \"Playing\"\n");
+        assert_eq!(all.try_recv().unwrap(), "event tv true\n");
+        assert_eq!(tv.try_recv().unwrap(), "event tv true\n");
+        assert!(tv.try_recv().is_err());
+        drop(all);
+        feed.publish("tv", json!(false));
+        assert_eq!(feed.subscribers.lock().unwrap().as_ref().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn close() {
+        let feed = Feed::default();
+        let tv = feed.subscribe(vec!["tv".into()]);
+        feed.close();
+        assert!(tv.recv().is_err());
+        // too late
+        assert!(feed.subscribe(vec![]).recv().is_err());
+        feed.publish("tv", json!(true));
+    }
+}
@@ -0,0 +1,311 @@
+//! HTTP API and a remote for phones. Needs the `http` feature.
+//!
+//! The commands of the control socket as URLs: the words of a line are the path
+//! below `/api`, like `POST /api/volume/40`, `POST /api/outlet/fan/off/in/10m` or
+//! `GET /api/state/tv`. Commands that only look are `GET`, the others `POST` with
+//! `Content-Type: application/json`, which other web pages can not send without asking.
+//! Answers are JSON, errors `{"error": "<reason>"}`.
+//!
+//! - `GET /api/events[/name...]` the events of [crate::feed] as Server-Sent Events
+//! - `GET /api/inputs` the inputs of the config
+//! - `GET /` the remote
+//!
+//! Clients are not known, `[http] allow` lists the classes of commands anyone may use.
+use crate::access::Class;
+use crate::config::{Config, Http};
+use crate::event::Control;
+use crate::protocol::{self, Request, Response};
+use crate::sock::{self, Server};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Method};
+
+/// the remote
+const PAGE: &str = include_str!("remote.html");
+/// most requests served at once. Event streams count
+const MAX_REQUESTS: usize = 32;
+/// event streams get a comment when nothing happened for this long, to notice the hang up
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// What a request asks for
+#[derive(Debug, PartialEq)]
+enum Route {
+    Page,
+    Inputs,
+    Control(Control),
+    State(Option<String>),
+    /// events with these names. All if empty
+    Events(Vec<String>),
+}
+
+/// Serve `[http]` until stopped
+pub fn serve(http: &Http, srv: Arc<Server>) -> io::Result<()> {
+    let server = if http.is_unix() {
+        let path = Path::new(&http.listen);
+        sock::remove_stale(path)?;
+        tiny_http::Server::http_unix(path)
+    } else {
+        tiny_http::Server::http(&http.listen)
+    }
+    .map(Arc::new)
+    .map_err(|e| io::Error::other(format!("http {}: {}", http.listen, e)))?;
+    if http.is_unix() {
+        let path = Path::new(&http.listen);
+        sock::set_access(path, None, http.group.as_deref(), http.mode)?;
+    }
+    info!("HTTP on {}", http.listen);
+    let s = Arc::clone(&server);
+    srv.stop.on_stop(move || s.unblock());
+    let requests = Arc::new(AtomicUsize::new(0));
+    for req in server.incoming_requests() {
+        if requests.fetch_add(1, Ordering::Relaxed) >= MAX_REQUESTS {
+            requests.fetch_sub(1, Ordering::Relaxed);
+            warn!("too many HTTP requests");
+            let _ = req.respond(json_response(503, json!({ "error": "busy" })));
+            continue;
+        }
+        let (srv, requests) = (Arc::clone(&srv), Arc::clone(&requests));
+        let allow = http.allow.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle(req, &srv, &allow) {
+                debug!("HTTP: {}", e);
+            }
+            requests.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+    if http.is_unix() {
+        let _ = std::fs::remove_file(&http.listen);
+    }
+    Ok(())
+}
+
+fn handle(req: tiny_http::Request, srv: &Server, allow: &[Class]) -> io::Result<()> {
+    debug!(method:% = req.method(), url = req.url(); "HTTP request");
+    let content_type = req
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Content-Type"))
+        .map(|h| h.value.as_str());
+    let route = route(req.method(), req.url(), &srv.cfg, allow).and_then(|r| {
+        if *req.method() == Method::Post && !is_json(content_type) {
+            return Err((415, "use Content-Type: application/json".into()));
+        }
+        Ok(r)
+    });
+    let (status, body) = match route {
+        Ok(Route::Page) => {
+            let page = tiny_http::Response::from_string(PAGE)
+                .with_header(header("Content-Type", "text/html; charset=utf-8"));
+            return req.respond(page);
+        }
+        Ok(Route::Events(names)) => return events(req, srv, names),
+        Ok(Route::Inputs) => (200, inputs(&srv.cfg)),
+        Ok(Route::Control(c)) => answer(srv.control(c)),
+        Ok(Route::State(f)) => answer(srv.state(f.as_deref())),
+        Err((status, e)) => {
+            if status == 403 {
+                warn!(url = req.url(); "HTTP {} denied", req.url());
+            }
+            (status, json!({ "error": e }))
+        }
+    };
+    req.respond(json_response(status, body))
+}
+
+/// What `method` and `url` ask for, or the status and reason why not
+fn route(
+    method: &Method,
+    url: &str,
+    cfg: &Config,
+    allow: &[Class],
+) -> Result<Route, (u16, String)> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let words: Vec<&str> = path.split('/').filter(|w| !w.is_empty()).collect();
+    let bad = |e| (400, e);
+    let route = match words[..] {
+        [] => return get(method).map(|_| Route::Page),
+        ["api", "inputs"] => Route::Inputs,
+        ["api", "events", ref names @ ..] => {
+            match protocol::parse(&format!("subscribe {}", names.join(" ")), cfg).map_err(bad)? {
+                Request::Subscribe(names) => Route::Events(names),
+                r => unreachable!("{:?}", r),
+            }
+        }
+        ["api", "hello" | "subscribe", ..] => return Err((404, "not found".into())),
+        ["api", ref line @ ..] if !line.is_empty() => {
+            match protocol::parse(&line.join(" "), cfg).map_err(bad)? {
+                Request::Control(c) => Route::Control(c),
+                Request::State(f) => Route::State(f),
+                r => unreachable!("{:?}", r),
+            }
+        }
+        _ => return Err((404, "not found".into())),
+    };
+    let class = match &route {
+        Route::Control(c) => Class::of(c),
+        _ => Class::Read,
+    };
+    match class {
+        Class::Read => get(method)?,
+        _ if *method != Method::Post => return Err((405, "use POST".into())),
+        _ => {}
+    }
+    if !allow.contains(&class) {
+        return Err((403, "permission denied".into()));
+    }
+    Ok(route)
+}
+
+/// Any web page can make a browser POST a form without asking.
+/// Other types of content are only sent if we allow it in a CORS preflight, which we do not answer
+fn is_json(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|t| t.split(';').next())
+        .is_some_and(|t| t.trim().eq_ignore_ascii_case("application/json"))
+}
+
+fn get(method: &Method) -> Result<(), (u16, String)> {
+    match method {
+        Method::Get => Ok(()),
+        _ => Err((405, "use GET".into())),
+    }
+}
+
+/// JSON of the answer. Text like `on` becomes a string
+fn answer(r: Response) -> (u16, Value) {
+    match r {
+        Ok(t) if t.is_empty() => (200, Value::Null),
+        Ok(t) => (200, serde_json::from_str(&t).unwrap_or(Value::String(t))),
+        Err(e) => (500, json!({ "error": e })),
+    }
+}
+
+/// the addresses of the inputs by name
+fn inputs(cfg: &Config) -> Value {
+    cfg.inputs
+        .iter()
+        .map(|(name, a)| (name.clone(), a.to_string().into()))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Send the events until the client hangs up
+fn events(req: tiny_http::Request, srv: &Server, names: Vec<String>) -> io::Result<()> {
+    let events = srv.feed.subscribe(names);
+    // a response of tiny_http is buffered until it ends
+    let mut w = req.into_writer();
+    w.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+          Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+    )?;
+    w.flush()?;
+    // the feed ends on stop
+    loop {
+        match events.recv_timeout(KEEPALIVE) {
+            Ok(line) => {
+                if let Some((name, value)) = line
+                    .trim_end()
+                    .strip_prefix("event ")
+                    .and_then(|e| e.split_once(' '))
+                {
+                    write!(w, "event: {}\ndata: {}\n\n", name, value)?;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => w.write_all(b":\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        w.flush()?;
+    }
+}
+
+fn json_response(status: u16, body: Value) -> tiny_http::Response<io::Cursor<Vec<u8>>> {
+    tiny_http::Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).expect("valid header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PhysAddr;
+
+    #[test]
+    fn routes() {
+        let mut cfg = Config::default();
+        cfg.outlets.names.insert("fan".into(), 3);
+        cfg.inputs.insert(
+            "tv".into(),
+            PhysAddr::try_from("3.0.0.0".to_string()).unwrap(),
+        );
+        let allow = [Class::Read, Class::Volume, Class::Power];
+        let r = |m, url| route(&m, url, &cfg, &allow);
+        assert_eq!(r(Method::Get, "/"), Ok(Route::Page));
+        assert_eq!(
+            r(Method::Post, "/api/volume/40"),
+            Ok(Route::Control(Control::Volume(40)))
+        );
+        assert_eq!(
+            r(Method::Post, "/api/outlet/fan/off/in/10m"),
+            Ok(Route::Control(Control::OutletTimer(
+                3,
+                Some(false),
+                Duration::from_secs(600)
+            )))
+        );
+        assert_eq!(
+            r(Method::Get, "/api/outlet/"),
+            Ok(Route::Control(Control::Outlets))
+        );
+        assert_eq!(
+            r(Method::Get, "/api/state/tv?x"),
+            Ok(Route::State(Some("tv".into())))
+        );
+        assert_eq!(
+            r(Method::Get, "/api/events/state/tv"),
+            Ok(Route::Events(vec!["state".into(), "tv".into()]))
+        );
+        assert_eq!(r(Method::Get, "/api/events"), Ok(Route::Events(vec![])));
+        assert_eq!(r(Method::Get, "/api/inputs"), Ok(Route::Inputs));
+        assert_eq!(r(Method::Get, "/api/volume/40").unwrap_err().0, 405);
+        assert_eq!(r(Method::Post, "/api/state").unwrap_err().0, 405);
+        assert_eq!(r(Method::Post, "/api/volume/400").unwrap_err().0, 400);
+        assert_eq!(r(Method::Get, "/api/events/nope").unwrap_err().0, 400);
+        assert_eq!(r(Method::Get, "/api/hello/1").unwrap_err().0, 404);
+        assert_eq!(r(Method::Get, "/api").unwrap_err().0, 404);
+        assert_eq!(r(Method::Get, "/favicon.ico").unwrap_err().0, 404);
+        assert_eq!(r(Method::Post, "/api/cec/5/0x71").unwrap_err().0, 403);
+        assert_eq!(inputs(&cfg), json!({ "tv": "3.0.0.0" }));
+    }
+
+    #[test]
+    fn content_type() {
+        assert!(is_json(Some("application/json")));
+        assert!(is_json(Some("Application/JSON; charset=utf-8")));
+        assert!(!is_json(None));
+        assert!(!is_json(Some("application/x-www-form-urlencoded")));
+        assert!(!is_json(Some("text/plain")));
+        assert!(!is_json(Some("application/jsonp")));
+    }
+
+    #[test]
+    fn answers() {
+        assert_eq!(answer(Ok("40".into())), (200, json!(40)));
+        assert_eq!(answer(Ok("muted".into())), (200, json!("muted")));
+        assert_eq!(answer(Ok(String::new())), (200, Value::Null));
+        assert_eq!(
+            answer(Err("not connected".into())),
+            (500, json!({ "error": "not connected" }))
+        );
+    }
+}
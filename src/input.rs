@@ -0,0 +1,95 @@
+//! Switching to an input.
+//!
+//! `<Set Stream Path>` asks the device at a physical address to become the active source.
+//! It answers with `<Active Source>` and the switches on the way, like the AVR, follow.
+//! The TV is woken first, and the AVR if the input is behind it.
+//! Until the device answers, this is repeated with a `<Routing Change>` for the switches.
+use crate::cec::CecBus;
+use crate::config::PhysAddr;
+use crate::phys::{is_below, parent};
+use crate::protocol::Response;
+use crate::GState;
+use cec_linux::{CecLogicalAddress, CecOpcode, CecPhysicalAddress};
+use log::{debug, info, warn};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+/// between tries
+const RETRY: Duration = Duration::from_secs(2);
+/// give up after this long. The AVR might have to boot
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// An input that was asked for and is not shown yet
+pub struct Selection {
+    pub target: CecPhysicalAddress,
+    /// who asked
+    reply: Option<Sender<Response>>,
+    started: Instant,
+    /// when to try again
+    pub next_try: Instant,
+    tries: u32,
+}
+
+impl Selection {
+    pub fn new(target: CecPhysicalAddress, reply: Option<Sender<Response>>) -> Selection {
+        let now = Instant::now();
+        Selection {
+            target,
+            reply,
+            started: now,
+            next_try: now,
+            tries: 0,
+        }
+    }
+    /// Send what is missing to show the input.
+    /// `me` is our physical address, `g` what is known about the bus
+    pub fn try_once(
+        &mut self,
+        cec: &dyn CecBus,
+        from: CecLogicalAddress,
+        me: CecPhysicalAddress,
+        g: &GState,
+    ) -> std::io::Result<()> {
+        self.tries += 1;
+        self.next_try = Instant::now() + RETRY;
+        debug!(target: "cec", "selecting {}, try {}", PhysAddr(self.target), self.tries);
+        if g.tv != Some(true) {
+            cec.turn_on(from, CecLogicalAddress::Tv)?;
+        }
+        if is_below(self.target, parent(me)) && g.avr_standby == Some(true) {
+            // might still be booting. The next try will tell
+            if let Err(e) = cec.turn_on(from, CecLogicalAddress::Audiosystem) {
+                debug!(target: "cec", "AVR power on: {}", e);
+            }
+        }
+        let to = CecLogicalAddress::UnregisteredBroadcast;
+        let target = self.target.to_bytes();
+        if self.target == me {
+            // that is us
+            return cec.transmit_data(from, to, CecOpcode::ActiveSource, &target);
+        }
+        if self.tries > 1 && g.active_source != 0xffff {
+            let old = CecPhysicalAddress::from_num(g.active_source).to_bytes();
+            cec.transmit_data(
+                from,
+                to,
+                CecOpcode::RoutingChange,
+                &[old[0], old[1], target[0], target[1]],
+            )?;
+        }
+        cec.transmit_data(from, to, CecOpcode::SetStreamPath, &target)
+    }
+    pub fn timed_out(&self, now: Instant) -> bool {
+        now >= self.started + TIMEOUT
+    }
+    /// tell the one who asked
+    pub fn finish(self, r: Response) {
+        match &r {
+            Ok(_) => info!(target: "cec", "{} is the active source", PhysAddr(self.target)),
+            Err(e) => warn!(target: "cec", "input {}: {}", PhysAddr(self.target), e),
+        }
+        if let Some(reply) = self.reply {
+            let _ = reply.send(r);
+        }
+    }
+}
@@ -0,0 +1,4 @@
+//! What the daemon and `cecctl` share.
+
+/// the control socket without socket activation
+pub const SOCKET_PATH: &str = "/tmp/cec";
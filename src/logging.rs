@@ -0,0 +1,200 @@
+//! Logging to journald, or to stderr with sd-daemon `<N>` prefixes.
+//!
+//! Every message belongs to a category with its own level:
+//! `cec`, `state`, `snapcast`, `socket` and `power`.
+//! The category is the target of the message, or derived from the module it comes from.
+//! Key-values like `opcode` or `outlet` become journal fields.
+use crate::config;
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const CATEGORIES: [&str; 5] = ["cec", "state", "snapcast", "socket", "power"];
+
+/// level of each category, as LevelFilter
+static LEVELS: [AtomicUsize; 5] = [const { AtomicUsize::new(LevelFilter::Info as usize) }; 5];
+
+const JOURNAL: &str = "/run/systemd/journal/socket";
+
+struct Logger {
+    /// None: write to stderr
+    journal: Option<UnixDatagram>,
+}
+
+/// Install the logger. Writes to journald if stderr is connected to it
+pub fn init() {
+    let journal = if is_journal(std::env::var("JOURNAL_STREAM").ok()) {
+        UnixDatagram::unbound()
+            .and_then(|s| s.connect(JOURNAL).map(|()| s))
+            .ok()
+    } else {
+        None
+    };
+    if log::set_boxed_logger(Box::new(Logger { journal })).is_ok() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+/// `JOURNAL_STREAM` is `device:inode` of the stream connected to journald
+fn is_journal(stream: Option<String>) -> bool {
+    let meta = match std::fs::metadata("/proc/self/fd/2") {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+    stream.is_some_and(|s| s == format!("{}:{}", meta.dev(), meta.ino()))
+}
+
+/// set the levels from the config
+pub fn configure(cfg: &config::Log) {
+    for (c, l) in cfg.levels() {
+        set_level(c, l);
+    }
+}
+/// change the level of a category. false if there is no such category
+pub fn set_level(category: &str, level: LevelFilter) -> bool {
+    match CATEGORIES.iter().position(|&c| c == category) {
+        Some(i) => {
+            LEVELS[i].store(level as usize, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+fn filter(n: usize) -> LevelFilter {
+    LevelFilter::iter()
+        .find(|&l| l as usize == n)
+        .unwrap_or(LevelFilter::Info)
+}
+
+/// category of a target like `cecremote::monitor` or `power`
+fn category(target: &str) -> usize {
+    let module = target
+        .strip_prefix("cecremote::")
+        .unwrap_or(target)
+        .split("::")
+        .next()
+        .unwrap_or_default();
+    let c = match module {
+        "monitor" | "cec" | "vbus" | "devices" | "phys" | "sim" => "cec",
+        "snapclient_mitm" | "snapcast" => "snapcast",
+        "sock" | "socket" | "http" | "mqtt" | "dbus" => "socket",
+        "power" => "power",
+        _ => "state",
+    };
+    CATEGORIES.iter().position(|&x| x == c).unwrap_or(1)
+}
+
+/// sd-daemon priority
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let i = category(metadata.target());
+        metadata.level() <= filter(LEVELS[i].load(Ordering::Relaxed))
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        let prio = priority(record.level());
+        if let Some(j) = &self.journal {
+            let mut msg = Vec::new();
+            field(&mut msg, "PRIORITY", &prio.to_string());
+            field(&mut msg, "MESSAGE", &record.args().to_string());
+            field(&mut msg, "SYSLOG_IDENTIFIER", "cecremote");
+            field(&mut msg, "CATEGORY", CATEGORIES[category(record.target())]);
+            if let Some(f) = record.file() {
+                field(&mut msg, "CODE_FILE", f);
+            }
+            if let Some(l) = record.line() {
+                field(&mut msg, "CODE_LINE", &l.to_string());
+            }
+            for (k, v) in &fields.0 {
+                field(&mut msg, &journal_name(k), v);
+            }
+            if j.send(&msg).is_ok() {
+                return;
+            }
+        }
+        let mut line = format!("<{}>{}", prio, record.args());
+        for (k, v) in &fields.0 {
+            let _ = write!(line, " {}={}", k, v);
+        }
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+    fn flush(&self) {}
+}
+
+/// the key-values of a record
+struct Fields(Vec<(String, String)>);
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// `opcode` -> `OPCODE`
+fn journal_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' => c.to_ascii_uppercase(),
+            'A'..='Z' | '0'..='9' => c,
+            _ => '_',
+        })
+        .collect();
+    // leading _ is reserved for trusted fields
+    name.trim_start_matches('_').to_string()
+}
+
+/// append a field in the format of the native journal protocol
+fn field(msg: &mut Vec<u8>, name: &str, value: &str) {
+    msg.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        msg.push(b'\n');
+        msg.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        msg.push(b'=');
+    }
+    msg.extend_from_slice(value.as_bytes());
+    msg.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn categories() {
+        assert_eq!(CATEGORIES[category("cecremote::monitor")], "cec");
+        assert_eq!(CATEGORIES[category("cecremote")], "state");
+        assert_eq!(CATEGORIES[category("cecremote::snapclient_mitm")], "snapcast");
+        assert_eq!(CATEGORIES[category("power")], "power");
+    }
+
+    #[test]
+    fn journal_fields() {
+        let mut msg = Vec::new();
+        field(&mut msg, "OPCODE", "Standby");
+        field(&mut msg, "MESSAGE", "a\nb");
+        assert_eq!(
+            msg,
+            b"OPCODE=Standby\nMESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n".to_vec()
+        );
+        assert_eq!(journal_name("active_source"), "ACTIVE_SOURCE");
+    }
+}
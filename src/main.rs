@@ -1,26 +1,56 @@
+use cec::CecBus;
 use cec_linux::{
-    CecDevice, CecEvent, CecEventStateChange, CecLogAddrType, CecLogAddrs, CecLogicalAddress,
-    CecModeFollower, CecModeInitiator, CecOpcode, CecPowerStatus, CecPrimDevType,
-    CecUserControlCode, Version, CecPhysicalAddress, VendorID
+    CecLogAddrType, CecLogAddrs, CecLogicalAddress, CecModeFollower, CecModeInitiator,
+    CecOpcode, CecPowerStatus, CecPrimDevType, CecUserControlCode, Version, CecPhysicalAddress,
+    VendorID
 };
-use sispm::{get_devices, GlobalSiSPM};
+use config::{Config, Outlets};
+use power::PowerSwitch;
 use std::convert::TryFrom;
-use std::convert::TryInto;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use std::{thread, time};
+use event::{Control, Event};
+use log::{debug, error, info, log, warn};
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::unistd::getpid;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
 
+mod access;
+mod cec;
+mod config;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod devices;
+mod event;
+mod feed;
+#[cfg(feature = "http")]
+mod http;
+mod input;
+mod logging;
 mod monitor;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod outlet;
+mod phys;
+mod power;
+mod protocol;
+mod sim;
 mod snapclient_mitm;
 mod sock;
+mod state;
+mod stop;
+mod vbus;
+mod volume;
 
+use devices::{Registry, Report, Scan};
+use feed::Feed;
 use monitor::mon;
+use phys::{child, parent, PhysAddr, Verdict};
 use sock::{listen_for_vol_changes, setup_sock};
+use state::{Action, Answers, MediaState, Query, Snapcast, Timers, Volume};
 
-const MY_ADDR: CecPhysicalAddress = CecPhysicalAddress::from_num(0x3300);
-
-#[derive(Default)]
+#[derive(Default, Clone, Copy, PartialEq)]
 pub struct GState {
     /// TV is playing
     tv: Option<bool>,
@@ -32,20 +62,46 @@ pub struct GState {
     cec_addr: Option<CecLogicalAddress>,
     /// current active source
     active_source: u16,
+    /// AVR is muted
+    muted: Option<bool>,
+    /// the AVR outlet was switched off from the control socket.
+    /// It stays off until the TV or snapcast changes
+    avr_off_by_hand: bool,
 }
 
+/// how soon the state machine looks again while a client of the control socket uses the bus
+const BUS_BUSY_RETRY: Duration = Duration::from_millis(50);
+/// audio status reads after a mute key, for AVRs that report the change late
+const MUTE_POLLS: u32 = 5;
+const MUTE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What acts on the devices. Shared by the main loop and the control socket
 pub struct Actor {
-    cec: CecDevice,
-    pwr_socket: GlobalSiSPM,
+    /// one conversation on the bus at a time
+    cec: Mutex<Box<dyn CecBus>>,
+    /// does not wait for the bus
+    pwr_socket: power::Shared,
+    /// AVR volume
+    volume: volume::Controller,
+    /// outlets to switch later. Run by the main loop
+    outlet_timers: Mutex<outlet::Schedule>,
+}
+impl Actor {
+    fn cec(&self) -> MutexGuard<'_, Box<dyn CecBus>> {
+        self.cec.lock().expect("cec lock")
+    }
 }
 
 fn main() -> std::io::Result<()> {
-    let stop = Arc::new(AtomicBool::new(false));
-    let die_c = Arc::downgrade(&stop);
+    logging::init();
+    // for the SIGHUP thread. Must be blocked before other threads exist
+    let mut hup = SigSet::empty();
+    hup.add(Signal::SIGHUP);
+    hup.thread_block()?;
+    let (sender, events) = mpsc::channel();
+    let stop = sender.clone();
     if let Err(e) = ctrlc::set_handler(move || {
-        if let Some(stop) = die_c.upgrade() {
-            stop.swap(true, Ordering::Relaxed);
-        }
+        let _ = stop.send(Event::Stop);
     }) {
         match e {
             ctrlc::Error::NoSuchSignal(_) => unreachable!(),
@@ -54,420 +110,779 @@ fn main() -> std::io::Result<()> {
         }
     }
 
-    let listener = setup_sock();
+    let cfg = match Config::load() {
+        Ok(cfg) => Arc::new(cfg),
+        Err(e) => {
+            error!("{}", e);
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+    };
+    logging::configure(&cfg.log);
+    let threads = stop::Stop::new();
+    // ends the wait for SIGHUP
+    threads.on_stop(|| {
+        let _ = kill(getpid(), Signal::SIGHUP);
+    });
+    let t = Arc::clone(&threads);
+    threads.spawn("sighup", move || reload_log_levels(hup, t));
+    let (listener, sock_path) = match setup_sock(&cfg.socket) {
+        Ok(s) => s,
+        Err(e) => {
+            error!(target: "socket", "control socket: {}", e);
+            return Err(e);
+        }
+    };
 
-    //send
-    let cec_bus = CecDevice::open("/dev/cec0")?;
-    let capas = cec_bus.get_capas()?;
-    println!("capas  {:?}", capas);
-    //cec_bus.set_mode(CecModeInitiator::Send, CecModeFollower::RepliesOnly)?;
-    //monitor
-    let cec_mon = CecDevice::open("/dev/cec0")?;
+    // simulated TV, AVR and set-top box of the virtual bus
+    let mut sims = None;
+    let (cec_bus, cec_mon, cec_scan): (Box<dyn CecBus>, Box<dyn CecBus>, Box<dyn CecBus>) =
+        if cfg.cec.device == Path::new("virtual") {
+            info!(target: "cec", "using a virtual CEC bus");
+            let my_addr = cfg
+                .cec
+                .phys_addr
+                .map_or(CecPhysicalAddress::from_num(0x3300), |p| p.0);
+            let bus = vbus::VirtualBus::new();
+            let tv = sim::Tv::new(&bus);
+            let avr = sim::Avr::new(&bus, parent(my_addr));
+            // on another port of the AVR
+            let port = if child(parent(my_addr), 2) == my_addr { 1 } else { 2 };
+            let stb = sim::Player::new(&bus, child(parent(my_addr), port));
+            sims = Some((tv, avr, stb));
+            let adapter = bus.add_adapter(my_addr, CecLogAddrType::PLAYBACK);
+            (
+                Box::new(adapter.open()),
+                Box::new(adapter.open()),
+                Box::new(adapter.open()),
+            )
+        } else {
+            //send
+            let cec_bus = cec::Device::open(&cfg.cec.device)?;
+            let capas = cec_bus.get_capas()?;
+            debug!(target: "cec", "capas {:?}", capas);
+            //cec_bus.set_mode(CecModeInitiator::Send, CecModeFollower::RepliesOnly)?;
+            //monitor
+            let cec_mon = cec::Device::open(&cfg.cec.device)?;
+            //ask the other devices
+            let cec_scan = cec::Device::open(&cfg.cec.device)?;
+            (Box::new(cec_bus), Box::new(cec_mon), Box::new(cec_scan))
+        };
     cec_mon.set_mode(CecModeInitiator::None, CecModeFollower::Monitor)?;
 
-    //clear address
-    let log = CecLogAddrs::default();
-    cec_bus.set_log(log)?;
-    //set address
-    let log = CecLogAddrs::new(
-        VendorID::NONE,
-        Version::V1_4,
-        "pi4".to_string().try_into().unwrap(),
-        &[CecPrimDevType::PLAYBACK],
-        &[CecLogAddrType::PLAYBACK],
-    );
-    cec_bus.set_log(log)?;
-
-    let global_state = Arc::new(Mutex::new(GState::default()));
-    let mutex = Arc::clone(&global_state);
+    claim(&*cec_bus, &cfg.cec.osd_name)?;
 
-    thread::spawn(move || mon(cec_mon, mutex));
+    let (tx, t) = (sender.clone(), Arc::clone(&threads));
+    threads.spawn("monitor", move || mon(cec_mon, tx, t));
+    let (scan, requests) = mpsc::channel();
+    let tx = sender.clone();
+    threads.spawn("scanner", move || devices::scanner(cec_scan, requests, tx));
 
-    let pwr_socket = get_devices()
-        .expect("on pwr socket")
-        .pop()
-        .expect("no pwr socket connected");
+    let mut pwr_socket = power::open(&cfg.power)?;
+    if let Some((_, avr, _)) = &sims {
+        pwr_socket = Box::new(sim::Plug::new(pwr_socket, avr.clone(), cfg.outlets.avr)?);
+    }
 
-    let mut state = if pwr_socket.get_status(2).expect("status?") {
+    let state = if pwr_socket.get_status(cfg.outlets.avr)? {
         //AVR has power...
         MediaState::AVRHasPwr
     } else {
         MediaState::Off
     };
 
-    let actor = Arc::new(Mutex::new(Actor {
-        cec: cec_bus,
-        pwr_socket,
-    }));
-    let act = Arc::clone(&actor);
-    thread::spawn(move || listen_for_vol_changes(listener, act));
+    let feed = Arc::new(Feed::default());
+    let actor = Arc::new(Actor {
+        cec: Mutex::new(cec_bus),
+        pwr_socket: power::Shared::new(Box::new(feed::Published::new(pwr_socket, Arc::clone(&feed)))),
+        volume: Default::default(),
+        outlet_timers: Default::default(),
+    });
+    let server = sock::Server::new(
+        sender.clone(),
+        Arc::clone(&cfg),
+        Arc::clone(&feed),
+        Arc::clone(&actor),
+        Arc::clone(&threads),
+    );
+    let srv = Arc::clone(&server);
+    threads.spawn("socket", move || listen_for_vol_changes(listener, srv));
+    #[cfg(feature = "http")]
+    if cfg.http.is_some() {
+        let srv = Arc::clone(&server);
+        threads.spawn("http", move || {
+            let http = srv.cfg.http.as_ref().expect("http config");
+            if let Err(e) = http::serve(http, Arc::clone(&srv)) {
+                error!(target: "socket", "{}", e);
+            }
+        });
+    }
+    #[cfg(not(feature = "http"))]
+    if let Some(h) = &cfg.http {
+        warn!(target: "socket", "not built with the http feature, not listening on {}", h.listen);
+    }
+    #[cfg(feature = "mqtt")]
+    if cfg.mqtt.is_some() {
+        let srv = Arc::clone(&server);
+        threads.spawn("mqtt", move || {
+            mqtt::run(srv.cfg.mqtt.as_ref().expect("mqtt config"), &srv);
+        });
+    }
+    #[cfg(not(feature = "mqtt"))]
+    if let Some(m) = &cfg.mqtt {
+        warn!(target: "socket", "not built with the mqtt feature, not connecting to {}", m.host);
+    }
+    #[cfg(feature = "dbus")]
+    if cfg.dbus.is_some() {
+        let srv = Arc::clone(&server);
+        threads.spawn("dbus", move || {
+            let d = srv.cfg.dbus.as_ref().expect("dbus config");
+            if let Err(e) = dbus::serve(d, Arc::clone(&srv)) {
+                error!(target: "socket", "D-Bus: {}", e);
+            }
+        });
+    }
+    #[cfg(not(feature = "dbus"))]
+    if let Some(d) = &cfg.dbus {
+        warn!(target: "socket", "not built with the dbus feature, not owning {}", d.name);
+    }
 
     //monitor audio status
-    let pw_plays = Arc::new(AtomicBool::new(false));
-    let shared1 = Arc::downgrade(&pw_plays);
     //let (pw_sender, pw_receiver) = pipewire::channel::channel();
     //thread::spawn(move || pulse::watch(shared1, pw_receiver).expect("pw"));
 
     let act = Arc::clone(&actor);
-    let snapclient_vol = Arc::new(Mutex::new(0u8));
-    let snapclient_volume = Arc::clone(&snapclient_vol);
-
-    let snapclient_vol_changed = Arc::new(AtomicBool::new(false));
-    let snapclient_vchanged = Arc::downgrade(&snapclient_vol_changed);
-
-    thread::spawn(move || {
-        snapclient_mitm::main(shared1, act, snapclient_vol, snapclient_vchanged).expect("mitm err")
+    let c = Arc::clone(&cfg);
+    let t = Arc::clone(&threads);
+    let tx = sender.clone();
+    threads.spawn("snapcast", move || {
+        if let Err(e) = snapclient_mitm::main(tx, act, c, t) {
+            error!(target: "snapcast", "mitm err: {}", e);
+        }
     });
     //wait for snapclient to start and all
-    thread::sleep(time::Duration::from_secs(5));
-
-    let cycle_time = time::Duration::from_millis(SLEEP_TIME_CYCLE_MS);
-    let mut cycles_not_changed = 0;
-    // volume of AVR when not in our audiomode
-    let mut old_vol = 0;
-    while !stop.load(Ordering::Relaxed) {
-        thread::sleep(cycle_time);
-        let GState {
-            tv,
-            avr_ready,
-            avr_standby,
-            cec_addr,
-            active_source,
-        } = *global_state.lock().unwrap();
-        let pulse = pw_plays.load(Ordering::Relaxed);
+    thread::sleep(Duration::from_secs(5));
 
-        if !matches!(
-            &state,
-            MediaState::Off | MediaState::Watching | MediaState::Playing
-        ) {
-            cycles_not_changed += 1;
-            if cycles_not_changed > CYCLES_TO_SWITCH_OFF {
-                println!("<3>Hang in State {:?}", state);
-                state = MediaState::SwitchOff;
-                cycles_not_changed = 0;
-            }
+    let mut daemon = Daemon::new(cfg, actor, state, scan, feed, sender);
+    daemon.run()?;
+    loop {
+        match event::recv(&events, daemon.deadline()) {
+            Ok(Some(Event::Stop)) | Err(_) => break,
+            Ok(event) => daemon.react(event)?,
         }
-
-        state = match &state {
-            MediaState::Watching if tv == Some(false) => {
-                // TV turned Off
-                println!("Watching: {tv:?} {pulse}");
-                let m = actor.lock().expect("main lock");
-                switch_light(&m.pwr_socket, false);
-                if pulse {
-                    if let Some(from) = cec_addr {
-                        cec_audio_mode(&m.cec, from);
-                        // store volume
-                        set_volume(
-                            &m.cec,
-                            from,
-                            *snapclient_volume.lock().unwrap(),
-                            Some(&mut old_vol),
-                        );
+    }
+    info!("shutting down");
+    threads.stop();
+    daemon.shutdown();
+    // ends the scanner
+    drop(daemon);
+    threads.join(Duration::from_secs(3));
+    if let Some(p) = sock_path {
+        if let Err(e) = std::fs::remove_file(&p) {
+            warn!(target: "socket", "could not remove {}: {}", p.display(), e);
+        }
+    }
+    info!("Bye");
+    Ok(())
+}
+/// State of the main loop
+struct Daemon {
+    cfg: Arc<Config>,
+    actor: Arc<Actor>,
+    g: GState,
+    snap: Snapcast,
+    /// snapclient volume, scaled for the AVR
+    snapclient_volume: u8,
+    state: MediaState,
+    /// when the current state was entered
+    entered: Instant,
+    /// [Timers::long_wait] was already set in this state
+    long_wait_done: bool,
+    /// the last step did something that had no effect yet. Repeat it then
+    retry_at: Option<Instant>,
+    /// volume of AVR when not in our audiomode
+    old_vol: u8,
+    /// our physical address
+    phys: PhysAddr,
+    /// wrong address we already tried to correct
+    fix_tried: Option<CecPhysicalAddress>,
+    /// the other devices on the bus
+    devices: Registry,
+    /// requests for [devices::scanner]
+    scan: mpsc::Sender<Scan>,
+    /// a scan of all addresses is running
+    scanning: bool,
+    /// for subscribers of the control socket
+    feed: Arc<Feed>,
+    /// input that should become the active source
+    selection: Option<input::Selection>,
+    /// for what is done in other threads
+    events: mpsc::Sender<Event>,
+}
+impl Daemon {
+    fn new(
+        cfg: Arc<Config>,
+        actor: Arc<Actor>,
+        state: MediaState,
+        scan: mpsc::Sender<Scan>,
+        feed: Arc<Feed>,
+        events: mpsc::Sender<Event>,
+    ) -> Daemon {
+        let phys = PhysAddr::new(cfg.cec.phys_addr.map(|p| p.0), cfg.cec.edid.clone());
+        Daemon {
+            cfg,
+            actor,
+            g: GState::default(),
+            snap: Snapcast::default(),
+            snapclient_volume: 0,
+            state,
+            entered: Instant::now(),
+            long_wait_done: false,
+            retry_at: None,
+            old_vol: 0,
+            phys,
+            fix_tried: None,
+            devices: Registry::default(),
+            scan,
+            scanning: false,
+            feed,
+            selection: None,
+            events,
+        }
+    }
+    /// when [Daemon::react] has to be called without an event
+    fn deadline(&self) -> Option<Instant> {
+        let next_try = self.selection.as_ref().map(|s| s.next_try);
+        let timer = self.actor.outlet_timers.lock().unwrap().next();
+        [self.state_deadline(), next_try, timer].into_iter().flatten().min()
+    }
+    /// when [Daemon::run] has to be called without an event
+    fn state_deadline(&self) -> Option<Instant> {
+        let timeout = if state::is_stable(self.state) {
+            None
+        } else if !self.long_wait_done {
+            Some(self.entered + state::LONG_WAIT)
+        } else {
+            Some(self.entered + state::TIME_TO_SWITCH_OFF)
+        };
+        match (self.retry_at, timeout) {
+            (Some(r), Some(t)) => Some(r.min(t)),
+            (r, t) => r.or(t),
+        }
+    }
+    fn timers(&self, now: Instant) -> Timers {
+        if state::is_stable(self.state) {
+            return Timers::default();
+        }
+        let in_state = now - self.entered;
+        Timers {
+            long_wait: !self.long_wait_done && in_state >= state::LONG_WAIT,
+            hang: in_state >= state::TIME_TO_SWITCH_OFF,
+        }
+    }
+    /// React to an event or a passed deadline (None)
+    fn react(&mut self, event: Option<Event>) -> std::io::Result<()> {
+        let old = (self.g, self.snap, self.snapclient_volume, self.phys.get());
+        let changed = match event {
+            Some(e) => self.handle(e),
+            None => false,
+        };
+        let due = self.actor.outlet_timers.lock().unwrap().due(Instant::now());
+        for (n, on) in due {
+            // a plug may take its time
+            let (act, events) = (Arc::clone(&self.actor), self.events.clone());
+            thread::spawn(move || match outlet::switch(&act.pwr_socket, n, on) {
+                Ok(on) => drop(events.send(Event::OutletSwitched(n, on))),
+                Err(e) => error!(target: "power", "timer: outlet {}: {}", n, e),
+            });
+        }
+        if changed || self.state_deadline().is_some_and(|d| d <= Instant::now()) {
+            self.run()?;
+        }
+        if self.selection.as_ref().is_some_and(|s| s.next_try <= Instant::now()) {
+            self.retry_selection();
+        }
+        self.publish_changes(old);
+        Ok(())
+    }
+    /// tell subscribers what changed since `old`
+    fn publish_changes(&self, old: (GState, Snapcast, u8, CecPhysicalAddress)) {
+        use serde_json::json;
+        let (g, snap, vol, phys) = old;
+        let f = &self.feed;
+        if g.tv != self.g.tv {
+            f.publish("tv", json!(self.g.tv));
+        }
+        if g.active_source != self.g.active_source {
+            let a = CecPhysicalAddress::from_num(self.g.active_source);
+            f.publish("active_source", json!(config::PhysAddr(a).to_string()));
+        }
+        if g.avr_standby != self.g.avr_standby {
+            f.publish("avr_standby", json!(self.g.avr_standby));
+        }
+        if g.muted != self.g.muted {
+            f.publish("muted", json!(self.g.muted));
+        }
+        if snap.playing != self.snap.playing {
+            f.publish("playing", json!(self.snap.playing));
+        }
+        if vol != self.snapclient_volume {
+            f.publish("snapclient_volume", json!(self.snapclient_volume));
+        }
+        if phys != self.phys.get() {
+            let p = (self.phys.get() != CecPhysicalAddress::INVALID)
+                .then(|| config::PhysAddr(self.phys.get()).to_string());
+            f.publish("phys_addr", json!(p));
+        }
+    }
+    /// Apply an event. Returns true if the state machine has to look at it
+    fn handle(&mut self, event: Event) -> bool {
+        let old = (self.g, self.snap);
+        match event {
+            Event::Addr(phys, a) => {
+                let hotplug = self.g.cec_addr.is_none();
+                self.g.cec_addr = a;
+                if phys == CecPhysicalAddress::INVALID {
+                    self.phys.disconnected();
+                    self.devices.clear();
+                } else if let Some(a) = a {
+                    let verdict = self.phys.connected(phys);
+                    self.correct_phys(verdict);
+                    let me = devices::Device {
+                        phys_addr: Some(phys),
+                        prim_type: Some(4),
+                        osd_name: Some(self.cfg.cec.osd_name.clone()),
+                        version: Some(Version::V1_4.into()),
+                        power: Some(CecPowerStatus::On),
+                        ..Default::default()
+                    };
+                    self.devices.set(a, Some(me));
+                    if hotplug {
+                        self.scanning = self.scan.send(Scan::All(a)).is_ok();
                     }
-                    MediaState::Playing
-                } else {
-                    MediaState::SwitchOff
                 }
             }
-            MediaState::Playing if snapclient_vol_changed.load(Ordering::Relaxed) => {
-                //snapcast vol changed
-                let from = match cec_addr {
-                    Some(a) => a,
-                    None => continue,
-                };
-                let m = actor.lock().expect("main lock");
-                snapclient_vol_changed.store(false, Ordering::Relaxed);
-                set_volume(&m.cec, from, *snapclient_volume.lock().unwrap(), None);
-                continue;
+            Event::TvOff => self.g.tv = Some(false),
+            Event::ActiveSource(a) => {
+                self.g.tv = Some(true);
+                self.g.active_source = a;
+                if self.selection.as_ref().is_some_and(|s| s.target.to_num() == a) {
+                    let s = self.selection.take().unwrap();
+                    let r = Ok(config::PhysAddr(s.target).to_string());
+                    s.finish(r);
+                }
             }
-            MediaState::Playing if tv == Some(true) => {
-                // TV turned on while snapcast runns
-                println!("Playing: {tv:?} {pulse}");
-
-                let m = actor.lock().expect("main lock");
-                if let Some(from) = cec_addr {
-                    cec_audio_mode_off(&m.cec, from);
-                    set_volume(&m.cec, from, old_vol, None);
+            Event::AvrStandby(s) => self.g.avr_standby = s,
+            Event::AvrMuted(m) => self.g.muted = Some(m),
+            Event::Device(from, report) => {
+                if let Report::PhysAddr(phys, prim) = report {
+                    if Some(from) != self.g.cec_addr {
+                        self.reported(from, phys);
+                    }
+                    if from == CecLogicalAddress::Audiosystem
+                        && phys == parent(self.phys.get())
+                        && prim == 5
+                    {
+                        //audio became ready to receive commands
+                        self.g.avr_ready = true;
+                    }
+                }
+                if self.devices.update(from, report) && !self.scanning {
+                    // ask the new one about the rest
+                    if let Some(me) = self.g.cec_addr.filter(|&me| me != from) {
+                        let _ = self.scan.send(Scan::One(me, from));
+                    }
                 }
-
-                switch_light(&m.pwr_socket, true);
-                MediaState::Watching
             }
-            MediaState::Playing if !pulse => {
-                // Audio turned Off
-                println!("Playing: {tv:?} {pulse}");
-
-                let from = match cec_addr {
-                    Some(a) => a,
-                    None => continue,
-                };
-                let m = actor.lock().expect("main lock");
-                cec_audio_mode_off(&m.cec, from);
-                set_volume(&m.cec, from, old_vol, None);
-                MediaState::SwitchOff
+            Event::Scanned(addr, device) => {
+                if let Some(phys) = device.as_ref().and_then(|d| d.phys_addr) {
+                    self.reported(addr, phys);
+                }
+                self.devices.set(addr, device);
             }
-            MediaState::Off if pulse || tv == Some(true) => {
-                // Turn On
-                println!("Off: tv={tv:?} pulse={pulse}");
-                cycles_not_changed = 0;
-                let m = actor.lock().expect("main lock");
-                switch_avr(&m.pwr_socket, true, &global_state);
-                MediaState::WaitForAudio
+            Event::ScanDone => {
+                self.scanning = false;
+                info!(target: "cec", "devices on the bus:");
+                for l in self.devices.tree().lines() {
+                    info!(target: "cec", "{}", l);
+                }
             }
-            MediaState::WaitForAudio if avr_ready => {
-                // ARV is now available (after activating its power socket)
-                println!("WaitForAudio+avr_ready: {tv:?} {pulse}");
-
-                //FIXME Some(false) true -> cec cmd: Audiosystem -> Unregistered   RoutingChange: CecDatapacket([48, 0, 51, 0])
-                //but only once...
-                let m = actor.lock().expect("main lock");
-                if tv == Some(true) {
-                    switch_light(&m.pwr_socket, true);
-                    if let Some(from) = cec_addr {
-                        if active_source != 0xffff {
-                            // or just send to audio...
-                            print_err(
-                                m.cec.transmit_data(
-                                    from,
-                                    CecLogicalAddress::UnregisteredBroadcast,
-                                    CecOpcode::ActiveSource,
-                                    &active_source.to_be_bytes(),
-                                ),
-                                "ActiveSource resend",
-                            );
-                        }
-                    }
-                    MediaState::Watching
-                } else if pulse {
-                    let from = match cec_addr {
-                        Some(a) => a,
-                        None => {
-                            match wait_for_addr(&m.cec) {
-                                Ok(()) => {},
-                                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
-                                    try_to_rescue_it_all(&m.cec, &global_state)?;
-                                },
-                                Err(e) => println!("{:?}", e)
-                            }
-                            continue;
-                        }
-                    };
-                    cec_audio_mode(&m.cec, from);
-                    match request_pwr_state(&m.cec, from) {
-                        Some(CecPowerStatus::On) => {
-                            // store volume
-                            set_volume(
-                                &m.cec,
-                                from,
-                                *snapclient_volume.lock().unwrap(),
-                                Some(&mut old_vol),
-                            );
-                            MediaState::Playing
-                        }
-                        Some(CecPowerStatus::Standby) => {
-                            print_err(
-                                m.cec.turn_on(from, CecLogicalAddress::Audiosystem),
-                                "Turn Audio on",
-                            );
-                            continue;
-                        }
-                        _ => {
-                            //retry cec audio mode
-                            continue;
-                        }
-                    }
-                    /*if let Some(CecPowerStatus::On) = request_pwr_state(&m.cec, from) {
-                        // store volume
-                        set_volume(
-                            &m.cec,
-                            from,
-                            *snapclient_volume.lock().unwrap(),
-                            Some(&mut old_vol),
-                        );
-                        MediaState::Playing
-                    }else{
-                        //retry cec audio mode
-                        cycles_not_changed += 1;
-                        continue;
-                    }*/
-                } else {
-                    println!("<3>TV and Audio off. No need for AVR anymore");
-                    MediaState::SwitchOff
+            Event::Playing(p) => self.snap.playing = p,
+            Event::SnapMute(m) => {
+                if self.snap.muted != m {
+                    self.snap.muted = m;
+                    self.snap.mute_changed = true;
                 }
             }
-            MediaState::WaitForAudio if cycles_not_changed == CYCLES_LONG_WAIT => {
-                //AVR wont turn on but has power
-                println!("<4>WaitForAudio takes too long");
-                let from = match cec_addr {
-                    Some(a) => a,
-                    None => {
-                        println!("<4>no address to send from");
-                        continue;
-                    }
-                };
-                let m = actor.lock().expect("main lock");
-
-                let avr_pwr = request_pwr_state(&m.cec, from);
-                if avr_pwr.is_some() {
-                    global_state.lock().unwrap().avr_ready = true;
+            Event::SnapVolume(v) => {
+                self.snapclient_volume = v;
+                self.snap.vol_changed = true;
+            }
+            Event::Control(Control::State, reply) => {
+                if let Some(reply) = reply {
+                    let _ = reply.send(Ok(self.status().to_string()));
                 }
-
-                if tv == Some(true) {
-                    if let Some(CecPowerStatus::On) = avr_pwr {
-                        //all good
-                        println!("<4>But AVR is already on");
-                        continue;
-                    }
-                    print_err(
-                        m.cec.turn_on(from, CecLogicalAddress::Audiosystem),
-                        "PwrOn audio",
-                    );
-                } else if pulse {
-                    if let Some(CecPowerStatus::On) = avr_pwr {
-                        //all good
-                        if m.cec
-                            .request_data(
-                                from,
-                                CecLogicalAddress::Audiosystem,
-                                CecOpcode::GiveSystemAudioModeStatus,
-                                b"",
-                                CecOpcode::SystemAudioModeStatus,
-                            )
-                            .ok()
-                            //.and_then(|data| data.first().copied())
-                            //.is_some_and(|v| v == 1)
-                            .is_some_and(|v| v == [0x33, 0])
-                        {
-                            println!("<4>But AVR is already in SystemAudioMode");
-                            continue;
-                        }
-                    }
-                    cec_audio_mode(&m.cec, from);
+            }
+            Event::Control(Control::ActiveSource(port), reply) => {
+                self.select(child(parent(self.phys.get()), port), reply)
+            }
+            Event::Control(Control::Input(target), reply) => self.select(target, reply),
+            Event::OutletSwitched(n, on) => self.outlet_switched(n, on),
+            Event::Control(Control::OutletTimer(n, on, after), reply) => {
+                self.actor.outlet_timers.lock().unwrap().set(n, on, after);
+                if let Some(reply) = reply {
+                    let _ = reply.send(Ok(String::new()));
                 }
-                continue;
             }
-            MediaState::Watching if avr_standby != Some(false) => {
-                //TV is running but AVR is off
-                println!("<5>Watching: avr standby: {avr_standby:?}");
-                let from = match cec_addr {
-                    Some(a) => a,
-                    None => continue,
+            Event::Control(Control::OutletCancel(n), reply) => {
+                let r = match self.actor.outlet_timers.lock().unwrap().cancel(n) {
+                    true => Ok(String::new()),
+                    false => Err(format!("outlet {} has no timer", n)),
                 };
-                let m = actor.lock().expect("main lock");
+                if let Some(reply) = reply {
+                    let _ = reply.send(r);
+                }
+            }
+            Event::Control(c, reply) => {
+                let r = sock::execute(c, &self.actor);
+                if let Some(reply) = reply {
+                    let _ = reply.send(r);
+                }
+            }
+            Event::Stop => {}
+        }
+        if self.g.tv != old.0.tv || self.snap.playing != old.1.playing {
+            self.g.avr_off_by_hand = false;
+        }
+        old != (self.g, self.snap)
+    }
+    /// An outlet was switched by a client or a timer.
+    ///
+    /// The AVR outlet going off while the state machine needs it ends in Off.
+    /// Otherwise it would switch it on again right away
+    fn outlet_switched(&mut self, n: u8, on: bool) {
+        if n == self.cfg.outlets.avr {
+            self.g.avr_off_by_hand = !on;
+            if !on {
+                self.g.avr_ready = false;
+                self.g.avr_standby = None;
+                if state::needs_avr(self.state) {
+                    warn!(target: "state", "AVR outlet switched off in {:?}. Off until the TV or snapcast changes",
+                        self.state);
+                    self.enter(MediaState::Off);
+                }
+            }
+        }
+    }
+    /// make `target` the active source. `reply` gets the result once it is
+    fn select(&mut self, target: CecPhysicalAddress, reply: Option<mpsc::Sender<protocol::Response>>) {
+        let s = input::Selection::new(target, reply);
+        if let Some(old) = self.selection.take() {
+            old.finish(Err(format!("{} was selected instead", config::PhysAddr(target))));
+        }
+        if self.g.tv == Some(true) && self.g.active_source == target.to_num() {
+            s.finish(Ok(config::PhysAddr(target).to_string()));
+            return;
+        }
+        self.try_selection(s);
+    }
+    fn retry_selection(&mut self) {
+        let s = match self.selection.take() {
+            Some(s) => s,
+            None => return,
+        };
+        if s.timed_out(Instant::now()) {
+            let e = format!("{} did not become the active source", config::PhysAddr(s.target));
+            s.finish(Err(e));
+            return;
+        }
+        self.try_selection(s);
+    }
+    fn try_selection(&mut self, mut s: input::Selection) {
+        let from = match self.g.cec_addr {
+            Some(a) => a,
+            None => return s.finish(Err("not connected".to_string())),
+        };
+        let cec = match self.actor.cec.try_lock() {
+            Ok(c) => c,
+            Err(TryLockError::WouldBlock) => {
+                // a client has the bus. Not a try
+                s.next_try = Instant::now() + BUS_BUSY_RETRY;
+                self.selection = Some(s);
+                return;
+            }
+            Err(TryLockError::Poisoned(e)) => panic!("cec lock: {}", e),
+        };
+        let r = s.try_once(&**cec, from, self.phys.get(), &self.g);
+        drop(cec);
+        match r {
+            Ok(()) => self.selection = Some(s),
+            Err(e) => s.finish(Err(e.to_string())),
+        }
+    }
+    /// What the daemon believes, for the control socket.
+    ///
+    /// Counting cycles was replaced by deadlines: `in_state` and `deadline_in` are seconds
+    fn status(&self) -> serde_json::Value {
+        let now = Instant::now();
+        let secs = |d: Duration| d.as_millis() as f64 / 1000.0;
+        let volume_level = self.actor.volume.has_level();
+        serde_json::json!({
+            "state": format!("{:?}", self.state),
+            "in_state": secs(now - self.entered),
+            "long_wait_done": self.long_wait_done,
+            "deadline_in": self.state_deadline().map(|d| secs(d.saturating_duration_since(now))),
+            "tv": self.g.tv,
+            "avr_ready": self.g.avr_ready,
+            "avr_standby": self.g.avr_standby,
+            "cec_addr": self.g.cec_addr.map(|a| format!("{:?}", a)),
+            "phys_addr": (self.phys.get() != CecPhysicalAddress::INVALID)
+                .then(|| config::PhysAddr(self.phys.get()).to_string()),
+            "active_source": (self.g.active_source != 0xffff).then(|| {
+                config::PhysAddr(CecPhysicalAddress::from_num(self.g.active_source)).to_string()
+            }),
+            "muted": self.g.muted,
+            "avr_off_by_hand": self.g.avr_off_by_hand,
+            "volume_level": volume_level,
+            "playing": self.snap.playing,
+            "snapclient_muted": self.snap.muted,
+            "snapclient_volume": self.snapclient_volume,
+            "old_vol": self.old_vol,
+        })
+    }
+    /// leave the AVR and the outlets as [config::Shutdown] says
+    fn shutdown(&mut self) {
+        let policy = &self.cfg.shutdown;
+        if self.g.tv == Some(true) && !policy.when_tv_on {
+            info!("TV is on. Leaving the AVR alone");
+            return;
+        }
+        let m = Arc::clone(&self.actor);
+        let cec = m.cec();
+        let avr_pwr = m.pwr_socket.get_status(self.cfg.outlets.avr).unwrap_or(true);
+        if let (true, Some(from)) = (avr_pwr, self.g.cec_addr) {
+            if policy.audio_mode_off {
+                cec_audio_mode_off(&**cec, from);
+            }
+            if policy.avr_standby && policy.avr_outlet_off {
                 print_err(
-                    m.cec.turn_on(from, CecLogicalAddress::Audiosystem),
-                    "PwrOn audio",
+                    cec
+                        .transmit(from, CecLogicalAddress::Audiosystem, CecOpcode::Standby),
+                    "SendStandbyDevices audio",
                 );
-                let _ = request_pwr_state(&m.cec, from);
-                continue;
-            }
-            MediaState::Playing if avr_standby != Some(false) => {
-                //snapcast is running but AVR is off
-                // none -> ask for standby status
-                println!("<5>Playing: avr standby: {avr_standby:?}"); //None -> is not the reason the TV turns on
-                let m = actor.lock().expect("main lock");
-                let from = match cec_addr {
-                    Some(a) => a,
-                    None => {
-                        println!("not connected to bus");
-                        let _ = wait_for_addr(&m.cec);
-                        continue;
+                // give it time to store its settings
+                for _ in 0..8 {
+                    if request_pwr_state(&**cec, from) != Some(CecPowerStatus::On) {
+                        break;
                     }
-                };
-
-                cec_audio_mode(&m.cec, from);
-                let _ = request_pwr_state(&m.cec, from);
-                continue;
+                    thread::sleep(Duration::from_millis(250));
+                }
             }
-            MediaState::AVRHasPwr => {
-                println!("AVRHasPwr: {avr_standby:?}");
-                match avr_standby {
-                    None => {
-                        // Service started, dont know whats up
-                        let from = match cec_addr {
-                            Some(a) => a,
-                            None => continue,
-                        };
-                        let m = actor.lock().expect("main lock");
-                        match request_pwr_state(&m.cec, from) {
-                            Some(CecPowerStatus::Standby) => MediaState::SwitchOff,
-                            Some(CecPowerStatus::On) => MediaState::WaitForAudio,
-                            _ => continue,
-                        }
-                    }
-                    Some(false) => {
-                        //AVR is on
-                        if let Some(from) = cec_addr {
-                            let m = actor.lock().expect("main lock");
-                            let _ = m.cec.transmit(
-                                from,
-                                CecLogicalAddress::Audiosystem,
-                                CecOpcode::GivePhysicalAddr,
-                            );
+        }
+        if policy.avr_outlet_off && avr_pwr {
+            switch_avr(&m.pwr_socket, &self.cfg.outlets, false, &mut self.g);
+        }
+        if policy.light_off {
+            switch_light(&m.pwr_socket, &self.cfg.outlets, false);
+        }
+    }
+    /// another device uses `phys`. Is ours still right?
+    fn reported(&mut self, from: CecLogicalAddress, phys: CecPhysicalAddress) {
+        self.phys.reported(from, phys);
+        let verdict = self.phys.check();
+        self.correct_phys(verdict);
+    }
+    /// Get the expected physical address if the kernel uses a wrong one.
+    ///
+    /// Set it if the adapter allows it. Otherwise claim the logical address again
+    fn correct_phys(&mut self, verdict: Verdict) {
+        let expected = match verdict {
+            Verdict::Good => {
+                self.fix_tried = None;
+                return;
+            }
+            Verdict::Wrong(e) => e,
+        };
+        if self.fix_tried == Some(expected) {
+            return;
+        }
+        self.fix_tried = Some(expected);
+        warn!(
+            target: "cec",
+            phys_addr:? = self.phys.current(), expected:? = expected;
+            "wrong physical address, expected {}",
+            config::PhysAddr(expected)
+        );
+        let cec = self.actor.cec();
+        if let Err(e) = cec.set_phys(expected) {
+            debug!(target: "cec", "set_phys: {:?}. Claiming again", e);
+            print_err(claim(&**cec, &self.cfg.cec.osd_name), "claim");
+        }
+    }
+    /// let the state machine decide and do what it says until it settles
+    fn run(&mut self) -> std::io::Result<()> {
+        self.retry_at = None;
+        loop {
+            let timers = self.timers(Instant::now());
+            if timers.long_wait {
+                self.long_wait_done = true;
+            }
+            let mut answers = Answers::default();
+            let mut acted = false;
+            let actor = Arc::clone(&self.actor);
+            // held until the state is decided, so that nothing is done twice
+            let mut cec = None;
+            let next = loop {
+                let s = state::step(self.state, &self.g, self.snap, timers, &answers);
+                if s.actions.is_empty() && s.ask.is_none() {
+                    break s.next;
+                }
+                // logging alone is nothing to retry
+                acted |= s.ask.is_some()
+                    || s.actions.iter().any(|a| !matches!(a, Action::Log(..)));
+                if cec.is_none() {
+                    cec = match actor.cec.try_lock() {
+                        Ok(c) => Some(c),
+                        Err(TryLockError::WouldBlock) => {
+                            // a client has the bus. Decide again then
+                            self.long_wait_done &= !timers.long_wait;
+                            self.retry_at = Some(Instant::now() + BUS_BUSY_RETRY);
+                            return Ok(());
                         }
-                        MediaState::WaitForAudio
-                    }
-                    Some(true) => {
-                        //AVR is in standby
-                        MediaState::SwitchOff
+                        Err(TryLockError::Poisoned(e)) => panic!("cec lock: {}", e),
+                    };
+                }
+                let bus = cec.as_deref().map(|c| &**c).expect("bus is locked");
+                for a in &s.actions {
+                    self.execute(a, bus)?;
+                }
+                match s.ask {
+                    Some(q) => ask(q, bus, &actor.pwr_socket, &self.cfg, self.phys.get(), &mut answers),
+                    None => break s.next,
+                }
+            };
+            drop(cec);
+            match next {
+                Some(next) => self.enter(next),
+                None => {
+                    if acted {
+                        self.retry_at = Some(Instant::now() + state::RETRY_INTERVAL);
                     }
+                    return Ok(());
                 }
             }
-            MediaState::SwitchOff => {
-                // make sure AVR is in standby before cutting the power
-                let m = actor.lock().expect("main lock");
-                if m.pwr_socket.get_status(2).expect("get avr pwr") {
-                    println!("Off but AVR on. Standby: {avr_standby:?}");
-                    if avr_standby == Some(true) {
-                        // stay off
-                        // this is enforcing a delay before cutting the AVR power
-                        switch_avr(&m.pwr_socket, false, &global_state);
-                        MediaState::Off
-                    } else {
-                        //send AVR to standby first
-                        let from = match cec_addr {
-                            Some(a) => a,
-                            None => {
-                                println!("no cec address");
-                                let _ = wait_for_addr(&m.cec);
-                                continue;
-                            }
-                        };
-                        print_err(
-                            m.cec.transmit(
-                                from,
-                                CecLogicalAddress::Audiosystem,
-                                CecOpcode::Standby,
-                            ),
-                            "SendStandbyDevices audio",
-                        );
-                        let _ = request_pwr_state(&m.cec, from);
-                        continue;
-                    }
-                } else {
-                    //already off
-                    MediaState::Off
+        }
+    }
+    /// switch to `next` and tell subscribers
+    fn enter(&mut self, next: MediaState) {
+        self.state = next;
+        self.entered = Instant::now();
+        self.long_wait_done = false;
+        self.retry_at = None;
+        info!(state:? = self.state; "New State: {:?}", self.state);
+        self.feed
+            .publish("state", serde_json::json!(format!("{:?}", self.state)));
+    }
+    /// do what the state machine decided
+    fn execute(&mut self, action: &Action, cec: &dyn CecBus) -> std::io::Result<()> {
+        let cfg = &self.cfg;
+        let m = &self.actor;
+        match *action {
+            Action::Log(level, ref l) => log!(target: "state", level, state:? = self.state; "{}", l),
+            Action::SwitchLight(on) => switch_light(&m.pwr_socket, &cfg.outlets, on),
+            Action::SwitchAvr(on) => switch_avr(&m.pwr_socket, &cfg.outlets, on, &mut self.g),
+            Action::AudioModeOn(from) => cec_audio_mode(cec, from, self.phys.get()),
+            Action::AudioModeOff(from) => cec_audio_mode_off(cec, from),
+            Action::SetVolume(from, v) => {
+                let (target, old) = match v {
+                    Volume::Snapclient => (self.snapclient_volume, None),
+                    Volume::SnapclientStoreOld => (self.snapclient_volume, Some(&mut self.old_vol)),
+                    Volume::Old => (self.old_vol, None),
+                };
+                match m.volume.set(cec, from, target, old) {
+                    Ok(v) => self.feed.publish("volume", serde_json::json!(v)),
+                    Err(e) => error!(target: "cec", "volume {}%: {}", target, e),
                 }
             }
-            _ => continue, //stay in state
-        };
-        cycles_not_changed = 0;
-        println!("New State: {:?}", state);
+            Action::ClearVolChanged => self.snap.vol_changed = false,
+            Action::Mute(from, on) => match mute(cec, from, Some(on)) {
+                Ok(muted) => self.g.muted = Some(muted),
+                Err(e) => error!(target: "cec", "mute: {}", e),
+            },
+            Action::ClearMuteChanged => self.snap.mute_changed = false,
+            Action::ResendActiveSource(from, active_source) => print_err(
+                cec.transmit_data(
+                    from,
+                    CecLogicalAddress::UnregisteredBroadcast,
+                    CecOpcode::ActiveSource,
+                    &active_source.to_be_bytes(),
+                ),
+                "ActiveSource resend",
+            ),
+            Action::TurnOnAvr(from) => print_err(
+                cec.turn_on(from, CecLogicalAddress::Audiosystem),
+                "PwrOn audio",
+            ),
+            Action::StandbyAvr(from) => print_err(
+                cec
+                    .transmit(from, CecLogicalAddress::Audiosystem, CecOpcode::Standby),
+                "SendStandbyDevices audio",
+            ),
+            Action::RequestPwrState(from) => {
+                let _ = request_pwr_state(cec, from);
+            }
+            Action::GivePhysAddr(from) => {
+                let _ = cec.transmit(
+                    from,
+                    CecLogicalAddress::Audiosystem,
+                    CecOpcode::GivePhysicalAddr,
+                );
+            }
+            Action::MarkAvrReady => self.g.avr_ready = true,
+        }
+        Ok(())
+    }
+}
+/// get the information the state machine needs
+fn ask(
+    query: Query,
+    cec: &dyn CecBus,
+    pwr_socket: &dyn PowerSwitch,
+    cfg: &Config,
+    my_addr: CecPhysicalAddress,
+    answers: &mut Answers,
+) {
+    match query {
+        Query::AvrPower(from) => answers.avr_power = Some(request_pwr_state(cec, from)),
+        Query::AudioMode(from) => {
+            answers.audio_mode = Some(
+                cec
+                    .request_data(
+                        from,
+                        CecLogicalAddress::Audiosystem,
+                        CecOpcode::GiveSystemAudioModeStatus,
+                        b"",
+                        CecOpcode::SystemAudioModeStatus,
+                    )
+                    .ok()
+                    //.and_then(|data| data.first().copied())
+                    //.is_some_and(|v| v == 1)
+                    .is_some_and(|v| v == my_addr.to_bytes()),
+            )
+        }
+        Query::AvrOutlet => {
+            answers.avr_outlet = Some(match pwr_socket.get_status(cfg.outlets.avr) {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    error!(target: "power", outlet = cfg.outlets.avr; "get avr pwr Err: {:?}", e);
+                    None
+                }
+            })
+        }
     }
-    println!("Bye");
-    Ok(())
 }
-/// 0.25s
-const SLEEP_TIME_CYCLE_MS: u64 = 250;
-/// 7s
-const CYCLES_TO_SWITCH_OFF: u8 = (7_000 / SLEEP_TIME_CYCLE_MS) as u8;
-/// 5.5s
-const CYCLES_LONG_WAIT: u8 = (5_500 / SLEEP_TIME_CYCLE_MS) as u8;
 
 ///request PWR state of Audiosystem and block till answered
 #[inline]
-fn request_pwr_state(cec: &CecDevice, from: CecLogicalAddress) -> Option<CecPowerStatus> {
+fn request_pwr_state(cec: &dyn CecBus, from: CecLogicalAddress) -> Option<CecPowerStatus> {
     cec.request_data(
         from,
         CecLogicalAddress::Audiosystem,
@@ -481,42 +896,24 @@ fn request_pwr_state(cec: &CecDevice, from: CecLogicalAddress) -> Option<CecPowe
 }
 
 #[inline]
-fn switch_light(pwr_socket: &GlobalSiSPM, on: bool) {
-    print_err(pwr_socket.set_status(1, on), "pwr1");
+fn switch_light(pwr_socket: &dyn PowerSwitch, outlets: &Outlets, on: bool) {
+    info!(target: "power", outlet = outlets.light, on; "switch light");
+    print_err(pwr_socket.set_status(outlets.light, on), "pwr light");
 }
 #[inline]
-fn switch_avr(pwr_socket: &GlobalSiSPM, on: bool, state: &Arc<Mutex<GState>>) {
-    let mut s = state.lock().unwrap();
+fn switch_avr(
+    pwr_socket: &dyn PowerSwitch,
+    outlets: &Outlets,
+    on: bool,
+    s: &mut GState,
+) {
     s.avr_ready = false;
     s.avr_standby = None;
-    print_err(pwr_socket.set_status(2, on), "pwr2");
-}
-#[derive(Copy, Clone, Debug)]
-enum MediaState {
-    /// TV has audio
-    ///
-    /// Everything is on
-    Watching,
-    /// snapcast has audio
-    ///
-    /// Only AVR is on
-    Playing,
-    /// power down AVR socket
-    SwitchOff,
-    /// just do nothing
-    Off,
-    /// AVR has power, but not booted yet.
-    ///
-    /// Everything else is powered off.
-    ///
-    /// Ends once Audio sends ReportPhysicalAddr
-    WaitForAudio,
-    /// Initial State - Audio has power, standby is unknown
-    AVRHasPwr,
+    info!(target: "power", outlet = outlets.avr, on; "switch avr");
+    print_err(pwr_socket.set_status(outlets.avr, on), "pwr avr");
 }
-
 /// requests audio focus. Turn on AVR if needed
-fn cec_audio_mode(cec: &CecDevice, from: CecLogicalAddress) {
+fn cec_audio_mode(cec: &dyn CecBus, from: CecLogicalAddress, my_addr: CecPhysicalAddress) {
     /*
         #The feature can be initiated from a device (eg TV or STB) or the amplifier. In the case of initiation by a device
         #other than the amplifier, that device sends an <System Audio Mode Request> to the amplifier, with the
@@ -534,7 +931,7 @@ fn cec_audio_mode(cec: &CecDevice, from: CecLogicalAddress) {
             from,
             CecLogicalAddress::Audiosystem,
             CecOpcode::SystemAudioModeRequest,
-            &MY_ADDR.to_bytes(),
+            &my_addr.to_bytes(),
         ),
         "SystemAudioModeRequest failed",
     );
@@ -542,16 +939,16 @@ fn cec_audio_mode(cec: &CecDevice, from: CecLogicalAddress) {
         from,
         CecLogicalAddress::Audiosystem,
         CecOpcode::SystemAudioModeRequest,
-        &MY_ADDR.to_bytes(),
+        &my_addr.to_bytes(),
         CecOpcode::SetSystemAudioMode,
     ) {
         Ok(v) => {
-            println!("SystemAudioMode on: {:?}", v);
+            debug!(target: "cec", "SystemAudioMode on: {:?}", v);
             /*if v.first().is_some_and(|&v|v==1) {
 
             }*/
         }
-        Err(e) => println!("<3>SystemAudioModeRequest failed: {:?}", e),
+        Err(e) => error!(target: "cec", "SystemAudioModeRequest failed: {:?}", e),
     }*/
 
     //print_err(cec.audio_get_status(),"GiveAudioStatus");
@@ -559,11 +956,11 @@ fn cec_audio_mode(cec: &CecDevice, from: CecLogicalAddress) {
 ///Print an error
 fn print_err<E: std::fmt::Debug>(res: Result<(), E>, name: &str) {
     if let Err(e) = res {
-        println!("<3>{} Err: {:?}", name, e);
+        error!("{} Err: {:?}", name, e);
     }
 }
 ///requests termination of audio focus
-fn cec_audio_mode_off(cec: &CecDevice, from: CecLogicalAddress) {
+fn cec_audio_mode_off(cec: &dyn CecBus, from: CecLogicalAddress) {
     /*
     <System Audio Mode Request> sent without a [Physical Address] parameter requests termination of the feature.
     In this case, the amplifier sends a <Set System Audio Mode> [Off] message.
@@ -584,76 +981,68 @@ fn cec_audio_mode_off(cec: &CecDevice, from: CecLogicalAddress) {
         CecOpcode::SetSystemAudioMode,
     ) {
         Ok(v) => {
-            println!("SystemAudioMode off: {:?}", v);
+            debug!(target: "cec", "SystemAudioMode off: {:?}", v);
             /*if v.first().is_some_and(|&v|v==0) {
 
             }*/
         }
-        Err(e) => println!("<3>SystemAudioModeRequest off failed: {:?}", e),
+        Err(e) => error!(target: "cec", "SystemAudioModeRequest off failed: {:?}", e),
     }
 }
 
-fn set_volume(cec: &CecDevice, from: CecLogicalAddress, vol: u8, cur: Option<&mut u8>) {
-    if let Some(v) = cec
-        .request_data(
-            from,
-            CecLogicalAddress::Audiosystem,
-            CecOpcode::GiveAudioStatus,
-            b"",
-            CecOpcode::ReportAudioStatus,
-        )
-        .ok()
-        .and_then(|d| d.first().copied())
-    {
-        println!("Vol is: Muted: {} Vol: {}%", v & 0x80, v & 0x7f);
-        if let Some(c) = cur {
-            *c = v & 0x7f;
-        }
-        let steps = vol as i8 - (v & 0x7f) as i8;
-        let key = if steps.is_positive() {
-            CecUserControlCode::VolumeUp
-        } else {
-            CecUserControlCode::VolumeDown
-        };
-        let steps = steps.unsigned_abs() * 2;
-        for _ in 0..steps {
-            if let Err(e) = cec.keypress(from, CecLogicalAddress::Audiosystem, key) {
-                println!("<3>keypress Err: {:?}", e);
-                return;
+/// Mute (true), unmute (false) or toggle (None) the AVR and read back if it worked.
+/// Returns if it is muted now
+fn mute(cec: &dyn CecBus, from: CecLogicalAddress, on: Option<bool>) -> Result<bool, String> {
+    let muted = volume::audio_status(cec, from).ok_or("AVR does not report its audio status")?.1;
+    let want = on.unwrap_or(!muted);
+    if muted == want {
+        return Ok(muted);
+    }
+    info!(target: "cec", muted = want; "mute {}", want);
+    // the explicit functions are not supported by all. Mute toggles
+    let explicit = if want {
+        CecUserControlCode::MuteFunction
+    } else {
+        CecUserControlCode::RestoreVolumeFunction
+    };
+    for key in [explicit, CecUserControlCode::Mute] {
+        cec.keypress(from, CecLogicalAddress::Audiosystem, key)
+            .map_err(|e| e.to_string())?;
+        for i in 0..MUTE_POLLS {
+            if i > 0 {
+                thread::sleep(MUTE_POLL_INTERVAL);
+            }
+            if volume::audio_status(cec, from).is_some_and(|(_, m)| m == want) {
+                return Ok(want);
             }
         }
     }
+    Err(format!("AVR is still {}", if muted { "muted" } else { "unmuted" }))
 }
 
-fn wait_for_addr(cec: &CecDevice) -> std::io::Result<()> {
-    loop {
-        match cec.get_event()? {
-            CecEvent::StateChange(CecEventStateChange {
-                phys_addr,
-                log_addr_mask,
-            }) => {
-                if phys_addr != CecPhysicalAddress::INVALID && !log_addr_mask.is_empty() {
-                    if MY_ADDR == phys_addr {
-                        return Ok(());
-                    }else{
-                        return Err(std::io::ErrorKind::InvalidData.into());
-                    }
-                }
+/// apply the log levels of the config file on SIGHUP
+fn reload_log_levels(hup: SigSet, stop: Arc<stop::Stop>) {
+    while hup.wait().is_ok() && !stop.is_stopped() {
+        match Config::load() {
+            Ok(cfg) => {
+                logging::configure(&cfg.log);
+                info!("log levels reloaded");
             }
-            _ => continue,
+            Err(e) => error!("not reloaded: {}", e),
         }
     }
 }
-
-fn try_to_rescue_it_all(cec: &CecDevice, global_state: &Arc<Mutex<GState>>) -> std::io::Result<()> {
-    println!("attempting rescue");
-    cec.turn_on(CecLogicalAddress::Playback2, CecLogicalAddress::Tv)?;
-    thread::sleep(Duration::from_secs(10));
-    cec.transmit(
-        CecLogicalAddress::Playback2,
-        CecLogicalAddress::Tv,
-        CecOpcode::Standby,
-    )?;
-    global_state.lock().unwrap().tv = Some(false);
-    Ok(())
-}
\ No newline at end of file
+/// claim a logical address as playback device
+fn claim(cec: &dyn CecBus, osd_name: &str) -> std::io::Result<()> {
+    //clear address
+    cec.set_log(CecLogAddrs::default())?;
+    //set address
+    let log = CecLogAddrs::new(
+        VendorID::NONE,
+        Version::V1_4,
+        osd_name.as_bytes().into(),
+        &[CecPrimDevType::PLAYBACK],
+        &[CecLogAddrType::PLAYBACK],
+    );
+    cec.set_log(log)
+}
@@ -1,11 +1,18 @@
-use crate::GState;
+use crate::cec::{CecBus, Frame};
+use crate::devices::Report;
+use crate::event::Event;
+use crate::stop::Stop;
 use cec_linux::{
-    CecDevice, CecEvent, CecLogAddrMask, CecLogicalAddress, CecMsg, CecOpcode, CecPhysicalAddress, CecPowerStatus, PollFlags, PollTimeout
+    CecEvent, CecEventStateChange, CecLogAddrMask, CecLogicalAddress, CecOpcode, CecPhysicalAddress, CecPowerStatus,
+    PollFlags, PollTimeout,
 };
+use log::{debug, info};
 use std::process::Command;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
 
-pub fn mon(cec_mon: CecDevice, mut mutex: Arc<Mutex<GState>>) {
+pub fn mon(cec_mon: Box<dyn CecBus>, events: Sender<Event>, stop: Arc<Stop>) {
+    stop.on_stop(cec_mon.waker().unwrap());
     loop {
         let f = cec_mon
             .poll(
@@ -13,28 +20,12 @@ pub fn mon(cec_mon: CecDevice, mut mutex: Arc<Mutex<GState>>) {
                 PollTimeout::NONE,
             )
             .unwrap();
+        if stop.is_stopped() {
+            break;
+        }
         if f.intersects(PollFlags::POLLPRI) {
             if let CecEvent::StateChange(s) = cec_mon.get_event().unwrap() {
-                if s.phys_addr == CecPhysicalAddress::INVALID {
-                    println!("<7> CEC disconnected");
-                } else if !s.log_addr_mask.is_empty() {
-                    println!("<7> CEC connected {:?} {:?}", s.phys_addr, s.log_addr_mask);
-                    //sometimes phys addr is 0x3000 instead of 0x3300
-                }
-                let on = if s.log_addr_mask.contains(CecLogAddrMask::Playback1) {
-                    mutex.lock().unwrap().cec_addr = Some(CecLogicalAddress::Playback1);
-                    true
-                } else if s.log_addr_mask.contains(CecLogAddrMask::Playback2) {
-                    mutex.lock().unwrap().cec_addr = Some(CecLogicalAddress::Playback2);
-                    true
-                } else if s.log_addr_mask.contains(CecLogAddrMask::Playback3) {
-                    mutex.lock().unwrap().cec_addr = Some(CecLogicalAddress::Playback3);
-                    true
-                } else {
-                    mutex.lock().unwrap().cec_addr = None;
-                    false
-                };
-                if on {
+                if state_change(s, &events) {
                     let runs = Command::new("systemctl")
                         .args(["--user", "is-active", "pipewire"])
                         .output()
@@ -52,33 +43,51 @@ pub fn mon(cec_mon: CecDevice, mut mutex: Arc<Mutex<GState>>) {
         }
         if f.contains(PollFlags::POLLIN | PollFlags::POLLRDNORM) {
             let msg = cec_mon.rec().unwrap();
-            command(msg, &mut mutex)
+            command(msg, &events)
         }
     }
 }
 
-fn command(cmd: CecMsg, state: &mut Arc<Mutex<GState>>) {
+/// track our logical address. Returns true if we are on the bus
+pub fn state_change(s: CecEventStateChange, events: &Sender<Event>) -> bool {
+    if s.phys_addr == CecPhysicalAddress::INVALID {
+        debug!("CEC disconnected");
+    } else if !s.log_addr_mask.is_empty() {
+        debug!(phys_addr:? = s.phys_addr; "CEC connected {:?} {:?}", s.phys_addr, s.log_addr_mask);
+    }
+    let addr = if s.log_addr_mask.contains(CecLogAddrMask::Playback1) {
+        Some(CecLogicalAddress::Playback1)
+    } else if s.log_addr_mask.contains(CecLogAddrMask::Playback2) {
+        Some(CecLogicalAddress::Playback2)
+    } else if s.log_addr_mask.contains(CecLogAddrMask::Playback3) {
+        Some(CecLogicalAddress::Playback3)
+    } else {
+        None
+    };
+    let _ = events.send(Event::Addr(s.phys_addr, addr));
+    addr.is_some()
+}
+
+pub fn command(cmd: Frame, events: &Sender<Event>) {
     let opcode = match cmd.opcode() {
         Some(Ok(opc)) => opc,
         _ => return,
     };
     match opcode {
         CecOpcode::Standby if cmd.initiator() == CecLogicalAddress::Tv => {
-            state.lock().unwrap().tv = Some(false);
-            println!("======== Tv aus ===========")
+            let _ = events.send(Event::TvOff);
+            info!(initiator:? = cmd.initiator(), opcode:? = opcode; "======== Tv aus ===========")
         }
         CecOpcode::ActiveSource if cmd.initiator() != CecLogicalAddress::Playback2
             //if cmd.initiator() == CecLogicalAddress::Tv && cmd.parameters() == [0, 0]
             =>
         {
-            let mut s = state.lock().unwrap();
-            s.tv = Some(true);
-            if let Ok(ba) = cmd.parameters()[0..2].try_into() {
-                s.active_source = u16::from_be_bytes(ba);
-            }else{
-                s.active_source = 0xffff;
-            }
-            println!("======== {:x} an ===========", s.active_source);
+            let active_source = match cmd.parameters().get(0..2).and_then(|p| p.try_into().ok()) {
+                Some(ba) => u16::from_be_bytes(ba),
+                None => 0xffff,
+            };
+            let _ = events.send(Event::ActiveSource(active_source));
+            info!(initiator:? = cmd.initiator(), opcode:? = opcode, active_source; "======== {:x} an ===========", active_source);
         }/*
         CecOpcode::VendorCommandWithId
             if cmd.parameters()[0..3] == [8, 0, 70] =>
@@ -87,35 +96,57 @@ fn command(cmd: CecMsg, state: &mut Arc<Mutex<GState>>) {
             println!("≈========tv realy on=========");
         }*/
         CecOpcode::SetSystemAudioMode => {
-            let mut s = state.lock().unwrap();
             //s.audio_mode = cmd.parameters().first().map(|&b| b == 1);
-            println!("SetSystemAudioMode is {:?}", cmd.parameters());
-            s.avr_standby = Some(false);
+            debug!(opcode:? = opcode; "SetSystemAudioMode is {:?}", cmd.parameters());
+            let _ = events.send(Event::AvrStandby(Some(false)));
         }
-        CecOpcode::ReportPowerStatus if cmd.initiator() == CecLogicalAddress::Audiosystem => {
-            state.lock().unwrap().avr_standby = match cmd.parameters().first().and_then(|&i|CecPowerStatus::try_from(i).ok()) {
+        CecOpcode::ReportPowerStatus => {
+            let status = cmd.parameters().first().and_then(|&i| CecPowerStatus::try_from(i).ok());
+            if let Some(p) = status {
+                let _ = events.send(Event::Device(cmd.initiator(), Report::Power(p)));
+            }
+            if cmd.initiator() != CecLogicalAddress::Audiosystem {
+                return;
+            }
+            let standby = match status {
                 Some(CecPowerStatus::Standby) /*| Some(3)*/ => {
                     //standby
-                    println!("Updated AVR PWR: Some(true) -> standby");
+                    info!(initiator:? = cmd.initiator(), opcode:? = opcode; "Updated AVR PWR: Some(true) -> standby");
                     Some(true)
                 },
                 Some(CecPowerStatus::On) /*| Some(2)*/ => {
                     //on
-                    println!("Updated AVR PWR: Some(false) -> on");
+                    info!(initiator:? = cmd.initiator(), opcode:? = opcode; "Updated AVR PWR: Some(false) -> on");
                     Some(false)
                 },
                 _ => {
-                    println!("Updated AVR PWR: None");
+                    info!(initiator:? = cmd.initiator(), opcode:? = opcode; "Updated AVR PWR: None");
                     None
                 }
             };
+            let _ = events.send(Event::AvrStandby(standby));
         }
-        CecOpcode::ReportPhysicalAddr
-            if cmd.initiator() == CecLogicalAddress::Audiosystem
-                && cmd.parameters() == [0x30, 0, 5] =>
-        {
-            //audio became ready to receive commands
-            state.lock().unwrap().avr_ready = true;
+        CecOpcode::ReportAudioStatus if cmd.initiator() == CecLogicalAddress::Audiosystem => {
+            if let Some(&v) = cmd.parameters().first() {
+                let _ = events.send(Event::AvrMuted(v & 0x80 != 0));
+            }
+        }
+        CecOpcode::ReportPhysicalAddr if cmd.parameters().len() >= 3 => {
+            let p = cmd.parameters();
+            let phys = CecPhysicalAddress::from_bytes([p[0], p[1]]);
+            let _ = events.send(Event::Device(cmd.initiator(), Report::PhysAddr(phys, p[2])));
+        }
+        CecOpcode::DeviceVendorId if cmd.parameters().len() >= 3 => {
+            let p = cmd.parameters();
+            let vendor = u32::from_be_bytes([0, p[0], p[1], p[2]]);
+            let _ = events.send(Event::Device(cmd.initiator(), Report::Vendor(vendor)));
+        }
+        CecOpcode::SetOsdName => {
+            let name = String::from_utf8_lossy(cmd.parameters()).into_owned();
+            let _ = events.send(Event::Device(cmd.initiator(), Report::OsdName(name)));
+        }
+        CecOpcode::CecVersion if cmd.parameters().len() == 1 => {
+            let _ = events.send(Event::Device(cmd.initiator(), Report::Version(cmd.parameters()[0])));
         }
         CecOpcode::GiveDevicePowerStatus
             if cmd.initiator() == CecLogicalAddress::Tv
@@ -126,10 +157,15 @@ fn command(cmd: CecMsg, state: &mut Arc<Mutex<GState>>) {
         CecOpcode::UserControlPressed | CecOpcode::UserControlReleased
             if cmd.initiator() == CecLogicalAddress::Playback2 => {}
         CecOpcode::FeatureAbort if cmd.initiator() == CecLogicalAddress::Playback2 => {}//vendor id
-        CecOpcode::ReportPhysicalAddr | CecOpcode::DeviceVendorId | CecOpcode::GiveDeviceVendorId => {}
+        // the scan
+        CecOpcode::GivePhysicalAddr
+        | CecOpcode::GiveDeviceVendorId
+        | CecOpcode::GiveOsdName
+        | CecOpcode::GetCecVersion => {}
         _ => {
-            println!(
-                "<7>cec cmd: {:?} -> {:?}   {:?}: {:x?}",
+            debug!(
+                initiator:? = cmd.initiator(), destination:? = cmd.destination(), opcode:? = opcode;
+                "cec cmd: {:?} -> {:?}   {:?}: {:x?}",
                 cmd.initiator(),
                 cmd.destination(),
                 opcode,
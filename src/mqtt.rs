@@ -0,0 +1,432 @@
+//! MQTT client for home automation. Needs the `mqtt` feature.
+//!
+//! The events of [crate::feed] are retained topics below the prefix, with JSON payloads:
+//! `cecremote/state` is `"Playing"`, `cecremote/tv` is `true`, `cecremote/outlet/3` is `false`.
+//! `cecremote/source` has the name of the active input, or its address.
+//! `cecremote/availability` is `online`, or `offline` by the last will.
+//!
+//! Commands are lines of the control socket protocol on `cecremote/command`, or the payload
+//! of `cecremote/<words>/set`: `on` to `cecremote/outlet/fan/set` is `outlet fan on`.
+//!
+//! Home Assistant learns about the entities by discovery. It has no MQTT media player,
+//! so the AVR is a number for the volume, a switch for mute and a select for the input.
+use crate::access::Class;
+use crate::config::{Config, Mqtt};
+use crate::event::Control;
+use crate::feed;
+use crate::protocol::{self, Request};
+use crate::sock::Server;
+use log::{debug, info, warn};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// before connecting again
+const RECONNECT: Duration = Duration::from_secs(5);
+/// requests to the broker that may wait
+const CAPACITY: usize = 64;
+
+/// Stay connected to the broker until stopped
+pub fn run(m: &Mqtt, srv: &Server) {
+    let availability = format!("{}/availability", m.prefix);
+    let mut opts = MqttOptions::new(&m.client_id, &m.host, m.port);
+    opts.set_keep_alive(Duration::from_secs(30))
+        .set_last_will(LastWill::new(
+            &availability,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+    if let Some(user) = &m.user {
+        opts.set_credentials(user, m.password.as_deref().unwrap_or_default());
+    }
+    let (client, mut connection) = Client::new(opts, CAPACITY);
+    // the lines of the feed. An empty one asks to publish everything again, after connecting
+    let (lines, events) = mpsc::channel();
+    srv.feed.subscribe_to(vec![], lines.clone());
+    // ends the wait for a reconnect
+    let (stopping, stopped) = mpsc::channel::<()>();
+    let c = client.clone();
+    let a = availability.clone();
+    srv.stop.on_stop(move || {
+        drop(stopping);
+        // the last will is only for connections that break
+        let _ = c.try_publish(a, QoS::AtLeastOnce, true, "offline");
+        let _ = c.try_disconnect();
+    });
+    let (commands, queue) = mpsc::channel();
+    thread::scope(|s| {
+        s.spawn(|| publish(m, srv, &client, events));
+        s.spawn(|| work(m, srv, queue));
+        let mut connected = false;
+        while let Ok(notification) = connection.recv() {
+            match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!(host = m.host; "MQTT connected to {}:{}", m.host, m.port);
+                    connected = true;
+                    let filters = ["+/set", "outlet/+/set", "command"];
+                    for f in filters {
+                        let _ =
+                            client.try_subscribe(format!("{}/{}", m.prefix, f), QoS::AtLeastOnce);
+                    }
+                    let _ = client.try_publish(&availability, QoS::AtLeastOnce, true, "online");
+                    let _ = lines.send(String::new());
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) => {
+                    match command(&m.prefix, &p.topic, &p.payload) {
+                        Some(line) => {
+                            debug!(topic = p.topic, line; "MQTT command");
+                            let _ = commands.send(line);
+                        }
+                        None => debug!(topic = p.topic; "MQTT message ignored"),
+                    }
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                Ok(_) => {}
+                Err(_) if srv.stop.is_stopped() => break,
+                Err(e) => {
+                    if connected {
+                        warn!("MQTT: {}", e);
+                    } else {
+                        debug!("MQTT: {}", e);
+                    }
+                    connected = false;
+                    if stopped.recv_timeout(RECONNECT) != Err(RecvTimeoutError::Timeout) {
+                        break;
+                    }
+                }
+            }
+        }
+        drop(commands);
+        drop(lines);
+    });
+}
+
+/// Execute the commands in order. A volume is skipped if a later one is waiting
+fn work(m: &Mqtt, srv: &Server, commands: Receiver<String>) {
+    let mut queue = VecDeque::new();
+    loop {
+        if queue.is_empty() {
+            match commands.recv() {
+                Ok(c) => queue.push_back(c),
+                Err(_) => return,
+            }
+        }
+        queue.extend(commands.try_iter());
+        let line = queue.pop_front().expect("a command");
+        let c = match protocol::parse(&line, &srv.cfg) {
+            Ok(Request::Control(c)) => c,
+            Ok(_) => {
+                warn!(line; "MQTT: {} is not a command", line);
+                continue;
+            }
+            Err(e) => {
+                warn!(line; "MQTT: {}: {}", line, e);
+                continue;
+            }
+        };
+        if !m.allow.contains(&Class::of(&c)) {
+            warn!(line; "MQTT: {} denied", line);
+            continue;
+        }
+        if matches!(c, Control::Volume(_)) && queue.iter().any(|l| l.starts_with("volume ")) {
+            continue;
+        }
+        if let Err(e) = srv.control(c) {
+            warn!(line; "MQTT: {}: {}", line, e);
+        }
+    }
+}
+
+/// Publish the discovery, the state and then every line of the feed
+fn publish(m: &Mqtt, srv: &Server, client: &Client, lines: Receiver<String>) {
+    // while not connected, what does not fit is lost. It is sent again after connecting
+    let send = |topic: String, payload: String| {
+        if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+            debug!("MQTT publish: {}", e);
+        }
+    };
+    // the feed ends on stop
+    for line in lines {
+        if line.is_empty() {
+            if !m.discovery.is_empty() {
+                for (topic, config) in discovery(m, &srv.cfg) {
+                    send(topic, config.to_string());
+                }
+            }
+            for (name, value) in snapshot(srv) {
+                for (topic, payload) in topics(&m.prefix, &name, &value, &srv.cfg) {
+                    send(topic, payload);
+                }
+            }
+            continue;
+        }
+        let Some((name, value)) = line
+            .trim_end()
+            .strip_prefix("event ")
+            .and_then(|e| e.split_once(' '))
+        else {
+            continue;
+        };
+        let value = serde_json::from_str(value).unwrap_or(Value::Null);
+        for (topic, payload) in topics(&m.prefix, name, &value, &srv.cfg) {
+            send(topic, payload);
+        }
+    }
+}
+
+/// The current values as events
+fn snapshot(srv: &Server) -> Vec<(String, Value)> {
+    let mut events = Vec::new();
+    if let Ok(state) = srv.state(None) {
+        let state: Value = serde_json::from_str(&state).unwrap_or_default();
+        for name in feed::NAMES {
+            if let Some(v) = state.get(name) {
+                events.push((name.to_string(), v.clone()));
+            }
+        }
+    }
+    if let Ok(outlets) = srv.control(Control::Outlets) {
+        let outlets: Value = serde_json::from_str(&outlets).unwrap_or_default();
+        for (n, o) in outlets.as_object().into_iter().flatten() {
+            if let (Ok(n), Some(on)) = (n.parse::<u8>(), o["on"].as_bool()) {
+                events.push(("outlet".to_string(), json!({ "outlet": n, "on": on })));
+            }
+        }
+    }
+    events
+}
+
+/// Topics and payloads for an event
+fn topics(prefix: &str, name: &str, value: &Value, cfg: &Config) -> Vec<(String, String)> {
+    match name {
+        "outlet" => match (value["outlet"].as_u64(), value["on"].as_bool()) {
+            (Some(n), Some(on)) => vec![(format!("{}/outlet/{}", prefix, n), on.to_string())],
+            _ => Vec::new(),
+        },
+        "active_source" => {
+            let source = value
+                .as_str()
+                .and_then(|a| cfg.input_name(a))
+                .map_or(value.clone(), |name| json!(name));
+            vec![
+                (format!("{}/active_source", prefix), value.to_string()),
+                (format!("{}/source", prefix), source.to_string()),
+            ]
+        }
+        _ => vec![(format!("{}/{}", prefix, name), value.to_string())],
+    }
+}
+
+/// The line of a message to a command topic
+fn command(prefix: &str, topic: &str, payload: &[u8]) -> Option<String> {
+    let rest = topic.strip_prefix(prefix)?.strip_prefix('/')?;
+    let payload = std::str::from_utf8(payload).ok()?.trim();
+    if rest == "command" {
+        return Some(payload.to_string());
+    }
+    let words = rest.strip_suffix("/set")?;
+    Some(format!("{} {}", words.replace('/', " "), payload))
+}
+
+/// Home Assistant discovery topics and configs
+fn discovery(m: &Mqtt, cfg: &Config) -> Vec<(String, Value)> {
+    let p = &m.prefix;
+    let device = json!({
+        "identifiers": [m.client_id],
+        "name": cfg.cec.osd_name,
+        "model": "cecremote",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let binary = |name: &str, topic: &str, on: &str, off: &str, class: &str| {
+        json!({
+            "name": name,
+            "state_topic": format!("{}/{}", p, topic),
+            "payload_on": on,
+            "payload_off": off,
+            "device_class": class,
+        })
+    };
+    let switch = |name: String, state: String, command: String| {
+        json!({
+            "name": name,
+            "state_topic": format!("{}/{}", p, state),
+            "state_on": "true",
+            "state_off": "false",
+            "command_topic": format!("{}/{}/set", p, command),
+            "payload_on": "on",
+            "payload_off": "off",
+        })
+    };
+    let mut entities = vec![
+        (
+            "sensor",
+            "state".to_string(),
+            json!({
+                "name": "State",
+                "state_topic": format!("{}/state", p),
+                "value_template": "{{ value_json }}",
+            }),
+        ),
+        (
+            "binary_sensor",
+            "tv".into(),
+            binary("TV", "tv", "true", "false", "power"),
+        ),
+        // standby or off
+        (
+            "binary_sensor",
+            "avr".into(),
+            binary("AVR", "avr_standby", "false", "true", "power"),
+        ),
+        (
+            "binary_sensor",
+            "playing".into(),
+            binary("Playing", "playing", "true", "false", "running"),
+        ),
+        (
+            "number",
+            "volume".into(),
+            json!({
+                "name": "Volume",
+                "state_topic": format!("{}/volume", p),
+                "command_topic": format!("{}/volume/set", p),
+                "min": 1,
+                "max": 100,
+                "unit_of_measurement": "%",
+            }),
+        ),
+        (
+            "switch",
+            "mute".into(),
+            switch("Mute".into(), "muted".into(), "mute".into()),
+        ),
+    ];
+    if !cfg.inputs.is_empty() {
+        entities.push((
+            "select",
+            "source".into(),
+            json!({
+                "name": "Input",
+                "state_topic": format!("{}/source", p),
+                "value_template": "{{ value_json }}",
+                "command_topic": format!("{}/source/set", p),
+                "options": cfg.inputs.keys().collect::<Vec<_>>(),
+            }),
+        ));
+    }
+    for n in 1..=cfg.power.outlets() {
+        let name = cfg
+            .outlets
+            .name(n)
+            .map_or(format!("Outlet {}", n), str::to_string);
+        let topic = format!("outlet/{}", n);
+        let config = switch(name, topic.clone(), topic);
+        entities.push(("switch", format!("outlet_{}", n), config));
+    }
+    entities
+        .into_iter()
+        .map(|(component, obj, mut config)| {
+            config["unique_id"] = format!("{}_{}", m.client_id, obj).into();
+            config["availability_topic"] = format!("{}/availability", p).into();
+            config["device"] = device.clone();
+            let topic = format!(
+                "{}/{}/{}/{}/config",
+                m.discovery, component, m.client_id, obj
+            );
+            (topic, config)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PhysAddr;
+
+    #[test]
+    fn commands() {
+        let c = |t, p: &str| command("cecremote", t, p.as_bytes());
+        assert_eq!(c("cecremote/volume/set", "30"), Some("volume 30".into()));
+        assert_eq!(
+            c("cecremote/outlet/3/set", "on\n"),
+            Some("outlet 3 on".into())
+        );
+        assert_eq!(
+            c("cecremote/command", "outlet fan off in 10m"),
+            Some("outlet fan off in 10m".into())
+        );
+        assert_eq!(c("cecremote/volume", "30"), None);
+        assert_eq!(c("cecremotes/volume/set", "30"), None);
+        assert_eq!(c("other/volume/set", "30"), None);
+    }
+
+    #[test]
+    fn events() {
+        let mut cfg = Config::default();
+        cfg.inputs.insert(
+            "deck".into(),
+            PhysAddr::try_from("3.5.0.0".to_string()).unwrap(),
+        );
+        let t = |name, value| topics("c", name, &value, &cfg);
+        assert_eq!(
+            t("state", json!("Playing")),
+            [("c/state".into(), "\"Playing\"".into())]
+        );
+        assert_eq!(
+            t("outlet", json!({ "outlet": 3, "on": false })),
+            [("c/outlet/3".into(), "false".into())]
+        );
+        assert_eq!(
+            t("active_source", json!("3.5.0.0"))[1],
+            ("c/source".into(), "\"deck\"".into())
+        );
+        assert_eq!(
+            t("active_source", json!("3.6.0.0"))[1],
+            ("c/source".into(), "\"3.6.0.0\"".into())
+        );
+    }
+
+    #[test]
+    fn home_assistant() {
+        let mut cfg = Config::default();
+        cfg.outlets.names.insert("fan".into(), 3);
+        let m = Mqtt::default();
+        let d = discovery(&m, &cfg);
+        let (topic, fan) = d
+            .iter()
+            .find(|(t, _)| t.ends_with("outlet_3/config"))
+            .unwrap();
+        assert_eq!(topic, "homeassistant/switch/cecremote/outlet_3/config");
+        assert_eq!(fan["name"], "fan");
+        assert_eq!(fan["command_topic"], "cecremote/outlet/3/set");
+        assert_eq!(fan["availability_topic"], "cecremote/availability");
+        assert_eq!(fan["unique_id"], "cecremote_outlet_3");
+        let four = &d.iter().find(|(t, _)| t.contains("outlet_4")).unwrap().1;
+        assert_eq!(four["name"], "Outlet 4");
+        let avr = &d
+            .iter()
+            .find(|(t, _)| t.ends_with("/avr/config"))
+            .unwrap()
+            .1;
+        assert_eq!(avr["state_topic"], "cecremote/avr_standby");
+        assert_eq!(avr["payload_on"], "false");
+        // no inputs, no select
+        assert!(!d.iter().any(|(t, _)| t.contains("/select/")));
+        // a command to each command topic is understood
+        for (_, c) in &d {
+            if let Some(t) = c["command_topic"].as_str() {
+                let line = command("cecremote", t, b"on").unwrap();
+                let first = line.split(' ').next().unwrap();
+                assert!(
+                    ["volume", "mute", "outlet", "source"].contains(&first),
+                    "{}",
+                    t
+                );
+            }
+        }
+    }
+}
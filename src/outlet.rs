@@ -0,0 +1,130 @@
+//! Outlets switched from the control socket.
+//!
+//! Each outlet can have one [Timer], run by the main loop.
+//! Clients switch in their own thread, as a plug may take its time to answer.
+//! Switching the AVR outlet by hand is something the [crate::state] machine has to hear
+//! about, see [crate::event::Event::OutletSwitched].
+use crate::config::Config;
+use crate::power::PowerSwitch;
+use log::info;
+use std::collections::BTreeMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// a planned switch
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timer {
+    /// None toggles
+    pub on: Option<bool>,
+    pub at: Instant,
+}
+
+/// Timers by outlet
+#[derive(Default, Clone)]
+pub struct Schedule(BTreeMap<u8, Timer>);
+impl Schedule {
+    /// switch `n` after `after`. Replaces the timer it had
+    pub fn set(&mut self, n: u8, on: Option<bool>, after: Duration) {
+        let at = Instant::now() + after;
+        info!(target: "power", outlet = n; "switch {} {} in {:?}", n, words(on), after);
+        self.0.insert(n, Timer { on, at });
+    }
+    /// false if there was none
+    pub fn cancel(&mut self, n: u8) -> bool {
+        self.0.remove(&n).is_some()
+    }
+    /// when the next timer is due
+    pub fn next(&self) -> Option<Instant> {
+        self.0.values().map(|t| t.at).min()
+    }
+    /// remove the timers that are due
+    pub fn due(&mut self, now: Instant) -> Vec<(u8, Option<bool>)> {
+        let due: Vec<_> = self
+            .0
+            .iter()
+            .filter(|(_, t)| t.at <= now)
+            .map(|(&n, t)| (n, t.on))
+            .collect();
+        for (n, _) in &due {
+            self.0.remove(n);
+        }
+        due
+    }
+}
+
+/// Switch outlet `n`, toggle it for None. Returns whether it is on now
+pub fn switch(pwr: &dyn PowerSwitch, n: u8, on: Option<bool>) -> io::Result<bool> {
+    let on = match on {
+        Some(on) => on,
+        None => !pwr.get_status(n)?,
+    };
+    info!(target: "power", outlet = n, on; "switch {} {}", n, on);
+    pwr.set_status(n, on)?;
+    Ok(on)
+}
+
+/// Name, status and timer of every outlet by number.
+/// `on` is null if the status can not be read
+pub fn status(pwr: &dyn PowerSwitch, cfg: &Config, schedule: &Schedule) -> serde_json::Value {
+    let now = Instant::now();
+    let outlets = (1..=cfg.power.outlets())
+        .map(|n| {
+            let mut o = serde_json::json!({
+                "name": cfg.outlets.name(n),
+                "on": pwr.get_status(n).ok(),
+            });
+            if let Some(t) = schedule.0.get(&n) {
+                o["switch"] = words(t.on).into();
+                o["in"] = (t.at.saturating_duration_since(now).as_millis() as f64 / 1000.0).into();
+            }
+            (n.to_string(), o)
+        })
+        .collect();
+    serde_json::Value::Object(outlets)
+}
+
+fn words(on: Option<bool>) -> &'static str {
+    match on {
+        Some(true) => "on",
+        Some(false) => "off",
+        None => "toggle",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::power::Mock;
+
+    #[test]
+    fn timers() {
+        let mut s = Schedule::default();
+        s.set(1, Some(false), Duration::ZERO);
+        s.set(3, None, Duration::from_secs(60));
+        assert!(s.next().is_some_and(|t| t <= Instant::now()));
+        assert_eq!(s.due(Instant::now()), vec![(1, Some(false))]);
+        assert!(s.due(Instant::now()).is_empty());
+        assert!(s.cancel(3));
+        assert!(!s.cancel(3));
+        assert_eq!(s.next(), None);
+    }
+
+    #[test]
+    fn toggle() {
+        let pwr = Mock::new(4);
+        assert!(switch(&pwr, 3, None).unwrap());
+        assert!(!switch(&pwr, 3, None).unwrap());
+        assert!(switch(&pwr, 3, Some(true)).unwrap());
+        let mut cfg = Config::default();
+        cfg.outlets.names.insert("fan".into(), 3);
+        let mut s = Schedule::default();
+        s.set(1, Some(false), Duration::from_secs(60));
+        let v = status(&pwr, &cfg, &s);
+        assert_eq!(v["1"]["name"], "light");
+        assert_eq!(v["1"]["switch"], "off");
+        assert_eq!(v["2"]["on"], false);
+        assert_eq!(v["3"]["name"], "fan");
+        assert_eq!(v["3"]["on"], true);
+        assert_eq!(v["4"]["name"], serde_json::Value::Null);
+    }
+}
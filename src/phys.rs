@@ -0,0 +1,271 @@
+//! Our own physical address.
+//!
+//! The kernel gets it from the EDID on hotplug. When the TV is off, that is sometimes
+//! the one of the AVR (3.0.0.0 instead of 3.3.0.0).
+//! [PhysAddr] learns the right one and spots the wrong one.
+use crate::config::PhysAddr as Parsed;
+use cec_linux::{CecLogicalAddress, CecPhysicalAddress};
+use std::path::{Path, PathBuf};
+
+/// file in `$STATE_DIRECTORY` to remember the last good address
+const STATE_FILE: &str = "phys_addr";
+
+/// What is known about our physical address
+pub struct PhysAddr {
+    /// from the config. Always wins
+    configured: Option<CecPhysicalAddress>,
+    /// EDID to read. None: search /sys/class/drm
+    edid: Option<PathBuf>,
+    /// the last one that looked right
+    last_good: Option<CecPhysicalAddress>,
+    /// the one the kernel uses
+    current: CecPhysicalAddress,
+    /// physical addresses reported by other devices
+    others: Vec<(CecLogicalAddress, CecPhysicalAddress)>,
+}
+
+/// Result of [PhysAddr::connected]
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Good,
+    /// the kernel uses a wrong one. This is the expected one
+    Wrong(CecPhysicalAddress),
+}
+
+impl PhysAddr {
+    pub fn new(configured: Option<CecPhysicalAddress>, edid: Option<PathBuf>) -> PhysAddr {
+        PhysAddr {
+            configured,
+            edid,
+            last_good: load(),
+            current: CecPhysicalAddress::INVALID,
+            others: Vec::new(),
+        }
+    }
+    /// The address to put in messages.
+    /// The expected one if the kernel uses a wrong one
+    pub fn get(&self) -> CecPhysicalAddress {
+        if self.current != CecPhysicalAddress::INVALID && self.is_plausible(self.current) {
+            return self.current;
+        }
+        self.expected().unwrap_or(self.current)
+    }
+    /// the kernel claimed a logical address with `phys`
+    pub fn connected(&mut self, phys: CecPhysicalAddress) -> Verdict {
+        self.current = phys;
+        self.check()
+    }
+    /// is the address the kernel uses right?
+    pub fn check(&mut self) -> Verdict {
+        let phys = self.current;
+        if phys == CecPhysicalAddress::INVALID {
+            return Verdict::Good;
+        }
+        if self.is_plausible(phys) {
+            if self.last_good != Some(phys) {
+                self.last_good = Some(phys);
+                store(phys);
+            }
+            return Verdict::Good;
+        }
+        match self.expected() {
+            Some(e) if e != phys => Verdict::Wrong(e),
+            _ => Verdict::Good,
+        }
+    }
+    /// the one the kernel uses
+    pub fn current(&self) -> CecPhysicalAddress {
+        self.current
+    }
+    pub fn disconnected(&mut self) {
+        self.current = CecPhysicalAddress::INVALID;
+    }
+    /// another device reported its physical address
+    pub fn reported(&mut self, from: CecLogicalAddress, phys: CecPhysicalAddress) {
+        match self.others.iter_mut().find(|(l, _)| *l == from) {
+            Some(o) => o.1 = phys,
+            None => self.others.push((from, phys)),
+        }
+    }
+    /// what it should be
+    fn expected(&self) -> Option<CecPhysicalAddress> {
+        if self.configured.is_some() {
+            return self.configured;
+        }
+        // the EDID might be wrong for the same reason the kernel is
+        read_edid(self.edid.as_deref())
+            .into_iter()
+            .chain(self.last_good)
+            .find(|&e| !self.is_taken(e))
+    }
+    fn is_plausible(&self, phys: CecPhysicalAddress) -> bool {
+        match self.configured {
+            Some(c) => c == phys,
+            None => !self.is_taken(phys),
+        }
+    }
+    /// used by another device
+    fn is_taken(&self, phys: CecPhysicalAddress) -> bool {
+        self.others.iter().any(|&(_, p)| p == phys)
+    }
+}
+
+fn state_file() -> Option<PathBuf> {
+    std::env::var_os("STATE_DIRECTORY").map(|d| Path::new(&d).join(STATE_FILE))
+}
+fn load() -> Option<CecPhysicalAddress> {
+    let s = std::fs::read_to_string(state_file()?).ok()?;
+    Parsed::try_from(s.trim().to_string()).ok().map(|p| p.0)
+}
+fn store(phys: CecPhysicalAddress) {
+    if let Some(f) = state_file() {
+        if let Err(e) = std::fs::write(&f, format!("{}\n", Parsed(phys))) {
+            log::warn!("could not write {}: {}", f.display(), e);
+        }
+    }
+}
+
+/// Physical address from an EDID file. Without a path the connectors in /sys/class/drm are searched
+fn read_edid(path: Option<&Path>) -> Option<CecPhysicalAddress> {
+    match path {
+        Some(p) => edid_phys_addr(&std::fs::read(p).ok()?),
+        None => std::fs::read_dir("/sys/class/drm")
+            .ok()?
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().contains("-HDMI-A-"))
+            .find_map(|e| edid_phys_addr(&std::fs::read(e.path().join("edid")).ok()?)),
+    }
+}
+
+/// Find the physical address in the HDMI vendor specific data block of an EDID
+pub fn edid_phys_addr(edid: &[u8]) -> Option<CecPhysicalAddress> {
+    // 128 byte blocks. Extensions follow the base block
+    for ext in edid.chunks_exact(128).skip(1) {
+        // CTA-861 extension
+        if ext[0] != 0x02 {
+            continue;
+        }
+        // data blocks are between byte 4 and the first detailed timing descriptor
+        let end = (ext[2] as usize).min(127);
+        let mut i = 4;
+        while i < end {
+            let tag = ext[i] >> 5;
+            let len = (ext[i] & 0x1f) as usize;
+            let block = ext.get(i + 1..i + 1 + len)?;
+            // vendor specific with the IEEE OUI of HDMI Licensing
+            if tag == 3 && len >= 5 && block[..3] == [0x03, 0x0c, 0x00] {
+                let phys = CecPhysicalAddress::from_bytes([block[3], block[4]]);
+                return Some(phys).filter(|&p| p != CecPhysicalAddress::INVALID);
+            }
+            i += 1 + len;
+        }
+    }
+    None
+}
+
+/// the device we are plugged into: 3.3.0.0 -> 3.0.0.0
+pub fn parent(phys_addr: CecPhysicalAddress) -> CecPhysicalAddress {
+    let n = phys_addr.to_num();
+    let mask = (0..4)
+        .map(|i| 0xf << (i * 4))
+        .find(|m| n & m != 0)
+        .unwrap_or(0);
+    CecPhysicalAddress::from_num(n & !mask)
+}
+
+/// is `phys_addr` behind `above`? 3.2.0.0 is behind 3.0.0.0 and 0.0.0.0
+pub fn is_below(phys_addr: CecPhysicalAddress, above: CecPhysicalAddress) -> bool {
+    let mut p = phys_addr;
+    while p.to_num() != 0 {
+        p = parent(p);
+        if p == above {
+            return true;
+        }
+    }
+    false
+}
+
+/// the device at `port` of `parent`: 3.0.0.0 and 2 -> 3.2.0.0
+pub fn child(parent: CecPhysicalAddress, port: u8) -> CecPhysicalAddress {
+    let n = parent.to_num();
+    let shift = (0..4)
+        .rev()
+        .map(|i| i * 4)
+        .find(|s| n & (0xf << s) == 0)
+        .unwrap_or(0);
+    CecPhysicalAddress::from_num(n | ((port as u16 & 0xf) << shift))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(n: u16) -> CecPhysicalAddress {
+        CecPhysicalAddress::from_num(n)
+    }
+
+    #[test]
+    fn edid() {
+        let mut edid = vec![0u8; 256];
+        edid[126] = 1; // extension count
+        let ext = &mut edid[128..];
+        ext[0] = 0x02;
+        ext[1] = 0x03;
+        // audio block, then the HDMI VSDB
+        ext[4..8].copy_from_slice(&[0x23, 0x09, 0x07, 0x07]);
+        ext[8..14].copy_from_slice(&[0x65, 0x03, 0x0c, 0x00, 0x33, 0x00]);
+        ext[2] = 14;
+        assert_eq!(edid_phys_addr(&edid), Some(addr(0x3300)));
+        // no extension
+        assert_eq!(edid_phys_addr(&edid[..128]), None);
+        edid[136] = 0xff;
+        edid[137] = 0xff;
+        assert_eq!(edid_phys_addr(&edid), None);
+    }
+
+    #[test]
+    fn tree() {
+        assert_eq!(parent(addr(0x3300)), addr(0x3000));
+        assert_eq!(parent(addr(0x3000)), addr(0x0000));
+        assert_eq!(parent(addr(0x1234)), addr(0x1230));
+        assert_eq!(child(addr(0x3000), 2), addr(0x3200));
+        assert_eq!(child(addr(0x0000), 3), addr(0x3000));
+        assert!(is_below(addr(0x3200), addr(0x3000)));
+        assert!(is_below(addr(0x3200), addr(0x0000)));
+        assert!(!is_below(addr(0x3000), addr(0x3000)));
+        assert!(!is_below(addr(0x1200), addr(0x3000)));
+    }
+
+    #[test]
+    fn taken_by_avr() {
+        let mut p = PhysAddr {
+            configured: None,
+            edid: Some(PathBuf::from("/nonexistent")),
+            last_good: None,
+            current: CecPhysicalAddress::INVALID,
+            others: Vec::new(),
+        };
+        // nothing to compare with
+        assert_eq!(p.connected(addr(0x3000)), Verdict::Good);
+        p.connected(addr(0x3300));
+        assert_eq!(p.get(), addr(0x3300));
+        p.reported(CecLogicalAddress::Audiosystem, addr(0x3000));
+        assert_eq!(p.connected(addr(0x3000)), Verdict::Wrong(addr(0x3300)));
+        assert_eq!(p.get(), addr(0x3300));
+    }
+
+    #[test]
+    fn configured_wins() {
+        let mut p = PhysAddr {
+            configured: Some(addr(0x3300)),
+            edid: Some(PathBuf::from("/nonexistent")),
+            last_good: Some(addr(0x1000)),
+            current: CecPhysicalAddress::INVALID,
+            others: Vec::new(),
+        };
+        assert_eq!(p.get(), addr(0x3300));
+        assert_eq!(p.connected(addr(0x3000)), Verdict::Wrong(addr(0x3300)));
+        assert_eq!(p.connected(addr(0x3300)), Verdict::Good);
+        assert_eq!(p.last_good, Some(addr(0x3300)));
+    }
+}
@@ -0,0 +1,391 @@
+use crate::config::{HttpFlavor, Power};
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use sispm::GlobalSiSPM;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Something that can switch the mains of numbered outlets.
+///
+/// Outlets are numbered starting with 1 like on the sispm
+pub trait PowerSwitch: Send {
+    fn set_status(&self, num: u8, on: bool) -> std::io::Result<()>;
+    fn get_status(&self, num: u8) -> std::io::Result<bool>;
+}
+
+/// open the backend selected in the config
+pub fn open(cfg: &Power) -> std::io::Result<Box<dyn PowerSwitch>> {
+    Ok(match cfg {
+        Power::Sispm => Box::new(
+            sispm::get_devices()
+                .map_err(std::io::Error::other)?
+                .pop()
+                .ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::NotFound, "no sispm connected")
+                })?,
+        ),
+        Power::Gpio {
+            chip,
+            lines,
+            active_low,
+        } => Box::new(Gpio::open(chip, lines, *active_low)?),
+        Power::Http {
+            flavor,
+            host,
+            outlets,
+        } => Box::new(HttpPlug {
+            flavor: *flavor,
+            host: host.clone(),
+            outlets: *outlets,
+        }),
+        Power::Mock { outlets } => Box::new(Mock::new(*outlets)),
+    })
+}
+
+impl PowerSwitch for GlobalSiSPM {
+    fn set_status(&self, num: u8, on: bool) -> std::io::Result<()> {
+        GlobalSiSPM::set_status(self, num, on).map_err(std::io::Error::other)
+    }
+    fn get_status(&self, num: u8) -> std::io::Result<bool> {
+        GlobalSiSPM::get_status(self, num).map_err(std::io::Error::other)
+    }
+}
+
+/// Relays on GPIO lines of a gpiochip character device
+pub struct Gpio {
+    lines: Vec<LineHandle>,
+}
+impl Gpio {
+    fn open(chip: &std::path::Path, offsets: &[u32], active_low: bool) -> std::io::Result<Gpio> {
+        let mut chip = Chip::new(chip).map_err(std::io::Error::other)?;
+        let flags = |f| {
+            if active_low {
+                f | LineRequestFlags::ACTIVE_LOW
+            } else {
+                f
+            }
+        };
+        let mut lines = Vec::with_capacity(offsets.len());
+        for &o in offsets {
+            let line = chip.get_line(o).map_err(std::io::Error::other)?;
+            // keep the relay as it is. Requesting an output sets the value
+            let cur = line
+                .request(flags(LineRequestFlags::INPUT), 0, "cecremote")
+                .and_then(|h| h.get_value())
+                .map_err(std::io::Error::other)?;
+            lines.push(
+                line.request(flags(LineRequestFlags::OUTPUT), cur, "cecremote")
+                    .map_err(std::io::Error::other)?,
+            );
+        }
+        Ok(Gpio { lines })
+    }
+    fn line(&self, num: u8) -> std::io::Result<&LineHandle> {
+        num.checked_sub(1)
+            .and_then(|i| self.lines.get(i as usize))
+            .ok_or_else(|| std::io::ErrorKind::InvalidInput.into())
+    }
+}
+impl PowerSwitch for Gpio {
+    fn set_status(&self, num: u8, on: bool) -> std::io::Result<()> {
+        self.line(num)?
+            .set_value(on as u8)
+            .map_err(std::io::Error::other)
+    }
+    fn get_status(&self, num: u8) -> std::io::Result<bool> {
+        Ok(self.line(num)?.get_value().map_err(std::io::Error::other)? != 0)
+    }
+}
+
+/// Smart plug with a HTTP API
+pub struct HttpPlug {
+    flavor: HttpFlavor,
+    host: String,
+    outlets: u8,
+}
+impl HttpPlug {
+    const TIMEOUT: Duration = Duration::from_secs(2);
+    fn check(&self, num: u8) -> std::io::Result<()> {
+        if (1..=self.outlets).contains(&num) {
+            Ok(())
+        } else {
+            Err(std::io::ErrorKind::InvalidInput.into())
+        }
+    }
+    /// GET `path` and return the body
+    fn get(&self, path: &str) -> std::io::Result<String> {
+        let addr = with_port(&self.host);
+        let sock_addr = std::net::ToSocketAddrs::to_socket_addrs(&addr)?
+            .next()
+            .ok_or(std::io::ErrorKind::NotFound)?;
+        let mut s = TcpStream::connect_timeout(&sock_addr, Self::TIMEOUT)?;
+        s.set_read_timeout(Some(Self::TIMEOUT))?;
+        s.set_write_timeout(Some(Self::TIMEOUT))?;
+        write!(
+            s,
+            "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, addr
+        )?;
+        let mut resp = String::new();
+        s.read_to_string(&mut resp)?;
+        let (head, body) = resp
+            .split_once("\r\n\r\n")
+            .ok_or(std::io::ErrorKind::InvalidData)?;
+        if head.split(' ').nth(1) != Some("200") {
+            return Err(std::io::Error::other(format!(
+                "{}: {}",
+                path,
+                head.lines().next().unwrap_or_default()
+            )));
+        }
+        Ok(body.to_string())
+    }
+    /// find the relay state in a response
+    fn parse(&self, num: u8, body: &str) -> std::io::Result<bool> {
+        let json: serde_json::Value = serde_json::from_str(body)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let on = match self.flavor {
+            // {"POWER1":"ON"} or {"POWER":"ON"} if there is only one relay
+            HttpFlavor::Tasmota => json
+                .get(format!("POWER{num}"))
+                .or_else(|| json.get("POWER"))
+                .and_then(|v| v.as_str())
+                .map(|v| v == "ON"),
+            // {"ison": true, ...}
+            HttpFlavor::Shelly => json.get("ison").and_then(|v| v.as_bool()),
+        };
+        on.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, body.to_string()))
+    }
+}
+impl PowerSwitch for HttpPlug {
+    fn set_status(&self, num: u8, on: bool) -> std::io::Result<()> {
+        self.check(num)?;
+        let path = match self.flavor {
+            HttpFlavor::Tasmota => {
+                format!("/cm?cmnd=Power{}%20{}", num, if on { "On" } else { "Off" })
+            }
+            HttpFlavor::Shelly => {
+                format!("/relay/{}?turn={}", num - 1, if on { "on" } else { "off" })
+            }
+        };
+        let body = self.get(&path)?;
+        if self.parse(num, &body)? == on {
+            Ok(())
+        } else {
+            Err(std::io::Error::other(format!(
+                "outlet {num} did not switch"
+            )))
+        }
+    }
+    fn get_status(&self, num: u8) -> std::io::Result<bool> {
+        self.check(num)?;
+        let path = match self.flavor {
+            HttpFlavor::Tasmota => format!("/cm?cmnd=Power{num}"),
+            HttpFlavor::Shelly => format!("/relay/{}", num - 1),
+        };
+        let body = self.get(&path)?;
+        self.parse(num, &body)
+    }
+}
+
+/// `host` with port 80 if it has none. An IPv6 address needs brackets to have a port
+fn with_port(host: &str) -> String {
+    if host.parse::<SocketAddr>().is_ok() {
+        return host.to_string();
+    }
+    if let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        return SocketAddr::new(ip, 80).to_string();
+    }
+    match host.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+        _ => format!("{host}:80"),
+    }
+}
+
+/// A [PowerSwitch] for several threads. One switch at a time
+pub struct Shared(Mutex<Box<dyn PowerSwitch>>);
+impl Shared {
+    pub fn new(inner: Box<dyn PowerSwitch>) -> Shared {
+        Shared(Mutex::new(inner))
+    }
+}
+impl PowerSwitch for Shared {
+    fn set_status(&self, num: u8, on: bool) -> std::io::Result<()> {
+        self.0.lock().unwrap().set_status(num, on)
+    }
+    fn get_status(&self, num: u8) -> std::io::Result<bool> {
+        self.0.lock().unwrap().get_status(num)
+    }
+}
+
+/// Outlets that only exist in memory
+pub struct Mock {
+    outlets: Mutex<Vec<bool>>,
+}
+impl Mock {
+    pub fn new(outlets: u8) -> Mock {
+        Mock {
+            outlets: Mutex::new(vec![false; outlets as usize]),
+        }
+    }
+}
+impl PowerSwitch for Mock {
+    fn set_status(&self, num: u8, on: bool) -> std::io::Result<()> {
+        let mut o = self.outlets.lock().unwrap();
+        let o = num
+            .checked_sub(1)
+            .and_then(|i| o.get_mut(i as usize))
+            .ok_or(std::io::ErrorKind::InvalidInput)?;
+        log::info!(outlet = num, on; "mock outlet {num}: {on}");
+        *o = on;
+        Ok(())
+    }
+    fn get_status(&self, num: u8) -> std::io::Result<bool> {
+        num.checked_sub(1)
+            .and_then(|i| self.outlets.lock().unwrap().get(i as usize).copied())
+            .ok_or_else(|| std::io::ErrorKind::InvalidInput.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// a plug that answers each request with the next of `replies`. The thread returns the request lines
+    fn stub(
+        flavor: HttpFlavor,
+        replies: Vec<String>,
+    ) -> (HttpPlug, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let plug = HttpPlug {
+            flavor,
+            host: listener.local_addr().unwrap().to_string(),
+            outlets: 2,
+        };
+        let requests = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for reply in replies {
+                let (mut s, _) = listener.accept().unwrap();
+                let mut head = BufReader::new(&s).lines().map(Result::unwrap);
+                requests.push(head.next().unwrap());
+                head.take_while(|l| !l.is_empty()).for_each(drop);
+                s.write_all(reply.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (plug, requests)
+    }
+
+    fn ok(body: &str) -> String {
+        format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{body}")
+    }
+
+    #[test]
+    fn tasmota() {
+        let (plug, requests) = stub(
+            HttpFlavor::Tasmota,
+            vec![
+                ok(r#"{"POWER2":"ON"}"#),
+                ok(r#"{"POWER":"OFF"}"#),
+                ok(r#"{"POWER1":"ON"}"#),
+            ],
+        );
+        assert!(plug.set_status(2, true).is_ok());
+        assert!(!plug.get_status(1).unwrap());
+        let e = plug.set_status(1, false).unwrap_err();
+        assert_eq!(e.to_string(), "outlet 1 did not switch");
+        assert_eq!(
+            requests.join().unwrap(),
+            [
+                "GET /cm?cmnd=Power2%20On HTTP/1.0",
+                "GET /cm?cmnd=Power1 HTTP/1.0",
+                "GET /cm?cmnd=Power1%20Off HTTP/1.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn shelly() {
+        let (plug, requests) = stub(
+            HttpFlavor::Shelly,
+            vec![
+                ok(r#"{"ison":true,"source":"http"}"#),
+                ok(r#"{"ison":false}"#),
+                ok(r#"{"error":"bad"}"#),
+            ],
+        );
+        assert!(plug.get_status(2).unwrap());
+        assert!(plug.set_status(1, false).is_ok());
+        let e = plug.get_status(1).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            requests.join().unwrap(),
+            [
+                "GET /relay/1 HTTP/1.0",
+                "GET /relay/0?turn=off HTTP/1.0",
+                "GET /relay/0 HTTP/1.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_replies() {
+        let (plug, requests) = stub(
+            HttpFlavor::Tasmota,
+            vec![
+                "HTTP/1.1 401 Unauthorized\r\n\r\n{}".into(),
+                "HTTP/1.1 200 OK\r\n{}".into(),
+            ],
+        );
+        let e = plug.get_status(1).unwrap_err();
+        assert_eq!(e.to_string(), "/cm?cmnd=Power1: HTTP/1.1 401 Unauthorized");
+        let e = plug.get_status(1).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        requests.join().unwrap();
+        // not asked
+        for n in [0, 3] {
+            assert_eq!(
+                plug.get_status(n).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(with_port("plug"), "plug:80");
+        assert_eq!(with_port("plug:8080"), "plug:8080");
+        assert_eq!(with_port("192.168.1.5"), "192.168.1.5:80");
+        assert_eq!(with_port("192.168.1.5:81"), "192.168.1.5:81");
+        assert_eq!(with_port("fe80::1"), "[fe80::1]:80");
+        assert_eq!(with_port("[fe80::1]"), "[fe80::1]:80");
+        assert_eq!(with_port("[fe80::1]:81"), "[fe80::1]:81");
+    }
+
+    #[test]
+    fn mock() {
+        let m = Mock::new(2);
+        assert!(!m.get_status(2).unwrap());
+        m.set_status(2, true).unwrap();
+        assert!(m.get_status(2).unwrap());
+        assert!(!m.get_status(1).unwrap());
+        for n in [0, 3] {
+            assert_eq!(
+                m.set_status(n, true).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidInput
+            );
+            assert_eq!(
+                m.get_status(n).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidInput
+            );
+        }
+    }
+}
@@ -0,0 +1,446 @@
+//! What is spoken on the control socket.
+//!
+//! A connection starts with `hello <version>` and is answered with `ok <version>`,
+//! the version both sides speak. After that, every line is a command and gets one response:
+//! `ok` with an optional result or `error <reason>`.
+//! Lines can be sent without waiting for the answers, which come in order.
+//! Clients are served at the same time, so a slow command only delays its own connection.
+//! Of several `volume` lines waiting, only the last is done. The others are answered with
+//! `error superseded by a later volume`.
+//!
+//! - `volume <1-100>` set the AVR volume. Answers the volume reached
+//! - `mute [on|off|toggle]` mute or unmute the AVR. Answers `muted` or `unmuted`
+//! - `outlet <n|name> <on|off|toggle>` switch a power socket. Answers `on` or `off`
+//! - `outlet <n|name> <on|off|toggle> in <time>` do it later, like `in 90`, `in 30s`, `in 10m`
+//!   or `in 2h`. An outlet has one timer, a new one replaces it
+//! - `outlet <n|name> cancel` forget the timer
+//! - `outlet <n|name>` is it `on` or `off`?
+//! - `outlet` name, status and timer of every outlet, as JSON object by number.
+//!   Names are `light`, `avr` and those in the config
+//! - `source <port|name|address>` show port x of the AVR (3.x.0.0), an input named in the config
+//!   or the device at an address like `3.2.0.0`. Wakes the TV and answers the address
+//!   once the device is the active source
+//! - `cec <to> <opcode> [byte...] [reply <opcode> [ms]]` send a message from our logical address.
+//!   Numbers are decimal or hex with `0x`, opcodes can be named like `GiveAudioStatus`.
+//!   With `reply`, wait for that opcode (or a FeatureAbort) up to `ms`, at most and by default 1000,
+//!   and answer it as JSON object with `from`, `opcode` and `data`
+//! - `state` what the daemon believes, as JSON object
+//! - `state <field>` a single field of it, as JSON
+//! - `subscribe [name...]` turn the connection into a feed of `event <name> <json>` lines,
+//!   see [crate::feed]. All events without names
+//!
+//! Connections that do not start with `h` send a single byte instead, see [legacy].
+use crate::cec::Frame;
+use crate::config::{Config, PhysAddr};
+use crate::event::Control;
+use crate::feed;
+use cec_linux::{CecLogicalAddress, CecOpcode};
+use std::time::Duration;
+
+/// the newest version we speak
+pub const VERSION: u32 = 1;
+/// longest wait for a `cec` reply, as long as CEC allows a follower to take.
+/// The connection holds the bus while it waits, which holds up the volume of other clients
+const MAX_REPLY_MS: u64 = 1000;
+/// latest outlet timer
+const MAX_TIMER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Result of a command: `ok` with text or `error` with the reason
+pub type Response = Result<String, String>;
+
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    /// handshake with the version of the client
+    Hello(u32),
+    Control(Control),
+    /// all of the state or a single field
+    State(Option<String>),
+    /// events with these names. All if empty
+    Subscribe(Vec<String>),
+}
+
+/// Parse a line. Outlets and inputs can be named as in the config
+pub fn parse(line: &str, cfg: &Config) -> Result<Request, String> {
+    let mut words = line.split_whitespace();
+    let cmd = words.next().ok_or("empty line")?;
+    let args: Vec<&str> = words.collect();
+    let req = match (cmd, &args[..]) {
+        ("hello", [v]) => Request::Hello(number(v)?),
+        ("volume", [v]) => match number(v)? {
+            v @ 1..=100 => Request::Control(Control::Volume(v)),
+            _ => return Err("volume must be 1-100".into()),
+        },
+        ("mute", []) => Request::Control(Control::Mute(Some(true))),
+        ("mute", [m]) => Request::Control(Control::Mute(match *m {
+            "on" => Some(true),
+            "off" => Some(false),
+            "toggle" => None,
+            _ => return Err(format!("expected on, off or toggle, got {}", m)),
+        })),
+        ("outlet", []) => Request::Control(Control::Outlets),
+        ("outlet", [n]) => Request::Control(Control::OutletStatus(outlet(n, cfg)?)),
+        ("outlet", [n, "cancel"]) => Request::Control(Control::OutletCancel(outlet(n, cfg)?)),
+        ("outlet", [n, on]) => Request::Control(Control::Outlet(outlet(n, cfg)?, switch(on)?)),
+        ("outlet", [n, on, "in", t]) => Request::Control(Control::OutletTimer(
+            outlet(n, cfg)?,
+            switch(on)?,
+            duration(t)?,
+        )),
+        ("source", [p]) => match cfg.inputs.get(*p) {
+            Some(a) => Request::Control(Control::Input(a.0)),
+            None if p.contains('.') => {
+                Request::Control(Control::Input(PhysAddr::try_from(p.to_string())?.0))
+            }
+            None => match number(p).map_err(|_| format!("unknown input {}", p))? {
+                p @ 1..=7 => Request::Control(Control::ActiveSource(p)),
+                _ => return Err("port must be 1-7".into()),
+            },
+        },
+        ("cec", [to, opcode, rest @ ..]) => {
+            let to = CecLogicalAddress::try_from(number::<u8>(to)?)
+                .map_err(|_| format!("no logical address: {}", to))?;
+            let (data, reply) = match rest.iter().position(|w| *w == "reply") {
+                Some(i) => (&rest[..i], Some(reply(&rest[i + 1..])?)),
+                None => (rest, None),
+            };
+            if data.len() > 14 {
+                return Err("at most 14 parameters".into());
+            }
+            let data = data.iter().map(|b| number(b)).collect::<Result<_, _>>()?;
+            Request::Control(Control::Cec {
+                to,
+                opcode: cec_opcode(opcode)?,
+                data,
+                reply,
+            })
+        }
+        ("state", []) => Request::State(None),
+        ("state", [f]) => Request::State(Some(f.to_string())),
+        ("subscribe", names) => {
+            if let Some(n) = names.iter().find(|n| !feed::NAMES.contains(n)) {
+                return Err(format!("unknown event {}", n));
+            }
+            Request::Subscribe(names.iter().map(|n| n.to_string()).collect())
+        }
+        ("hello" | "volume" | "mute" | "outlet" | "source" | "cec" | "state", _) => {
+            return Err(format!("wrong arguments for {}", cmd))
+        }
+        _ => return Err(format!("unknown command {}", cmd)),
+    };
+    Ok(req)
+}
+
+/// decimal or hex with 0x
+fn number<T: TryFrom<u32>>(s: &str) -> Result<T, String> {
+    match s.strip_prefix("0x") {
+        Some(h) => u32::from_str_radix(h, 16).ok(),
+        None => s.parse().ok(),
+    }
+    .and_then(|n| T::try_from(n).ok())
+    .ok_or_else(|| format!("not a number: {}", s))
+}
+
+/// number or name from the config
+fn outlet(s: &str, cfg: &Config) -> Result<u8, String> {
+    let max = cfg.power.outlets();
+    match cfg.outlets.number(s) {
+        Some(n) => Ok(n),
+        None => match number(s).map_err(|_| format!("unknown outlet {}", s))? {
+            n @ 1.. if n <= max => Ok(n),
+            _ => Err(format!("outlet must be 1-{}", max)),
+        },
+    }
+}
+
+/// on, off or toggle (None)
+fn switch(s: &str) -> Result<Option<bool>, String> {
+    match s {
+        "on" => Ok(Some(true)),
+        "off" => Ok(Some(false)),
+        "toggle" => Ok(None),
+        _ => Err(format!("expected on, off or toggle, got {}", s)),
+    }
+}
+
+/// seconds, or with `s`, `m` or `h`
+fn duration(s: &str) -> Result<Duration, String> {
+    let (n, unit) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        _ => (s, 1),
+    };
+    let secs: u64 = n.parse().map_err(|_| format!("not a time: {}", s))?;
+    match Duration::from_secs(secs.saturating_mul(unit)) {
+        d if d > MAX_TIMER => Err("at most 24h".into()),
+        d => Ok(d),
+    }
+}
+
+/// number or name like `GiveAudioStatus`. Numbers may be unknown to cec_linux
+fn cec_opcode(s: &str) -> Result<u8, String> {
+    number::<u8>(s).or_else(|_| {
+        (0..=u8::MAX)
+            .filter_map(|n| CecOpcode::try_from(n).ok())
+            .find(|o| format!("{:?}", o).eq_ignore_ascii_case(s))
+            .map(u8::from)
+            .ok_or_else(|| format!("unknown opcode {}", s))
+    })
+}
+
+/// `<opcode> [ms]` after `reply`
+fn reply(args: &[&str]) -> Result<(u8, Duration), String> {
+    let (opcode, ms) = match args {
+        [o] => (o, 1000),
+        [o, ms] => (o, number(ms)?),
+        _ => return Err("expected reply <opcode> [ms]".into()),
+    };
+    if !(1..=MAX_REPLY_MS).contains(&ms) {
+        return Err(format!("wait 1-{} ms", MAX_REPLY_MS));
+    }
+    match cec_opcode(opcode)? {
+        // 0 is no reply to the kernel. A FeatureAbort is taken as reply anyway
+        0 => Err("FeatureAbort is always a reply, wait for what was asked".into()),
+        o => Ok((o, Duration::from_millis(ms))),
+    }
+}
+
+/// `{"from": "Audiosystem", "opcode": "ReportAudioStatus", "data": [40]}`.
+/// Opcodes unknown to cec_linux are numbers
+pub fn cec_reply(f: &Frame) -> String {
+    let opcode = match f.opcode() {
+        Some(Ok(o)) => serde_json::json!(format!("{:?}", o)),
+        Some(Err(o)) => serde_json::json!(o),
+        None => serde_json::Value::Null,
+    };
+    serde_json::json!({
+        "from": format!("{:?}", f.initiator()),
+        "opcode": opcode,
+        "data": f.parameters(),
+    })
+    .to_string()
+}
+
+/// the line to send back
+pub fn format(r: &Response) -> String {
+    match r {
+        Ok(s) if s.is_empty() => "ok\n".to_string(),
+        Ok(s) => format!("ok {}\n", s),
+        Err(e) => format!("error {}\n", e.replace('\n', " ")),
+    }
+}
+
+/// a field of the JSON object from [Control::State]. All of it without a name
+pub fn select(state: &str, field: Option<&str>) -> Response {
+    let f = match field {
+        None => return Ok(state.to_string()),
+        Some(f) => f,
+    };
+    let v: serde_json::Value = serde_json::from_str(state).map_err(|e| e.to_string())?;
+    v.get(f)
+        .map(|v| v.to_string())
+        .ok_or_else(|| format!("unknown field {}", f))
+}
+
+/// The single byte encoding of old scripts.
+///
+/// 1-100 volume, 0 mute, 0x80 | on << 2 | outlet (0 is 4) and 0xC0 | port for the active source.
+/// `h` (104) is unused, so it starts the line protocol
+pub fn legacy(n: u8) -> Option<Control> {
+    match n {
+        1..=100 => Some(Control::Volume(n)),
+        0 => Some(Control::Mute(Some(true))),
+        101..=127 => None,
+        0x80..=u8::MAX => match n & 0xF8 {
+            0x80 => {
+                let on = n & 0x04 != 0;
+                let mut n = n & 0x03;
+                if n == 0 {
+                    n = 4;
+                }
+                Some(Control::Outlet(n, Some(on)))
+            }
+            0xC0 => Some(Control::ActiveSource(n & 7)),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cec_linux::CecPhysicalAddress;
+
+    #[test]
+    fn commands() {
+        let o = Config::default();
+        assert_eq!(parse("hello 1", &o), Ok(Request::Hello(1)));
+        assert_eq!(
+            parse("volume 30\r", &o),
+            Ok(Request::Control(Control::Volume(30)))
+        );
+        assert_eq!(
+            parse("outlet avr off", &o),
+            Ok(Request::Control(Control::Outlet(2, Some(false))))
+        );
+        assert_eq!(
+            parse(" outlet 3 on ", &o),
+            Ok(Request::Control(Control::Outlet(3, Some(true))))
+        );
+        assert_eq!(
+            parse("state tv", &o),
+            Ok(Request::State(Some("tv".into())))
+        );
+        assert_eq!(
+            parse("subscribe tv state", &o),
+            Ok(Request::Subscribe(vec!["tv".into(), "state".into()]))
+        );
+        assert!(parse("subscribe weather", &o).is_err());
+        assert_eq!(
+            parse("cec 5 0x44 0x41", &o),
+            Ok(Request::Control(Control::Cec {
+                to: CecLogicalAddress::Audiosystem,
+                opcode: CecOpcode::UserControlPressed.into(),
+                data: vec![0x41],
+                reply: None,
+            }))
+        );
+        assert_eq!(
+            parse("cec 0 standby", &o),
+            Ok(Request::Control(Control::Cec {
+                to: CecLogicalAddress::Tv,
+                opcode: CecOpcode::Standby.into(),
+                data: vec![],
+                reply: None,
+            }))
+        );
+        assert_eq!(
+            parse("cec 5 0x73 40 reply ReportAudioStatus 500", &o),
+            Ok(Request::Control(Control::Cec {
+                to: CecLogicalAddress::Audiosystem,
+                opcode: 0x73,
+                data: vec![40],
+                reply: Some((0x7a, Duration::from_millis(500))),
+            }))
+        );
+        assert!(parse("cec 5 GiveAudioStatus reply", &o).is_err());
+        assert!(parse("cec 5 GiveAudioStatus reply ReportAudioStatus 60000", &o).is_err());
+        assert!(parse("cec 5 GiveAudioStatus reply ReportAudioStatus 1001", &o).is_err());
+        assert!(parse("cec 5 GiveAudioStatus reply FeatureAbort", &o).is_err());
+        assert!(parse("cec 5 GiveAudioStatus reply 0", &o).is_err());
+        assert_eq!(
+            parse("outlet light", &o),
+            Ok(Request::Control(Control::OutletStatus(1)))
+        );
+        assert!(parse("cec 16 standby", &o).is_err());
+        assert_eq!(
+            parse("mute toggle", &o),
+            Ok(Request::Control(Control::Mute(None)))
+        );
+        assert!(parse("volume 0", &o).is_err());
+        assert!(parse("outlet 1 maybe", &o).is_err());
+        assert!(parse("outlet 5 on", &o).is_err());
+        assert!(parse("reboot", &o).is_err());
+        assert!(parse("", &o).is_err());
+    }
+
+    #[test]
+    fn inputs() {
+        let mut cfg = Config::default();
+        let deck = CecPhysicalAddress::from_num(0x3500);
+        cfg.inputs.insert("steamdeck".into(), PhysAddr(deck));
+        assert_eq!(
+            parse("source steamdeck", &cfg),
+            Ok(Request::Control(Control::Input(deck)))
+        );
+        assert_eq!(
+            parse("source 3.2.0.0", &cfg),
+            Ok(Request::Control(Control::Input(
+                CecPhysicalAddress::from_num(0x3200)
+            )))
+        );
+        assert_eq!(
+            parse("source 3", &cfg),
+            Ok(Request::Control(Control::ActiveSource(3)))
+        );
+        assert!(parse("source xbox", &cfg).is_err());
+        assert!(parse("source 3.2", &cfg).is_err());
+    }
+
+    #[test]
+    fn outlets() {
+        let mut cfg = Config::default();
+        cfg.outlets.names.insert("fan".into(), 3);
+        let timer = |n, on, secs| {
+            Ok(Request::Control(Control::OutletTimer(
+                n,
+                on,
+                Duration::from_secs(secs),
+            )))
+        };
+        assert_eq!(
+            parse("outlet", &cfg),
+            Ok(Request::Control(Control::Outlets))
+        );
+        assert_eq!(
+            parse("outlet fan toggle", &cfg),
+            Ok(Request::Control(Control::Outlet(3, None)))
+        );
+        assert_eq!(
+            parse("outlet light off in 90", &cfg),
+            timer(1, Some(false), 90)
+        );
+        assert_eq!(parse("outlet 4 on in 30s", &cfg), timer(4, Some(true), 30));
+        assert_eq!(parse("outlet avr toggle in 10m", &cfg), timer(2, None, 600));
+        assert_eq!(
+            parse("outlet fan off in 2h", &cfg),
+            timer(3, Some(false), 7200)
+        );
+        assert_eq!(
+            parse("outlet fan cancel", &cfg),
+            Ok(Request::Control(Control::OutletCancel(3)))
+        );
+        assert!(parse("outlet heater on", &cfg).is_err());
+        assert!(parse("outlet fan off in 25h", &cfg).is_err());
+        assert!(parse("outlet fan off in soon", &cfg).is_err());
+        assert!(parse("outlet fan off at 10", &cfg).is_err());
+    }
+
+    #[test]
+    fn fields() {
+        let s = r#"{"state":"Playing","tv":null}"#;
+        assert_eq!(select(s, Some("state")), Ok("\"Playing\"".into()));
+        assert_eq!(select(s, Some("tv")), Ok("null".into()));
+        assert!(select(s, Some("radio")).is_err());
+    }
+
+    #[test]
+    fn legacy_bytes() {
+        assert_eq!(legacy(25), Some(Control::Volume(25)));
+        assert_eq!(legacy(0x80), Some(Control::Outlet(4, Some(false))));
+        assert_eq!(legacy(0x86), Some(Control::Outlet(2, Some(true))));
+        assert_eq!(legacy(0xC3), Some(Control::ActiveSource(3)));
+        assert_eq!(legacy(b'h'), None);
+    }
+
+    #[test]
+    fn cec_replies() {
+        let f = Frame::new(
+            CecLogicalAddress::Audiosystem,
+            CecLogicalAddress::Playback1,
+            CecOpcode::FeatureAbort.into(),
+            &[0x73, 0],
+        );
+        assert_eq!(
+            cec_reply(&f),
+            r#"{"data":[115,0],"from":"Audiosystem","opcode":"FeatureAbort"}"#
+        );
+    }
+
+    #[test]
+    fn responses() {
+        assert_eq!(format(&Ok(String::new())), "ok\n");
+        assert_eq!(format(&Ok("1".into())), "ok 1\n");
+        assert_eq!(format(&Err("a\nb".into())), "error a b\n");
+    }
+}
@@ -0,0 +1,1496 @@
+//! Simulated devices for the [virtual bus](crate::vbus).
+//!
+//! A Sony-like [Tv] and a Denon-like [Avr] that follow the CEC power and system audio rules,
+//! and a [Player] as source behind the AVR.
+//! Each runs on its own thread and can be scripted from the outside.
+//! Quirks of the real devices can be turned on.
+#![cfg_attr(not(test), allow(dead_code))] // most of it is only scripted by the tests
+use crate::cec::{CecBus, Frame};
+use crate::power::PowerSwitch;
+use crate::vbus::{VirtualAdapter, VirtualBus, VirtualCec};
+use crate::volume;
+use cec_linux::{
+    CecLogAddrType, CecLogAddrs, CecLogicalAddress, CecModeFollower, CecModeInitiator, CecOpcode,
+    CecPhysicalAddress, CecPowerStatus, CecPrimDevType, CecUserControlCode, PollFlags, PollTimeout,
+    Version,
+};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Behaviour of a simulated device
+trait Model: Send + 'static {
+    /// react to a frame addressed to us. Returns the frames to send
+    fn receive(&mut self, f: &Frame) -> Vec<Frame>;
+    /// called regularly, for things that take time
+    fn tick(&mut self, _cec: &VirtualCec) {}
+}
+
+struct Sim<M> {
+    model: Mutex<M>,
+    cec: VirtualCec,
+}
+
+/// run `model` on a new handle of `adapter` until the last [Arc] is dropped
+fn spawn<M: Model>(adapter: &VirtualAdapter, model: M) -> Arc<Sim<M>> {
+    let cec = adapter.open();
+    cec.set_mode(CecModeInitiator::Send, CecModeFollower::All)
+        .unwrap();
+    let sim = Arc::new(Sim {
+        model: Mutex::new(model),
+        cec,
+    });
+    let weak = Arc::downgrade(&sim);
+    thread::spawn(move || run(weak));
+    sim
+}
+fn run<M: Model>(sim: Weak<Sim<M>>) {
+    while let Some(sim) = sim.upgrade() {
+        let ready = sim
+            .cec
+            .poll(PollFlags::POLLIN, PollTimeout::from(50u16))
+            .unwrap_or(PollFlags::empty());
+        if ready.contains(PollFlags::POLLIN) {
+            let f = match sim.cec.rec() {
+                Ok(f) => f,
+                Err(_) => return,
+            };
+            let out = sim.model.lock().unwrap().receive(&f);
+            for o in out {
+                let _ = sim.cec.transmit_frame(o);
+            }
+        }
+        sim.model.lock().unwrap().tick(&sim.cec);
+    }
+}
+
+/// What a device does with a directed message it does not support
+fn unsupported(f: &Frame) -> Vec<Frame> {
+    match f.opcode() {
+        // answered by the adapter
+        Some(Ok(
+            CecOpcode::GivePhysicalAddr
+            | CecOpcode::GiveOsdName
+            | CecOpcode::GetCecVersion
+            | CecOpcode::GiveDeviceVendorId,
+        )) => vec![],
+        // never aborted
+        Some(Ok(CecOpcode::FeatureAbort | CecOpcode::UserControlReleased)) | None => vec![],
+        _ if f.is_broadcast() => vec![],
+        Some(o) => vec![Frame::new(
+            f.destination(),
+            f.initiator(),
+            CecOpcode::FeatureAbort.into(),
+            &[o.map_or_else(|e| e, u8::from), 0], // unrecognized opcode
+        )],
+    }
+}
+fn reply(f: &Frame, opcode: CecOpcode, params: &[u8]) -> Frame {
+    Frame::new(f.destination(), f.initiator(), opcode.into(), params)
+}
+fn power_status(standby: bool) -> u8 {
+    if standby {
+        CecPowerStatus::Standby.into()
+    } else {
+        CecPowerStatus::On.into()
+    }
+}
+
+struct TvState {
+    on: bool,
+    /// input that is shown
+    active_source: [u8; 2],
+    /// adapter, right and wrong physical address
+    wrong_phys: Option<(VirtualAdapter, CecPhysicalAddress, CecPhysicalAddress)>,
+}
+impl TvState {
+    fn set_on(&mut self, on: bool) {
+        self.on = on;
+        if let Some((a, right, wrong)) = &self.wrong_phys {
+            a.set_phys(if on { *right } else { *wrong });
+        }
+    }
+}
+impl Model for TvState {
+    fn receive(&mut self, f: &Frame) -> Vec<Frame> {
+        match f.opcode() {
+            Some(Ok(CecOpcode::ImageViewOn | CecOpcode::TextViewOn)) => {
+                self.set_on(true);
+                vec![]
+            }
+            Some(Ok(CecOpcode::Standby)) => {
+                self.set_on(false);
+                vec![]
+            }
+            Some(Ok(CecOpcode::GiveDevicePowerStatus)) => vec![reply(
+                f,
+                CecOpcode::ReportPowerStatus,
+                &[power_status(!self.on)],
+            )],
+            Some(Ok(CecOpcode::ActiveSource)) => {
+                if let Ok(p) = f.parameters().try_into() {
+                    self.active_source = p;
+                }
+                vec![]
+            }
+            _ => unsupported(f),
+        }
+    }
+}
+
+/// A TV at 0.0.0.0 like a Sony Bravia.
+///
+/// Starts in standby
+#[derive(Clone)]
+pub struct Tv(Arc<Sim<TvState>>);
+impl Tv {
+    pub fn new(bus: &VirtualBus) -> Tv {
+        let adapter = bus.add_adapter(CecPhysicalAddress::from_num(0), CecLogAddrType::TV);
+        let sim = spawn(
+            &adapter,
+            TvState {
+                on: false,
+                active_source: [0, 0],
+                wrong_phys: None,
+            },
+        );
+        sim.cec
+            .set_log(CecLogAddrs::new(
+                0x080046, // Sony
+                Version::V1_4,
+                b"BRAVIA"[..].into(),
+                &[CecPrimDevType::TV],
+                &[CecLogAddrType::TV],
+            ))
+            .unwrap();
+        Tv(sim)
+    }
+    /// The remote was used to turn it on. Shows its own tuner
+    pub fn power_on(&self) {
+        let mut tv = self.0.model.lock().unwrap();
+        tv.set_on(true);
+        tv.active_source = [0, 0];
+        let _ = self.0.cec.transmit_data(
+            CecLogicalAddress::Tv,
+            CecLogicalAddress::UnregisteredBroadcast,
+            CecOpcode::ActiveSource,
+            &[0, 0],
+        );
+    }
+    /// The remote was used to turn it off. Everything else is sent to standby too
+    pub fn standby(&self) {
+        let mut tv = self.0.model.lock().unwrap();
+        tv.set_on(false);
+        let _ = self.0.cec.transmit(
+            CecLogicalAddress::Tv,
+            CecLogicalAddress::UnregisteredBroadcast,
+            CecOpcode::Standby,
+        );
+    }
+    pub fn is_on(&self) -> bool {
+        self.0.model.lock().unwrap().on
+    }
+    /// the physical address of the shown input
+    pub fn active_source(&self) -> CecPhysicalAddress {
+        CecPhysicalAddress::from_bytes(self.0.model.lock().unwrap().active_source)
+    }
+    /// Quirk: While in standby, `adapter` gets `wrong` as physical address
+    /// instead of its current one. Like 3.0.0.0 instead of 3.3.0.0
+    pub fn quirk_wrong_phys_addr(&self, adapter: VirtualAdapter, wrong: CecPhysicalAddress) {
+        let mut tv = self.0.model.lock().unwrap();
+        let right = adapter.phys_addr();
+        if !tv.on {
+            adapter.set_phys(wrong);
+        }
+        tv.wrong_phys = Some((adapter, right, wrong));
+    }
+}
+
+struct AvrState {
+    /// when it will be on the bus. None if it has no power or is booted
+    boot_at: Option<Instant>,
+    boot_time: Duration,
+    mains: bool,
+    standby: bool,
+    /// physical address of the source in system audio mode
+    system_audio: Option<[u8; 2]>,
+    /// volume in half percent
+    half_steps: u8,
+    /// half percent per key press
+    key_step: u8,
+    muted: bool,
+    /// CEC 2.0: takes SetAudioVolumeLevel
+    volume_level: bool,
+    /// reply to GiveSystemAudioModeStatus with the address of the source instead of On
+    status_quirk: bool,
+    /// time taken to answer GiveAudioStatus
+    status_delay: Duration,
+    /// time until a mute key shows in the audio status
+    mute_delay: Duration,
+    /// muted or not from then on
+    mute_at: Option<(Instant, bool)>,
+}
+impl AvrState {
+    fn key(&mut self, key: u8) {
+        match CecUserControlCode::try_from(key) {
+            Ok(CecUserControlCode::Power | CecUserControlCode::PowerOnFunction) => {
+                self.standby = false
+            }
+            Ok(CecUserControlCode::PowerOffFunction) => self.go_to_standby(),
+            Ok(CecUserControlCode::PowerToggleFunction) if self.standby => self.standby = false,
+            Ok(CecUserControlCode::PowerToggleFunction) => self.go_to_standby(),
+            _ if self.standby => {}
+            Ok(CecUserControlCode::VolumeUp) => {
+                self.half_steps = (self.half_steps + self.key_step).min(200)
+            }
+            Ok(CecUserControlCode::VolumeDown) => {
+                self.half_steps = self.half_steps.saturating_sub(self.key_step)
+            }
+            Ok(CecUserControlCode::Mute) => self.mute(None),
+            Ok(CecUserControlCode::MuteFunction) => self.mute(Some(true)),
+            Ok(CecUserControlCode::RestoreVolumeFunction) => self.mute(Some(false)),
+            _ => {}
+        }
+    }
+    /// mute, unmute or toggle (None)
+    fn mute(&mut self, on: Option<bool>) {
+        let now = self.mute_at.map_or(self.muted, |(_, m)| m);
+        let on = on.unwrap_or(!now);
+        if self.mute_delay.is_zero() {
+            self.muted = on;
+        } else {
+            self.mute_at = Some((Instant::now() + self.mute_delay, on));
+        }
+    }
+    fn go_to_standby(&mut self) {
+        self.standby = true;
+        self.system_audio = None;
+    }
+}
+impl Model for AvrState {
+    fn receive(&mut self, f: &Frame) -> Vec<Frame> {
+        match f.opcode() {
+            Some(Ok(CecOpcode::Standby)) => {
+                self.go_to_standby();
+                vec![]
+            }
+            Some(Ok(CecOpcode::GiveDevicePowerStatus)) => vec![reply(
+                f,
+                CecOpcode::ReportPowerStatus,
+                &[power_status(self.standby)],
+            )],
+            Some(Ok(CecOpcode::UserControlPressed)) => {
+                if let Some(&k) = f.parameters().first() {
+                    self.key(k);
+                }
+                vec![]
+            }
+            Some(Ok(CecOpcode::GiveAudioStatus)) => {
+                thread::sleep(self.status_delay);
+                vec![reply(
+                    f,
+                    CecOpcode::ReportAudioStatus,
+                    &[((self.muted as u8) << 7) | (self.half_steps / 2)],
+                )]
+            }
+            Some(Ok(CecOpcode::SystemAudioModeRequest)) => match f.parameters().get(..2) {
+                Some(p) => {
+                    // comes out of standby and switches to the source
+                    self.standby = false;
+                    self.system_audio = p.try_into().ok();
+                    vec![Frame::new(
+                        f.destination(),
+                        CecLogicalAddress::UnregisteredBroadcast,
+                        CecOpcode::SetSystemAudioMode.into(),
+                        &[1],
+                    )]
+                }
+                None => {
+                    self.system_audio = None;
+                    vec![reply(f, CecOpcode::SetSystemAudioMode, &[0])]
+                }
+            },
+            Some(Ok(CecOpcode::GiveSystemAudioModeStatus)) => {
+                let status = match self.system_audio {
+                    Some(p) if self.status_quirk => p.to_vec(),
+                    Some(_) => vec![1],
+                    None => vec![0],
+                };
+                vec![reply(f, CecOpcode::SystemAudioModeStatus, &status)]
+            }
+            Some(Ok(CecOpcode::ActiveSource | CecOpcode::SetStreamPath)) => vec![],
+            Some(Ok(CecOpcode::GiveFeatures)) if self.volume_level => vec![reply(
+                f,
+                CecOpcode::ReportFeatures,
+                &[
+                    Version::V2_0.into(),
+                    0x08, // audio system
+                    0x00, // no RC profile
+                    volume::FEAT_SET_AUDIO_VOLUME_LEVEL,
+                ],
+            )],
+            Some(Err(volume::SET_AUDIO_VOLUME_LEVEL)) if self.volume_level => {
+                match f.parameters().first() {
+                    Some(&v) if v <= 100 && !self.standby => {
+                        self.half_steps = v * 2;
+                        vec![reply(
+                            f,
+                            CecOpcode::ReportAudioStatus,
+                            &[((self.muted as u8) << 7) | v],
+                        )]
+                    }
+                    _ => vec![],
+                }
+            }
+            _ => unsupported(f),
+        }
+    }
+    fn tick(&mut self, cec: &VirtualCec) {
+        if let Some((_, on)) = self.mute_at.filter(|(t, _)| *t <= Instant::now()) {
+            self.mute_at = None;
+            self.muted = on;
+        }
+        if self.boot_at.is_some_and(|t| t <= Instant::now()) {
+            self.boot_at = None;
+            // claiming the address announces the physical address
+            let _ = cec.set_log(CecLogAddrs::new(
+                0x0005cd, // Denon
+                Version::V1_4,
+                b"AVR-X540BT"[..].into(),
+                &[CecPrimDevType::AUDIOSYSTEM],
+                &[CecLogAddrType::AUDIOSYSTEM],
+            ));
+        }
+    }
+}
+
+/// An AVR like a Denon AVR-X540BT.
+///
+/// It is only on the bus while it has mains power. After power is applied it takes a while to boot
+/// and then announces itself with ReportPhysicalAddr. It boots into standby.
+#[derive(Clone)]
+pub struct Avr(Arc<Sim<AvrState>>);
+impl Avr {
+    pub fn new(bus: &VirtualBus, phys_addr: CecPhysicalAddress) -> Avr {
+        let adapter = bus.add_adapter(phys_addr, CecLogAddrType::AUDIOSYSTEM);
+        Avr(spawn(
+            &adapter,
+            AvrState {
+                boot_at: None,
+                boot_time: Duration::from_secs(3),
+                mains: false,
+                standby: true,
+                system_audio: None,
+                half_steps: 80,
+                key_step: 1,
+                muted: false,
+                volume_level: false,
+                status_quirk: true,
+                status_delay: Duration::ZERO,
+                mute_delay: Duration::ZERO,
+                mute_at: None,
+            },
+        ))
+    }
+    /// plug it in or pull the plug
+    pub fn set_mains(&self, on: bool) {
+        let mut avr = self.0.model.lock().unwrap();
+        if avr.mains == on {
+            return;
+        }
+        avr.mains = on;
+        avr.standby = true;
+        avr.system_audio = None;
+        if on {
+            avr.boot_at = Some(Instant::now() + avr.boot_time);
+        } else {
+            avr.boot_at = None;
+            let _ = self.0.cec.set_log(CecLogAddrs::default());
+        }
+    }
+    /// change the volume step of a key press, in half percent
+    pub fn set_key_step(&self, half_percent: u8) {
+        self.0.model.lock().unwrap().key_step = half_percent;
+    }
+    /// CEC 2.0: take SetAudioVolumeLevel and say so in ReportFeatures
+    pub fn set_volume_level(&self, on: bool) {
+        self.0.model.lock().unwrap().volume_level = on;
+    }
+    pub fn set_boot_time(&self, boot_time: Duration) {
+        self.0.model.lock().unwrap().boot_time = boot_time;
+    }
+    /// Quirk: GiveSystemAudioModeStatus is answered with the physical address of the source,
+    /// like `[0x33, 0]`, instead of `[1]`. On by default
+    pub fn quirk_status_is_phys_addr(&self, on: bool) {
+        self.0.model.lock().unwrap().status_quirk = on;
+    }
+    /// take this long to answer GiveAudioStatus
+    pub fn set_status_delay(&self, delay: Duration) {
+        self.0.model.lock().unwrap().status_delay = delay;
+    }
+    /// Quirk: mute keys show in the audio status after `delay`
+    pub fn quirk_late_mute(&self, delay: Duration) {
+        self.0.model.lock().unwrap().mute_delay = delay;
+    }
+    pub fn has_mains(&self) -> bool {
+        self.0.model.lock().unwrap().mains
+    }
+    /// has power, is booted and not in standby
+    pub fn is_on(&self) -> bool {
+        let avr = self.0.model.lock().unwrap();
+        avr.mains && avr.boot_at.is_none() && !avr.standby
+    }
+    /// volume in percent
+    pub fn volume(&self) -> u8 {
+        self.0.model.lock().unwrap().half_steps / 2
+    }
+    pub fn is_muted(&self) -> bool {
+        self.0.model.lock().unwrap().muted
+    }
+    /// physical address of the source in system audio mode
+    pub fn system_audio(&self) -> Option<CecPhysicalAddress> {
+        self.0
+            .model
+            .lock()
+            .unwrap()
+            .system_audio
+            .map(CecPhysicalAddress::from_bytes)
+    }
+}
+
+struct PlayerState {
+    /// logical address it claimed
+    addr: CecLogicalAddress,
+    phys_addr: [u8; 2],
+    standby: bool,
+}
+impl Model for PlayerState {
+    fn receive(&mut self, f: &Frame) -> Vec<Frame> {
+        match f.opcode() {
+            Some(Ok(CecOpcode::SetStreamPath)) if f.parameters() == self.phys_addr => {
+                // CEC 2.0: comes out of standby
+                self.standby = false;
+                vec![Frame::new(
+                    self.addr,
+                    CecLogicalAddress::UnregisteredBroadcast,
+                    CecOpcode::ActiveSource.into(),
+                    &self.phys_addr,
+                )]
+            }
+            Some(Ok(CecOpcode::GiveDevicePowerStatus)) => vec![reply(
+                f,
+                CecOpcode::ReportPowerStatus,
+                &[power_status(self.standby)],
+            )],
+            Some(Ok(
+                CecOpcode::ActiveSource | CecOpcode::SetStreamPath | CecOpcode::RoutingChange,
+            )) => vec![],
+            _ => unsupported(f),
+        }
+    }
+}
+
+/// A set-top box that becomes the active source when its stream path is set.
+///
+/// Starts in standby. Claims a tuner address, so the playback ones are left for us
+#[derive(Clone)]
+pub struct Player(Arc<Sim<PlayerState>>);
+impl Player {
+    pub fn new(bus: &VirtualBus, phys_addr: CecPhysicalAddress) -> Player {
+        let adapter = bus.add_adapter(phys_addr, CecLogAddrType::TUNER);
+        let sim = spawn(
+            &adapter,
+            PlayerState {
+                addr: CecLogicalAddress::UnregisteredBroadcast,
+                phys_addr: phys_addr.to_bytes(),
+                standby: true,
+            },
+        );
+        sim.cec
+            .set_log(CecLogAddrs::new(
+                0xffffffff, // no vendor
+                Version::V1_4,
+                b"STB"[..].into(),
+                &[CecPrimDevType::TUNER],
+                &[CecLogAddrType::TUNER],
+            ))
+            .unwrap();
+        sim.model.lock().unwrap().addr = sim.cec.get_log().unwrap()[0];
+        Player(sim)
+    }
+    pub fn is_on(&self) -> bool {
+        !self.0.model.lock().unwrap().standby
+    }
+}
+
+/// Outlets where one of them powers a simulated [Avr]
+pub struct Plug {
+    inner: Box<dyn PowerSwitch>,
+    avr: Avr,
+    outlet: u8,
+}
+impl Plug {
+    pub fn new(inner: Box<dyn PowerSwitch>, avr: Avr, outlet: u8) -> std::io::Result<Plug> {
+        avr.set_mains(inner.get_status(outlet)?);
+        Ok(Plug { inner, avr, outlet })
+    }
+}
+impl PowerSwitch for Plug {
+    fn set_status(&self, num: u8, on: bool) -> std::io::Result<()> {
+        self.inner.set_status(num, on)?;
+        if num == self.outlet {
+            self.avr.set_mains(on);
+        }
+        Ok(())
+    }
+    fn get_status(&self, num: u8) -> std::io::Result<bool> {
+        self.inner.get_status(num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::devices;
+    use crate::event::{self, Control, Event};
+    use crate::power::{Mock, Shared};
+    use crate::protocol::{self, Response};
+    use crate::state::MediaState;
+    use crate::stop::Stop;
+    use crate::{monitor, sock, Actor, Daemon};
+    use cec_linux::CecEvent;
+    use std::cell::RefCell;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{self, Receiver, Sender};
+
+    const PI: CecPhysicalAddress = CecPhysicalAddress::from_num(0x3300);
+
+    /// a plug that answers late, like one on a bad WiFi
+    struct Slow(Plug, Arc<Mutex<Duration>>);
+    impl PowerSwitch for Slow {
+        fn set_status(&self, num: u8, on: bool) -> std::io::Result<()> {
+            thread::sleep(*self.1.lock().unwrap());
+            self.0.set_status(num, on)
+        }
+        fn get_status(&self, num: u8) -> std::io::Result<bool> {
+            thread::sleep(*self.1.lock().unwrap());
+            self.0.get_status(num)
+        }
+    }
+
+    /// the daemon on a bus with a TV and an AVR
+    struct Setup {
+        bus: VirtualBus,
+        tv: Tv,
+        avr: Avr,
+        pi: VirtualAdapter,
+        daemon: Daemon,
+        sender: Sender<Event>,
+        events: Receiver<Event>,
+        /// stops the monitor
+        stop: Arc<AtomicBool>,
+        /// how long the plug takes for each request
+        plug_delay: Arc<Mutex<Duration>>,
+    }
+    impl Drop for Setup {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Relaxed);
+        }
+    }
+    impl Setup {
+        fn new() -> Setup {
+            let bus = VirtualBus::new();
+            let tv = Tv::new(&bus);
+            let avr = Avr::new(&bus, CecPhysicalAddress::from_num(0x3000));
+            avr.set_boot_time(Duration::from_millis(100));
+            let pi = bus.add_adapter(PI, CecLogAddrType::PLAYBACK);
+
+            let cec = pi.open();
+            let mon = pi.open();
+            mon.set_mode(CecModeInitiator::None, CecModeFollower::Monitor)
+                .unwrap();
+            cec.set_log(CecLogAddrs::new(
+                0xffffffff,
+                Version::V1_4,
+                b"pi4"[..].into(),
+                &[CecPrimDevType::PLAYBACK],
+                &[CecLogAddrType::PLAYBACK],
+            ))
+            .unwrap();
+
+            let (sender, events) = mpsc::channel();
+            let stop = Arc::new(AtomicBool::new(false));
+            let (tx, s) = (sender.clone(), Arc::clone(&stop));
+            // like monitor::mon
+            thread::spawn(move || {
+                while !s.load(Ordering::Relaxed) {
+                    let f = mon
+                        .poll(
+                            PollFlags::POLLIN | PollFlags::POLLPRI,
+                            PollTimeout::from(50u16),
+                        )
+                        .unwrap();
+                    if f.contains(PollFlags::POLLPRI) {
+                        if let CecEvent::StateChange(e) = mon.get_event().unwrap() {
+                            monitor::state_change(e, &tx);
+                        }
+                    }
+                    if f.contains(PollFlags::POLLIN) {
+                        monitor::command(mon.rec().unwrap(), &tx);
+                    }
+                }
+            });
+
+            let (scan, requests) = mpsc::channel();
+            let tx = sender.clone();
+            let cec_scan = pi.open();
+            thread::spawn(move || devices::scanner(Box::new(cec_scan), requests, tx));
+
+            let mut cfg = Config::default();
+            // no EDID
+            cfg.cec.edid = Some(PathBuf::from("/dev/null"));
+            let plug = Plug::new(Box::new(Mock::new(4)), avr.clone(), cfg.outlets.avr).unwrap();
+            let plug_delay = Arc::default();
+            let pwr_socket = Slow(plug, Arc::clone(&plug_delay));
+            let mut daemon = Daemon::new(
+                Arc::new(cfg),
+                Arc::new(Actor {
+                    cec: Mutex::new(Box::new(cec)),
+                    pwr_socket: Shared::new(Box::new(pwr_socket)),
+                    volume: Default::default(),
+                    outlet_timers: Default::default(),
+                }),
+                MediaState::Off,
+                scan,
+                Arc::default(),
+                sender.clone(),
+            );
+            daemon.snapclient_volume = 25;
+            daemon.run().unwrap();
+            Setup {
+                bus,
+                tv,
+                avr,
+                pi,
+                daemon,
+                sender,
+                events,
+                stop,
+                plug_delay,
+            }
+        }
+        /// run the main loop until `done`
+        fn run_until(&mut self, what: &str, done: impl Fn(&Setup) -> bool) {
+            let end = Instant::now() + Duration::from_secs(5);
+            while !done(self) {
+                assert!(Instant::now() < end, "timeout waiting for {what}");
+                // the sims do not send events
+                let poll = Instant::now() + Duration::from_millis(20);
+                let deadline = self.daemon.deadline().map_or(poll, |d| d.min(poll));
+                let e = event::recv(&self.events, Some(deadline)).unwrap();
+                self.daemon.react(e).unwrap();
+            }
+        }
+        /// let the main loop execute `c` and wait for the answer
+        fn ask(&mut self, c: Control) -> Response {
+            let (tx, rx) = mpsc::channel();
+            self.sender.send(Event::Control(c, Some(tx))).unwrap();
+            let answer = RefCell::new(None);
+            self.run_until("an answer", |_| {
+                if let Ok(a) = rx.try_recv() {
+                    *answer.borrow_mut() = Some(a);
+                }
+                answer.borrow().is_some()
+            });
+            answer.into_inner().unwrap()
+        }
+        fn outlet(&self, num: u8) -> bool {
+            self.daemon.actor.pwr_socket.get_status(num).unwrap()
+        }
+        fn set_playing(&self, playing: bool) {
+            self.sender.send(Event::Playing(playing)).unwrap();
+        }
+        fn server(&self, stop: &Arc<Stop>) -> Arc<sock::Server> {
+            sock::Server::new(
+                self.sender.clone(),
+                Arc::clone(&self.daemon.cfg),
+                Arc::clone(&self.daemon.feed),
+                Arc::clone(&self.daemon.actor),
+                Arc::clone(stop),
+            )
+        }
+        /// serve the control socket at a new path
+        fn listen(&self) -> (PathBuf, Arc<Stop>) {
+            let path = std::env::temp_dir().join(format!(
+                "cecremote-{}-{:?}",
+                std::process::id(),
+                thread::current().id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).unwrap();
+            let stop = Stop::new();
+            let srv = self.server(&stop);
+            thread::spawn(move || sock::listen_for_vol_changes(listener, srv));
+            (path, stop)
+        }
+        /// TV is on and AVR is its speaker
+        fn watching(&mut self) {
+            self.tv.power_on();
+            self.run_until("Watching", |s| {
+                s.daemon.state == MediaState::Watching && s.avr.is_on()
+            });
+            assert!(self.outlet(1), "light");
+            assert!(self.outlet(2), "avr");
+        }
+        /// Once what is on its way was handled, the main loop has no deadline and nothing sends it events
+        fn assert_idle(&mut self) {
+            let end = Instant::now() + Duration::from_secs(5);
+            loop {
+                assert!(
+                    Instant::now() < end,
+                    "never idle in {:?}",
+                    self.daemon.state
+                );
+                let quiet = Instant::now() + Duration::from_millis(200);
+                let deadline = self.daemon.deadline().map_or(quiet, |d| d.min(quiet));
+                match event::recv(&self.events, Some(deadline)).unwrap() {
+                    None if self.daemon.deadline().is_none() => break,
+                    e => self.daemon.react(e).unwrap(),
+                }
+            }
+            let later = Instant::now() + Duration::from_millis(500);
+            if let Some(e) = event::recv(&self.events, Some(later)).unwrap() {
+                panic!("woken in {:?} by {e:?}", self.daemon.state);
+            }
+            assert_eq!(self.daemon.deadline(), None);
+        }
+    }
+
+    #[test]
+    fn tv_turns_on() {
+        let mut s = Setup::new();
+        assert!(!s.avr.has_mains());
+        s.watching();
+        assert_eq!(s.avr.system_audio(), None);
+        assert_eq!(s.tv.active_source(), CecPhysicalAddress::from_num(0));
+    }
+
+    #[test]
+    fn input_wakes_tv() {
+        let mut s = Setup::new();
+        let stb = CecPhysicalAddress::from_num(0x3200);
+        let player = Player::new(&s.bus, stb);
+        s.run_until("address", |s| s.daemon.g.cec_addr.is_some());
+        assert_eq!(s.ask(Control::Input(stb)), Ok("3.2.0.0".to_string()));
+        assert!(s.tv.is_on());
+        assert!(player.is_on());
+        s.run_until("Watching", |s| {
+            s.daemon.state == MediaState::Watching && s.tv.active_source() == stb
+        });
+    }
+
+    #[test]
+    fn input_is_us() {
+        let mut s = Setup::new();
+        s.watching();
+        // legacy byte 0xC3: port 3 of the AVR
+        assert_eq!(s.ask(Control::ActiveSource(3)), Ok("3.3.0.0".to_string()));
+        s.run_until("TV shows us", |s| s.tv.active_source() == PI);
+    }
+
+    #[test]
+    fn tv_turns_off_while_snapcast_plays() {
+        let mut s = Setup::new();
+        s.watching();
+        s.set_playing(true);
+        s.tv.standby();
+        s.run_until("Playing", |s| {
+            s.daemon.state == MediaState::Playing && s.avr.volume() == 25
+        });
+        assert!(!s.outlet(1), "light");
+        assert!(s.avr.is_on());
+        assert_eq!(s.avr.system_audio(), Some(PI));
+        assert_eq!(s.daemon.old_vol, 40);
+    }
+
+    #[test]
+    fn snapcast_stops_playing() {
+        let mut s = Setup::new();
+        s.watching();
+        s.set_playing(true);
+        s.tv.standby();
+        s.run_until("Playing", |s| s.daemon.state == MediaState::Playing);
+        s.set_playing(false);
+        s.run_until("Off", |s| s.daemon.state == MediaState::Off);
+        assert!(!s.avr.has_mains());
+        assert!(!s.outlet(2), "avr");
+        assert_eq!(s.avr.volume(), 40);
+    }
+
+    #[test]
+    fn snapcast_starts_with_tv_off() {
+        let mut s = Setup::new();
+        s.set_playing(true);
+        s.run_until("Playing", |s| {
+            s.daemon.state == MediaState::Playing && s.avr.volume() == 25
+        });
+        assert!(!s.tv.is_on());
+        assert!(!s.outlet(1), "light");
+        assert_eq!(s.avr.system_audio(), Some(PI));
+    }
+
+    #[test]
+    fn avr_off_by_hand() {
+        let mut s = Setup::new();
+        s.set_playing(true);
+        s.run_until("Playing", |s| s.daemon.state == MediaState::Playing);
+        let stop = Stop::new();
+        let srv = s.server(&stop);
+        assert_eq!(
+            srv.control(Control::Outlet(2, Some(false))),
+            Ok("off".to_string())
+        );
+        s.run_until("Off", |s| s.daemon.state == MediaState::Off);
+        let end = Instant::now() + Duration::from_millis(300);
+        s.run_until("a while", |_| Instant::now() >= end);
+        assert_eq!(s.daemon.state, MediaState::Off);
+        assert!(!s.avr.has_mains());
+        // snapcast starts over
+        s.set_playing(false);
+        s.set_playing(true);
+        s.run_until("Playing", |s| s.daemon.state == MediaState::Playing);
+        assert!(s.avr.has_mains());
+    }
+
+    #[test]
+    fn slow_plug() {
+        let mut s = Setup::new();
+        s.run_until("address", |s| s.daemon.g.cec_addr.is_some());
+        *s.plug_delay.lock().unwrap() = Duration::from_millis(300);
+        let stop = Stop::new();
+        let srv = s.server(&stop);
+        let (a, b) = (Arc::clone(&srv), Arc::clone(&srv));
+        let outlets = thread::spawn(move || a.control(Control::Outlets));
+        let switch = thread::spawn(move || b.control(Control::Outlet(3, Some(true))));
+        thread::sleep(Duration::from_millis(50));
+        // the main loop answers while the plug takes 1.5s
+        let start = Instant::now();
+        assert!(s.ask(Control::State).is_ok());
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert!(!outlets.is_finished() && !switch.is_finished());
+        let outlets: serde_json::Value =
+            serde_json::from_str(&outlets.join().unwrap().unwrap()).unwrap();
+        assert_eq!(outlets.as_object().map(|o| o.len()), Some(4));
+        assert_eq!(switch.join().unwrap(), Ok("on".to_string()));
+        assert!(s.outlet(3));
+    }
+
+    #[test]
+    fn outlet_timer() {
+        let mut s = Setup::new();
+        let light = Control::OutletTimer(1, None, Duration::from_millis(100));
+        assert_eq!(s.ask(light), Ok(String::new()));
+        assert_eq!(
+            s.ask(Control::OutletTimer(3, Some(true), Duration::from_secs(60))),
+            Ok(String::new())
+        );
+        assert_eq!(s.ask(Control::OutletCancel(3)), Ok(String::new()));
+        assert!(s.ask(Control::OutletCancel(3)).is_err());
+        s.run_until("light on", |s| s.outlet(1));
+        assert!(!s.outlet(3));
+        assert_eq!(s.daemon.actor.outlet_timers.lock().unwrap().next(), None);
+    }
+
+    #[test]
+    fn snapcast_mutes() {
+        let mut s = Setup::new();
+        s.set_playing(true);
+        s.run_until("Playing", |s| s.daemon.state == MediaState::Playing);
+        s.sender.send(Event::SnapMute(true)).unwrap();
+        s.run_until("muted", |s| s.avr.is_muted());
+        assert_eq!(s.daemon.g.muted, Some(true));
+        // the TV gets its sound back
+        s.tv.power_on();
+        s.run_until("Watching", |s| s.daemon.state == MediaState::Watching);
+        assert!(!s.avr.is_muted());
+    }
+
+    #[test]
+    fn mute_toggles() {
+        let mut s = Setup::new();
+        s.watching();
+        let m = Arc::clone(&s.daemon.actor);
+        assert_eq!(
+            crate::mute(&**m.cec(), CecLogicalAddress::Playback1, None),
+            Ok(true)
+        );
+        assert!(s.avr.is_muted());
+        assert_eq!(
+            crate::mute(&**m.cec(), CecLogicalAddress::Playback1, Some(true)),
+            Ok(true)
+        );
+        assert_eq!(
+            crate::mute(&**m.cec(), CecLogicalAddress::Playback1, Some(false)),
+            Ok(false)
+        );
+        assert!(!s.avr.is_muted());
+    }
+
+    #[test]
+    fn mute_reported_late() {
+        let mut s = Setup::new();
+        s.watching();
+        s.avr.quirk_late_mute(Duration::from_millis(250));
+        let m = Arc::clone(&s.daemon.actor);
+        assert_eq!(
+            crate::mute(&**m.cec(), CecLogicalAddress::Playback1, Some(true)),
+            Ok(true)
+        );
+        // MuteFunction did it. Mute was not pressed as well
+        thread::sleep(Duration::from_millis(300));
+        assert!(s.avr.is_muted());
+        assert_eq!(
+            crate::mute(&**m.cec(), CecLogicalAddress::Playback1, None),
+            Ok(false)
+        );
+        thread::sleep(Duration::from_millis(300));
+        assert!(!s.avr.is_muted());
+    }
+
+    #[test]
+    fn volume_with_big_steps() {
+        let mut s = Setup::new();
+        s.watching();
+        // 1.5% per key press
+        s.avr.set_key_step(3);
+        let m = Arc::clone(&s.daemon.actor);
+        let v = m
+            .volume
+            .set(&**m.cec(), CecLogicalAddress::Playback1, 70, None);
+        assert!(v.as_ref().is_ok_and(|v| v.abs_diff(70) <= 1), "{:?}", v);
+        assert!(m.volume.step() > 0.9, "learned {}", m.volume.step());
+        assert_eq!(m.volume.has_level(), Some(false));
+        let v = m
+            .volume
+            .set(&**m.cec(), CecLogicalAddress::Playback1, 20, None);
+        assert!(v.as_ref().is_ok_and(|v| v.abs_diff(20) <= 1), "{:?}", v);
+        assert_eq!(Ok(s.avr.volume()), v);
+    }
+
+    #[test]
+    fn volume_level() {
+        let mut s = Setup::new();
+        s.watching();
+        s.avr.set_volume_level(true);
+        // keys would not get there
+        s.avr.set_key_step(0);
+        let m = Arc::clone(&s.daemon.actor);
+        let v = m
+            .volume
+            .set(&**m.cec(), CecLogicalAddress::Playback1, 70, None);
+        assert_eq!(v, Ok(70));
+        assert_eq!(m.volume.has_level(), Some(true));
+        assert_eq!(s.avr.volume(), 70);
+    }
+
+    #[test]
+    fn cec_command() {
+        let mut s = Setup::new();
+        s.watching();
+        let m = Arc::clone(&s.daemon.actor);
+        let send = |to, opcode: u8, reply: Option<CecOpcode>| {
+            let reply = reply.map(|r| (r.into(), Duration::from_millis(300)));
+            sock::execute(
+                Control::Cec {
+                    to,
+                    opcode,
+                    data: vec![],
+                    reply,
+                },
+                &m,
+            )
+        };
+        let avr = CecLogicalAddress::Audiosystem;
+        assert_eq!(
+            send(
+                avr,
+                CecOpcode::GiveAudioStatus.into(),
+                Some(CecOpcode::ReportAudioStatus)
+            ),
+            Ok(r#"{"data":[40],"from":"Audiosystem","opcode":"ReportAudioStatus"}"#.into())
+        );
+        assert_eq!(
+            send(
+                avr,
+                volume::SET_AUDIO_VOLUME_LEVEL,
+                Some(CecOpcode::ReportAudioStatus)
+            ),
+            Ok(r#"{"data":[115,0],"from":"Audiosystem","opcode":"FeatureAbort"}"#.into())
+        );
+        assert_eq!(
+            send(avr, CecOpcode::GiveAudioStatus.into(), None),
+            Ok("".into())
+        );
+        // a FeatureAbort is opcode 0, which would not wait at all and answer what we sent
+        let line = "cec 5 GiveAudioStatus reply FeatureAbort";
+        assert!(protocol::parse(line, &s.daemon.cfg).is_err());
+        // nobody there
+        let tuner = CecLogicalAddress::Tuner1;
+        assert!(send(tuner, CecOpcode::GiveOsdName.into(), None).is_err());
+        assert!(send(
+            tuner,
+            CecOpcode::GiveOsdName.into(),
+            Some(CecOpcode::SetOsdName)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn volume_gives_up() {
+        let mut s = Setup::new();
+        s.watching();
+        // ignores the keys
+        s.avr.set_key_step(0);
+        let m = Arc::clone(&s.daemon.actor);
+        let v = m
+            .volume
+            .set(&**m.cec(), CecLogicalAddress::Playback1, 70, None);
+        assert_eq!(v, Err(crate::volume::Error::GaveUp(40)));
+    }
+
+    #[test]
+    fn status() {
+        let mut s = Setup::new();
+        s.set_playing(true);
+        s.run_until("Playing", |s| s.daemon.state == MediaState::Playing);
+        let st = s.daemon.status();
+        assert_eq!(st["state"], "Playing");
+        assert_eq!(st["playing"], true);
+        assert_eq!(st["cec_addr"], "Playback1");
+        assert_eq!(st["phys_addr"], "3.3.0.0");
+        assert!(st["in_state"].is_f64());
+    }
+
+    #[test]
+    fn events_for_subscribers() {
+        let mut s = Setup::new();
+        let events = s
+            .daemon
+            .feed
+            .subscribe(vec!["state".into(), "playing".into()]);
+        s.set_playing(true);
+        s.run_until("Playing", |s| s.daemon.state == MediaState::Playing);
+        let lines: Vec<String> = events.try_iter().collect();
+        assert!(lines.contains(&"event playing true\n".to_string()));
+        assert_eq!(lines.last().unwrap(), "event state \"Playing\"\n");
+    }
+
+    #[test]
+    fn shutdown_while_playing() {
+        let mut s = Setup::new();
+        s.set_playing(true);
+        s.run_until("Playing", |s| s.daemon.state == MediaState::Playing);
+        s.daemon.shutdown();
+        assert_eq!(s.avr.system_audio(), None);
+        assert!(!s.avr.has_mains());
+    }
+
+    #[test]
+    fn shutdown_leaves_tv_sound() {
+        let mut s = Setup::new();
+        s.watching();
+        s.daemon.shutdown();
+        assert!(s.avr.is_on());
+        assert!(s.outlet(2), "avr");
+    }
+
+    /// send `lines` after the hello and get the answers
+    fn request(path: &Path, lines: &'static [&'static str]) -> thread::JoinHandle<Vec<String>> {
+        let stream = UnixStream::connect(path).unwrap();
+        thread::spawn(move || {
+            let mut text = String::from("hello 1\n");
+            for l in lines {
+                text += l;
+                text += "\n";
+            }
+            (&stream).write_all(text.as_bytes()).unwrap();
+            let reader = BufReader::new(&stream);
+            let answers = reader.lines().skip(1).take(lines.len());
+            answers.map(Result::unwrap).collect()
+        })
+    }
+
+    #[test]
+    fn clients_do_not_wait_for_each_other() {
+        let mut s = Setup::new();
+        s.watching();
+        let (path, stop) = s.listen();
+        // the TV does not answer, so this holds the bus for 1s
+        let slow = request(
+            &path,
+            &["cec 0 UserControlReleased reply ReportPowerStatus 1000"],
+        );
+        thread::sleep(Duration::from_millis(100));
+        let fast = request(&path, &["outlet 3 on", "outlet 3"]);
+        s.run_until("outlet 3", |_| fast.is_finished());
+        assert!(!slow.is_finished());
+        assert_eq!(fast.join().unwrap(), ["ok on", "ok on"]);
+        assert!(slow.join().unwrap()[0].starts_with("error"));
+        stop.stop();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn volumes_in_a_row() {
+        let mut s = Setup::new();
+        s.watching();
+        let (path, stop) = s.listen();
+        let volumes = s.daemon.feed.subscribe(vec!["volume".into()]);
+        let slider = request(&path, &["volume 30", "volume 35", "volume 45"]);
+        s.run_until("answers", |_| slider.is_finished());
+        let answers = slider.join().unwrap();
+        // those that were still waiting were skipped
+        for a in &answers[..2] {
+            assert!(
+                a.starts_with("ok") || a == "error superseded by a later volume",
+                "{a}"
+            );
+        }
+        assert_eq!(answers[2], "ok 45");
+        assert_eq!(s.avr.volume(), 45);
+        let last = volumes.try_iter().last();
+        assert_eq!(last.as_deref(), Some("event volume 45\n"));
+        stop.stop();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn idle_blocks() {
+        let mut s = Setup::new();
+        s.run_until("address", |s| s.daemon.g.cec_addr.is_some());
+        s.assert_idle();
+        s.watching();
+        s.assert_idle();
+        s.set_playing(true);
+        s.tv.standby();
+        s.run_until("Playing", |s| s.daemon.state == MediaState::Playing);
+        s.assert_idle();
+        s.set_playing(false);
+        s.run_until("Off", |s| s.daemon.state == MediaState::Off);
+        s.assert_idle();
+    }
+
+    #[test]
+    fn input_during_slow_volume() {
+        let mut s = Setup::new();
+        s.watching();
+        let stb = CecPhysicalAddress::from_num(0x3200);
+        let _player = Player::new(&s.bus, stb);
+        let (path, stop) = s.listen();
+        s.avr.set_status_delay(Duration::from_millis(300));
+        let slider = request(&path, &["volume 45"]);
+        // the volume has the bus
+        thread::sleep(Duration::from_millis(100));
+        let (tx, selected) = mpsc::channel();
+        s.sender
+            .send(Event::Control(Control::Input(stb), Some(tx)))
+            .unwrap();
+        // the main loop goes on while the selection waits for the bus
+        let fast = request(&path, &["outlet 3 on"]);
+        s.run_until("outlet 3", |_| fast.is_finished());
+        assert!(!slider.is_finished());
+        assert_eq!(fast.join().unwrap(), ["ok on"]);
+        s.run_until("volume", |_| slider.is_finished());
+        assert_eq!(slider.join().unwrap(), ["ok 45"]);
+        s.run_until("selected", |s| s.tv.active_source() == stb);
+        s.run_until("answer", |s| s.daemon.selection.is_none());
+        assert_eq!(selected.try_recv(), Ok(Ok("3.2.0.0".to_string())));
+        stop.stop();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn stop_hangs_up() {
+        let s = Setup::new();
+        let (path, stop) = s.listen();
+        // read until the daemon hangs up
+        let wait = |hello: &'static str| {
+            let stream = UnixStream::connect(&path).unwrap();
+            (&stream).write_all(hello.as_bytes()).unwrap();
+            thread::spawn(move || {
+                let lines = BufReader::new(&stream).lines();
+                lines.map(Result::unwrap).collect::<Vec<_>>()
+            })
+        };
+        let subscriber = wait("hello 1\nsubscribe\n");
+        let idle = wait("hello 1\n");
+        thread::sleep(Duration::from_millis(100));
+        stop.stop();
+        assert_eq!(subscriber.join().unwrap(), ["ok 1", "ok"]);
+        assert_eq!(idle.join().unwrap(), ["ok 1"]);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn audio_mode_status_quirk() {
+        let mut s = Setup::new();
+        s.set_playing(true);
+        s.run_until("Playing", |s| s.daemon.state == MediaState::Playing);
+        let m = Arc::clone(&s.daemon.actor);
+        let status = || {
+            m.cec()
+                .request_data(
+                    CecLogicalAddress::Playback1,
+                    CecLogicalAddress::Audiosystem,
+                    CecOpcode::GiveSystemAudioModeStatus,
+                    b"",
+                    CecOpcode::SystemAudioModeStatus,
+                )
+                .unwrap()
+        };
+        assert_eq!(status(), [0x33, 0]);
+        s.avr.quirk_status_is_phys_addr(false);
+        assert_eq!(status(), [1]);
+    }
+
+    #[test]
+    fn wrong_phys_addr_while_tv_is_off() {
+        let mut s = Setup::new();
+        s.avr.set_mains(true);
+        s.run_until("AVR reported 3.0.0.0", |s| s.daemon.g.avr_ready);
+        s.tv.quirk_wrong_phys_addr(s.pi.clone(), CecPhysicalAddress::from_num(0x3000));
+        s.run_until("corrected address", |s| {
+            s.pi.phys_addr() == PI && s.daemon.g.cec_addr.is_some()
+        });
+        assert_eq!(s.daemon.phys.get(), PI);
+    }
+
+    #[test]
+    fn devices_on_the_bus() {
+        let mut s = Setup::new();
+        s.avr.set_mains(true);
+        s.run_until("AVR scanned", |s| {
+            s.daemon
+                .devices
+                .iter()
+                .any(|(a, d)| a == CecLogicalAddress::Audiosystem && d.power.is_some())
+        });
+        assert_eq!(
+            s.daemon.devices.tree(),
+            "0.0.0.0 Tv \"BRAVIA\" Sony CEC 1.4 Standby
+  3.0.0.0 Audiosystem \"AVR-X540BT\" Denon CEC 1.4 Standby
+    3.3.0.0 Playback1 \"pi4\" CEC 1.4 On
+"
+        );
+    }
+
+    /// against a mosquitto on a free port
+    #[cfg(feature = "mqtt")]
+    #[test]
+    #[ignore = "needs mosquitto, run with --ignored"]
+    fn mqtt_broker() {
+        use crate::config::Mqtt;
+        use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+        use std::collections::HashMap;
+        use std::net::{TcpListener, TcpStream};
+        use std::process::{Command, Stdio};
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let broker = Command::new("mosquitto")
+            .args(["-p", &port.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let broker = broker.expect("mosquitto");
+        struct Kill(std::process::Child);
+        impl Drop for Kill {
+            fn drop(&mut self) {
+                let _ = self.0.kill();
+                let _ = self.0.wait();
+            }
+        }
+        let _broker = Kill(broker);
+        let end = Instant::now() + Duration::from_secs(5);
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(Instant::now() < end, "mosquitto does not listen");
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let mut s = Setup::new();
+        s.watching();
+        let stop = Stop::new();
+        let srv = s.server(&stop);
+        let m = Mqtt {
+            host: "127.0.0.1".into(),
+            port,
+            client_id: format!("cecremote-{}", std::process::id()),
+            discovery: String::new(),
+            ..Default::default()
+        };
+        let id = m.client_id.clone();
+        let daemon = thread::spawn(move || crate::mqtt::run(&m, &srv));
+
+        // topic: (payload, retained)
+        let (tx, published) = mpsc::channel();
+        let (client, mut connection) = Client::new(MqttOptions::new("test", "127.0.0.1", port), 10);
+        client.subscribe("cecremote/#", QoS::AtLeastOnce).unwrap();
+        thread::spawn(move || {
+            for e in connection.iter() {
+                if let Ok(Event::Incoming(Packet::Publish(p))) = e {
+                    let payload = String::from_utf8_lossy(&p.payload).into_owned();
+                    if tx.send((p.topic, (payload, p.retain))).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        let topics = RefCell::new(HashMap::new());
+        let seen = |topic: &str, payload: &str| {
+            topics.borrow_mut().extend(published.try_iter());
+            topics
+                .borrow()
+                .get(topic)
+                .is_some_and(|(p, _)| p == payload)
+        };
+        s.run_until("online", |_| seen("cecremote/availability", "online"));
+        s.run_until("state", |_| seen("cecremote/state", r#""Watching""#));
+        s.run_until("outlet", |_| seen("cecremote/outlet/2", "true"));
+
+        // retained: a new subscriber gets them
+        let (tx, retained) = mpsc::channel();
+        let (late, mut connection) = Client::new(MqttOptions::new("late", "127.0.0.1", port), 10);
+        late.subscribe("cecremote/+", QoS::AtLeastOnce).unwrap();
+        thread::spawn(move || {
+            for e in connection.iter() {
+                if let Ok(Event::Incoming(Packet::Publish(p))) = e {
+                    if tx.send((p.topic, p.retain)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        let mut got = HashMap::new();
+        while !(got.contains_key("cecremote/state") && got.contains_key("cecremote/tv")) {
+            let (topic, retain) = retained.recv_timeout(Duration::from_secs(5)).unwrap();
+            got.insert(topic, retain);
+        }
+        assert!(got["cecremote/state"], "retained");
+        assert!(got["cecremote/tv"], "retained");
+
+        // a command goes to the main loop
+        client
+            .publish("cecremote/outlet/3/set", QoS::AtLeastOnce, false, "on")
+            .unwrap();
+        s.run_until("outlet 3", |s| s.outlet(3));
+
+        // the broker takes the connection away, so the last will is published
+        let (_thief, mut connection) = Client::new(MqttOptions::new(id, "127.0.0.1", port), 10);
+        thread::spawn(move || for _ in connection.iter().take(3) {});
+        s.run_until("last will", |_| seen("cecremote/availability", "offline"));
+
+        stop.stop();
+        daemon.join().unwrap();
+    }
+
+    /// on a private dbus-daemon
+    #[cfg(feature = "dbus")]
+    #[test]
+    #[ignore = "needs dbus-daemon, run with --ignored"]
+    fn dbus_service() {
+        use crate::config::{Bus, Dbus};
+        use std::process::{Command, Stdio};
+        use zbus::blocking::{connection, fdo::PropertiesProxy, Proxy};
+
+        let daemon = Command::new("dbus-daemon")
+            .args(["--session", "--print-address", "--nofork"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut daemon = daemon.expect("dbus-daemon");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        struct Kill(std::process::Child);
+        impl Drop for Kill {
+            fn drop(&mut self) {
+                let _ = self.0.kill();
+                let _ = self.0.wait();
+            }
+        }
+        let _daemon = Kill(daemon);
+        let address = address.trim().to_string();
+
+        let mut s = Setup::new();
+        s.watching();
+        let stop = Stop::new();
+        let srv = s.server(&stop);
+        let d = Dbus {
+            bus: Bus::Session,
+            name: "org.cecremote.Test".into(),
+        };
+        let bus = connection::Builder::address(address.as_str()).unwrap();
+        thread::spawn(move || crate::dbus::serve_on(bus, &d, srv));
+
+        let conn = connection::Builder::address(address.as_str())
+            .unwrap()
+            .build()
+            .unwrap();
+        let (path, iface) = ("/org/cecremote", "org.cecremote.Remote1");
+        let props = PropertiesProxy::builder(&conn)
+            .destination("org.cecremote.Test")
+            .unwrap()
+            .path(path)
+            .unwrap()
+            .build()
+            .unwrap();
+        // it asks the main loop for the outlets first
+        s.run_until("on the bus", |_| {
+            props.get(iface.try_into().unwrap(), "State").is_ok()
+        });
+        let (tx, changes) = mpsc::channel();
+        let signals = props.receive_properties_changed().unwrap();
+        thread::spawn(move || {
+            for signal in signals {
+                let args = signal.args().unwrap();
+                let volume = args.changed_properties().get("Volume");
+                if tx.send(volume.map(|v| u8::try_from(v).unwrap())).is_err() {
+                    return;
+                }
+            }
+        });
+
+        // the outlet needs the main loop, so the calls are made elsewhere
+        let calls = thread::spawn(move || {
+            let remote = Proxy::new(&conn, "org.cecremote.Test", path, iface).unwrap();
+            let volume: u8 = remote.call("SetVolume", &(40u8,)).unwrap();
+            let muted: bool = remote.call("SetMute", &(true,)).unwrap();
+            let outlet: bool = remote.call("SetOutlet", &("3", true)).unwrap();
+            let bad = remote.call::<_, _, bool>("SetOutlet", &("no such", true));
+            (volume, muted, outlet, bad.is_err())
+        });
+        s.run_until("calls", |_| calls.is_finished());
+        assert_eq!(calls.join().unwrap(), (40, true, true, true));
+        assert_eq!(s.avr.volume(), 40);
+        assert!(s.avr.is_muted());
+        assert!(s.outlet(3));
+
+        let end = Instant::now() + Duration::from_secs(5);
+        loop {
+            let volume = changes.recv_timeout(end - Instant::now()).unwrap();
+            if let Some(v) = volume {
+                assert_eq!(v, 40);
+                break;
+            }
+        }
+        stop.stop();
+    }
+}
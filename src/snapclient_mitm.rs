@@ -1,46 +1,67 @@
+use crate::config::Config;
+use crate::event::Event;
+use crate::power::PowerSwitch;
+use crate::stop::Stop;
 use crate::Actor;
+use log::{debug, error, info};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
 use std::convert::TryInto;
 use std::io::{Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::process::{Command, Stdio};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Weak;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 pub fn main(
-    playing: Weak<AtomicBool>,
-    act: Arc<Mutex<Actor>>,
-    snapclient_vol: Arc<Mutex<u8>>,
-    snapclient_vol_changed: Weak<AtomicBool>,
+    events: Sender<Event>,
+    act: Arc<Actor>,
+    cfg: Arc<Config>,
+    stop: Arc<Stop>,
 ) -> Result<(), std::io::Error> {
     let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    // pid of snapclient
+    let child = Arc::new(Mutex::new(None));
+    let c = Arc::clone(&child);
+    stop.on_stop(move || {
+        if let Some(pid) = *c.lock().unwrap() {
+            let _ = kill(Pid::from_raw(pid), Signal::SIGTERM);
+        }
+        // a connection ends accept()
+        drop(TcpStream::connect(("127.0.0.1", port)));
+    });
 
-    loop {
-        act.lock().unwrap().pwr_socket.set_status(2, true).unwrap();
+    while !stop.is_stopped() {
+        act.pwr_socket.set_status(cfg.outlets.avr, true)?;
 
-        let mut snapclient = Command::new("snapclient")
+        let mut snapclient = Command::new(&cfg.snapcast.client)
             .args([
                 "-h",
                 "127.0.0.1",
                 "-p",
                 &format!("{}", listener.local_addr()?.port()),
-                "--logsink",
-                "system",
-                "-s",
-                "14",
-                "--mixer",
-                "none",
             ])
+            .args(&cfg.snapcast.args)
             .stdin(Stdio::null())
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .spawn()?;
+        *child.lock().unwrap() = Some(snapclient.id() as i32);
+        if stop.is_stopped() {
+            // the waker might have missed it
+            let _ = snapclient.kill();
+        }
         //journalctl -t snapclient
         loop {
             let (client, _) = listener.accept()?;
-            let _ = fwd(client, &playing, &snapclient_vol, &snapclient_vol_changed);
+            if stop.is_stopped() {
+                let _ = snapclient.kill();
+                break;
+            }
+            let _ = fwd(client, cfg.snapcast.server, &events);
             thread::sleep(Duration::from_secs(1));
             match snapclient.try_wait()? {
                 None => {
@@ -55,38 +76,31 @@ pub fn main(
         }
 
         let status = snapclient.wait()?;
-        println!("<5>snapclient exit: {}", status);
-        playing.upgrade().unwrap().store(false, Ordering::Relaxed);
+        *child.lock().unwrap() = None;
+        info!("snapclient exit: {}", status);
+        let _ = events.send(Event::Playing(false));
     }
+    Ok(())
 }
 
 fn fwd(
     client: TcpStream,
-    playing: &Weak<AtomicBool>,
-    snapclient_vol: &Arc<Mutex<u8>>,
-    snapclient_vol_changed: &Weak<AtomicBool>,
+    snapserver: SocketAddr,
+    events: &Sender<Event>,
 ) -> Result<(), std::io::Error> {
-    let server = TcpStream::connect("127.0.0.1:1704")?;
-    println!("started snapcast mitm");
+    let server = TcpStream::connect(snapserver)?;
+    info!("started snapcast mitm");
 
     let mut s = server.try_clone()?;
     let mut c = client.try_clone()?;
 
-    let playing = playing.clone();
-    let snapclient_vol_changed = snapclient_vol_changed.clone();
-    let snapclient_vol = snapclient_vol.clone();
+    let events = events.clone();
 
     thread::spawn(move || {
-        if let Err(e) = server_to_client(
-            server,
-            client,
-            &playing,
-            snapclient_vol,
-            &snapclient_vol_changed,
-        ) {
-            println!("<3>s2c err: {}", e);
+        if let Err(e) = server_to_client(server, client, &events) {
+            error!("s2c err: {}", e);
         }
-        playing.upgrade().unwrap().store(false, Ordering::Relaxed);
+        let _ = events.send(Event::Playing(false));
     });
 
     let mut buffer = [0u8; 1024 * 17];
@@ -94,19 +108,19 @@ fn fwd(
     loop {
         let r = match c.read(&mut buffer) {
             Err(e) => {
-                println!("<3>c2s error: {}", e);
+                error!("c2s error: {}", e);
                 s.shutdown(Shutdown::Write)?;
                 return Err(e);
             },
             Ok(r) => r
         };
         if r == 0 {
-            println!("<5>c2s done");
+            info!("c2s done");
             s.shutdown(Shutdown::Write)?;
             return Ok(());
         }
         if let Err(e) = s.write_all(&buffer[..r]) {
-            println!("<3>c2s error: {}", e);
+            error!("c2s error: {}", e);
             c.shutdown(Shutdown::Read)?;
             return Err(e);
         }
@@ -133,9 +147,7 @@ fn server_read(server: &mut TcpStream, buf: &mut [u8]) -> Result<(u16, usize), s
 fn server_to_client(
     mut server: TcpStream,
     mut client: TcpStream,
-    playing: &Weak<AtomicBool>,
-    snapclient_vol: Arc<Mutex<u8>>,
-    snapclient_vol_changed: &Weak<AtomicBool>,
+    events: &Sender<Event>,
 ) -> Result<(), std::io::Error> {
     let mut last = Instant::now();
     let mut playing_now = false;
@@ -172,17 +184,10 @@ fn server_to_client(
                     100 -> 80
                     1 -> 20
                      */
-                    *snapclient_vol.lock().unwrap() = ((vol + 34) as f32 * 0.6) as u8;
-                    snapclient_vol_changed
-                        .upgrade()
-                        .unwrap()
-                        .store(true, Ordering::Relaxed);
+                    let _ = events.send(Event::SnapVolume(((vol + 34) as f32 * 0.6) as u8));
                 }
-                println!(
-                    "SC Volume m:{} v:{:?}",
-                    m,
-                    v
-                );
+                let _ = events.send(Event::SnapMute(m));
+                debug!(muted = m, volume:? = v; "SC Volume m:{} v:{:?}", m, v);
                 
                 /*println!(
                     "ServerSettings: {} m:{} v:{:?}",
@@ -196,8 +201,8 @@ fn server_to_client(
                 last = Instant::now();
                 if !playing_now {
                     playing_now = true;
-                    playing.upgrade().unwrap().store(true, Ordering::Relaxed);
-                    println!("snapclient has data");
+                    let _ = events.send(Event::Playing(true));
+                    info!(playing = true; "snapclient has data");
                 }
             }
             4 if playing_now => {
@@ -206,8 +211,8 @@ fn server_to_client(
                 let time_diff = Instant::elapsed(&last);
                 if time_diff > Duration::new(5, 0) {
                     playing_now = false;
-                    playing.upgrade().unwrap().store(false, Ordering::Relaxed);
-                    println!("snapclient no data since 5s");
+                    let _ = events.send(Event::Playing(false));
+                    info!(playing = false; "snapclient no data since 5s");
                 }
             }
             1 => { //Codec Header
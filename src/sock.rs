@@ -1,16 +1,35 @@
-use crate::{print_err, Actor};
+use crate::access::{Class, Peer};
+use crate::config::{self, Config};
+use crate::event::{Control, Event};
+use crate::feed::Feed;
+use crate::outlet;
+use crate::power::PowerSwitch;
+use crate::protocol::{self, Request, Response};
+use crate::stop::Stop;
+use crate::Actor;
 use cec_linux::CecLogicalAddress;
+use cecremote::SOCKET_PATH;
+use log::{debug, info, warn};
+use nix::unistd::{Group, User};
+use std::collections::{HashMap, VecDeque};
 use std::env;
-use std::io::Read;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
 use std::os::fd::FromRawFd;
-use std::os::unix::net::UnixListener;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
+use std::thread;
 
-pub fn setup_sock() -> UnixListener {
+/// The control socket. Passed by systemd or bound to [SOCKET_PATH].
+/// The path is Some if we created the socket file
+pub fn setup_sock(cfg: &config::Socket) -> io::Result<(UnixListener, Option<PathBuf>)> {
     let pid = env::var("LISTEN_PID");
     let fds = env::var("LISTEN_FDS");
-    //let env = env::vars();
-    //println!("env {:?} {:?}", pid, fds);
     if pid
         .ok()
         .and_then(|x| x.parse::<u32>().ok())
@@ -20,118 +39,468 @@ pub fn setup_sock() -> UnixListener {
             .and_then(|x| x.parse::<usize>().ok())
             .is_some_and(|x| x == 1)
     {
-        unsafe { UnixListener::from_raw_fd(3) }
-    } else {
-        println!("no FD");
-        UnixListener::bind("/tmp/cec").expect("faild to listen on UDS")
-    }
-}
-pub fn listen_for_vol_changes(listener: UnixListener, act: Arc<Mutex<Actor>>) {
-    let mut buf = [0u8; 1];
-    for mut stream in listener.incoming().flatten() {
-        //if let Ok(mut stream) = stream {
-        if let Ok(()) = stream.read_exact(&mut buf) {
-            //0-100 Vol
-            //&0x80 on/off
-            //&0xC0 activesource
-            let n = buf[0];
-            match n {
-                1..=100 => {
-                    set_volume(&act, n);
+        return Ok((unsafe { UnixListener::from_raw_fd(3) }, None));
+    }
+    debug!("no FD");
+    let path = Path::new(SOCKET_PATH);
+    let l = match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            remove_stale(path)?;
+            UnixListener::bind(path)?
+        }
+        r => r?,
+    };
+    set_access(path, cfg.owner.as_deref(), cfg.group.as_deref(), cfg.mode)?;
+    Ok((l, Some(path.to_path_buf())))
+}
+/// Remove a socket file left over by a crash. Anything else at `path` is left alone
+pub fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+        Ok(m) if !m.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Ok(_) => {}
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use. Already running?", path.display()),
+        ));
+    }
+    info!("removing stale {}", path.display());
+    fs::remove_file(path)
+}
+/// Set owner, group and mode of a socket file we created
+pub fn set_access(
+    path: &Path,
+    owner: Option<&str>,
+    group: Option<&str>,
+    mode: Option<u32>,
+) -> io::Result<()> {
+    let owner = owner.map(uid).transpose()?;
+    let group = group.map(gid).transpose()?;
+    if owner.is_some() || group.is_some() {
+        std::os::unix::fs::chown(path, owner, group)?;
+    }
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+fn uid(user: &str) -> io::Result<u32> {
+    match user.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => match User::from_name(user)? {
+            Some(u) => Ok(u.uid.as_raw()),
+            None => Err(io::Error::other(format!("no user {}", user))),
+        },
+    }
+}
+fn gid(group: &str) -> io::Result<u32> {
+    match group.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => match Group::from_name(group)? {
+            Some(g) => Ok(g.gid.as_raw()),
+            None => Err(io::Error::other(format!("no group {}", group))),
+        },
+    }
+}
+/// most connections served at once
+const MAX_CLIENTS: usize = 32;
+
+/// What executes commands. Shared by the connections and other frontends
+pub struct Server {
+    events: Sender<Event>,
+    pub cfg: Arc<Config>,
+    pub feed: Arc<Feed>,
+    actor: Arc<Actor>,
+    pub stop: Arc<Stop>,
+    /// connections being served, to end them on stop
+    clients: Mutex<HashMap<usize, UnixStream>>,
+    next_client: AtomicUsize,
+}
+impl Server {
+    pub fn new(
+        events: Sender<Event>,
+        cfg: Arc<Config>,
+        feed: Arc<Feed>,
+        actor: Arc<Actor>,
+        stop: Arc<Stop>,
+    ) -> Arc<Server> {
+        let srv = Arc::new(Server {
+            events,
+            cfg,
+            feed,
+            actor,
+            stop,
+            clients: Mutex::default(),
+            next_client: AtomicUsize::new(0),
+        });
+        let weak = Arc::downgrade(&srv);
+        srv.stop.on_stop(move || {
+            if let Some(srv) = weak.upgrade() {
+                srv.hang_up();
+            }
+        });
+        srv
+    }
+    /// Execute `c` in this thread, or let the main loop do it if it needs its state.
+    /// Either way, the answer is waited for
+    pub fn control(&self, c: Control) -> Response {
+        if on_main_loop(&c) {
+            return ask(&self.events, c);
+        }
+        match c {
+            Control::Outlet(n, on) => return self.switch_outlet(n, on),
+            Control::Outlets => {
+                // not locked while the plugs answer
+                let timers = self.actor.outlet_timers.lock().unwrap().clone();
+                let status = outlet::status(&self.actor.pwr_socket, &self.cfg, &timers);
+                return Ok(status.to_string());
+            }
+            _ => {}
+        }
+        let volume = matches!(c, Control::Volume(_));
+        let r = execute(c, &self.actor);
+        if let (true, Ok(v)) = (volume, &r) {
+            self.feed.publish("volume", v.parse::<u8>().ok().into());
+        }
+        r
+    }
+    /// Switch an outlet and tell the state machine. Answers `on` or `off`
+    fn switch_outlet(&self, n: u8, on: Option<bool>) -> Response {
+        let on = outlet::switch(&self.actor.pwr_socket, n, on)
+            .map_err(|e| format!("outlet {}: {}", n, e))?;
+        let _ = self.events.send(Event::OutletSwitched(n, on));
+        Ok(if on { "on" } else { "off" }.to_string())
+    }
+    /// all of the state or a single field, as JSON
+    pub fn state(&self, field: Option<&str>) -> Response {
+        ask(&self.events, Control::State).and_then(|s| protocol::select(&s, field))
+    }
+    /// Keep a handle of `stream` to end it on stop. None if there are too many
+    fn add_client(&self, stream: &UnixStream) -> io::Result<Option<usize>> {
+        let mut clients = self.clients.lock().unwrap();
+        // hang_up has been or will be waiting for the lock
+        if self.stop.is_stopped() {
+            return Err(io::Error::other("stopped"));
+        }
+        if clients.len() >= MAX_CLIENTS {
+            return Ok(None);
+        }
+        let id = self.next_client.fetch_add(1, Ordering::Relaxed);
+        clients.insert(id, stream.try_clone()?);
+        Ok(Some(id))
+    }
+    /// end the event streams and the connections
+    fn hang_up(&self) {
+        self.feed.close();
+        for c in self.clients.lock().unwrap().values() {
+            let _ = c.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Serve each connection in a thread of its own. See [crate::protocol]
+pub fn listen_for_vol_changes(listener: UnixListener, srv: Arc<Server>) {
+    // a connection ends accept()
+    if let Some(path) = listener
+        .local_addr()
+        .ok()
+        .and_then(|a| a.as_pathname().map(PathBuf::from))
+    {
+        srv.stop.on_stop(move || drop(UnixStream::connect(path)));
+    }
+    for stream in listener.incoming().flatten() {
+        if srv.stop.is_stopped() {
+            return;
+        }
+        let id = match srv.add_client(&stream) {
+            Ok(Some(id)) => id,
+            Ok(None) => {
+                warn!("too many connections");
+                let _ = (&stream).write_all(protocol::format(&Err("busy".into())).as_bytes());
+                continue;
+            }
+            Err(e) => {
+                debug!("connection: {}", e);
+                continue;
+            }
+        };
+        let srv = Arc::clone(&srv);
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, &srv) {
+                debug!("connection: {}", e);
+            }
+            srv.clients.lock().unwrap().remove(&id);
+        });
+    }
+}
+
+/// A line of a connection. They are answered in order
+enum Job {
+    /// known without doing anything
+    Answer(Response),
+    Run(Control),
+    State(Option<String>),
+    Subscribe(Vec<String>),
+}
+
+/// Handle one connection. Legacy clients send a single byte.
+///
+/// Lines are read while earlier ones are still executed by [work]
+fn serve(stream: UnixStream, srv: &Server) -> io::Result<()> {
+    let peer = Peer::of(&stream)?;
+    let allow = &srv.cfg.socket.allow;
+    let mut reader = BufReader::new(&stream);
+    let first = match reader.fill_buf()? {
+        [] => return Ok(()),
+        b => b[0],
+    };
+    if first != b'h' {
+        reader.consume(1);
+        match protocol::legacy(first) {
+            Some(c) if !peer.may(Class::of(&c), allow) => {
+                warn!(uid = peer.uid, pid = peer.pid; "{:?} denied", c);
+            }
+            Some(c) => {
+                if let Err(e) = srv.control(c) {
+                    warn!(byte = first; "legacy request: {}", e);
+                }
+            }
+            None => {
+                warn!(byte = first; "unknown request");
+                // probably a command without hello
+                (&stream).write_all(protocol::format(&Err("hello first".into())).as_bytes())?;
+            }
+        }
+        return Ok(());
+    }
+    let (jobs, queue) = mpsc::channel();
+    thread::scope(|s| {
+        let worker = s.spawn(|| work(&stream, queue, srv));
+        let mut version = None;
+        let mut line = Vec::new();
+        loop {
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) if line.last() != Some(&b'\n') => break,
+                Ok(_) => {}
+                Err(e) => {
+                    debug!("connection: {}", e);
+                    break;
+                }
+            }
+            let text = String::from_utf8_lossy(&line).into_owned();
+            line.clear();
+            debug!(request = text.trim(); "request");
+            let req = protocol::parse(&text, &srv.cfg);
+            let class = match &req {
+                Ok(Request::Control(c)) => Some(Class::of(c)),
+                Ok(Request::State(_) | Request::Subscribe(_)) => Some(Class::Read),
+                Ok(Request::Hello(_)) | Err(_) => None,
+            };
+            let job = match (req, version) {
+                (_, Some(_)) if class.is_some_and(|c| !peer.may(c, allow)) => {
+                    warn!(uid = peer.uid, pid = peer.pid; "{} denied", text.trim());
+                    Job::Answer(Err("permission denied".to_string()))
                 }
-                0 => println!("mute"),
-                101..=127 => println!("?"),
-                0x80..=u8::MAX => match n & 0xF8 {
-                    0x80 => {
-                        //request sispm to be switched
-                        let on = n & 0x04 != 0;
-                        let mut n = n & 0x03;
-                        if n == 0 {
-                            n = 4;
-                        }
-                        println!("switch {} {}", n, on);
-                        act.lock()
-                            .expect("could not lock for ctrl sock")
-                            .pwr_socket
-                            .set_status(n, on)
-                            .expect("pwr boom");
-                    }
-                    0xC0 => {
-                        //request active source to be 3.x.0.0
-                        //                              5 = SteamDeck Game
-                        //                              3 = Pi Cable/Sat
-                        //                              ? = Ps4
-                        //4 blueRay, 1 DVD/BlueRay, 2 Media Player
-                        let data = [0x30 + (n & 7), 0];
-
-                        let act = act.lock().expect("could not lock for ctrl sock");
-
-                        let from = match act
-                            .cec
-                            .get_log()
-                            .ok()
-                            .and_then(|l| l.addresses().first().copied())
-                            .unwrap_or(CecLogicalAddress::UnregisteredBroadcast)
-                        {
-                            CecLogicalAddress::UnregisteredBroadcast => continue,
-                            a => a,
-                        };
-                        /*.map_or(0xff, |l| l.log_addr[0]) {
-                            0xff => {
-                                //not connected
-                                continue;
-                            }
-                            0xf => {
-                                //not registered
-                                continue;
-                            }
-                            f @ 0..=0xe => CecLogicalAddress::try_from(f).unwrap(),
-                            _ => unreachable!("no cec address"),
-                        };*/
-
-                        print_err(
-                            act.cec.transmit_data(
-                                from,
-                                CecLogicalAddress::UnregisteredBroadcast,
-                                cec_linux::CecOpcode::ActiveSource,
-                                &data,
-                            ),
-                            "cec boom SetStreamPath",
-                        );
-                    }
-                    _ => println!("?"),
-                },
+                (Ok(Request::Hello(v)), _) if v >= 1 => {
+                    let v = v.min(protocol::VERSION);
+                    version = Some(v);
+                    Job::Answer(Ok(v.to_string()))
+                }
+                (Ok(Request::Hello(v)), _) => {
+                    Job::Answer(Err(format!("unsupported version {}", v)))
+                }
+                (_, None) => Job::Answer(Err("hello first".to_string())),
+                (Ok(Request::Control(c)), Some(_)) => Job::Run(c),
+                (Ok(Request::State(f)), Some(_)) => Job::State(f),
+                (Ok(Request::Subscribe(names)), Some(_)) => Job::Subscribe(names),
+                (Err(e), Some(_)) => Job::Answer(Err(e)),
+            };
+            // the connection only gets events after a subscribe
+            let last = version.is_none() || matches!(job, Job::Subscribe(_));
+            if jobs.send(job).is_err() || last {
+                // no handshake, no conversation
+                break;
+            }
+        }
+        drop(jobs);
+        worker.join().unwrap_or(Ok(()))
+    })
+}
+
+/// Execute the jobs of a connection in order and write the answers.
+///
+/// A volume is skipped if a later one is waiting, as a slider sends them
+/// faster than the AVR follows
+fn work(stream: &UnixStream, jobs: Receiver<Job>, srv: &Server) -> io::Result<()> {
+    let mut queue = VecDeque::new();
+    let mut writer = stream;
+    loop {
+        if queue.is_empty() {
+            match jobs.recv() {
+                Ok(j) => queue.push_back(j),
+                Err(_) => return Ok(()),
+            }
+        }
+        queue.extend(jobs.try_iter());
+        let r = match queue.pop_front().expect("a job") {
+            Job::Run(Control::Volume(_))
+                if queue
+                    .iter()
+                    .any(|j| matches!(j, Job::Run(Control::Volume(_)))) =>
+            {
+                Err("superseded by a later volume".to_string())
+            }
+            Job::Answer(r) => r,
+            Job::Run(c) => srv.control(c),
+            Job::State(f) => srv.state(f.as_deref()),
+            Job::Subscribe(names) => {
+                writer.write_all(protocol::format(&Ok(String::new())).as_bytes())?;
+                forward(stream, names, &srv.feed);
+                return Ok(());
+            }
+        };
+        writer.write_all(protocol::format(&r).as_bytes())?;
+    }
+}
+
+/// write events to a subscriber until it hangs up
+fn forward(stream: &UnixStream, names: Vec<String>, feed: &Feed) {
+    let (lines, events) = mpsc::channel();
+    feed.subscribe_to(names, lines.clone());
+    thread::scope(|s| {
+        // anything sent is ignored. An empty line tells of the hang up
+        s.spawn(move || {
+            let mut buf = [0u8; 64];
+            while (&*stream).read(&mut buf).is_ok_and(|n| n > 0) {}
+            let _ = lines.send(String::new());
+        });
+        for line in events.iter() {
+            if line.is_empty() || (&*stream).write_all(line.as_bytes()).is_err() {
+                break;
+            }
+        }
+        // for the reader
+        let _ = stream.shutdown(Shutdown::Both);
+    });
+}
+
+/// let the main loop execute `c` and wait for the result
+fn ask(events: &Sender<Event>, c: Control) -> Response {
+    let (tx, rx) = mpsc::channel();
+    if events.send(Event::Control(c, Some(tx))).is_err() {
+        return Err("shutting down".to_string());
+    }
+    rx.recv().unwrap_or_else(|_| Err("shutting down".to_string()))
+}
+
+/// do what was requested
+pub fn execute(c: Control, act: &Actor) -> Response {
+    match c {
+        Control::Volume(vol) => set_volume(act, vol),
+        Control::Mute(on) => {
+            let from = own_addr(act).ok_or("not connected")?;
+            match super::mute(&**act.cec(), from, on)? {
+                true => Ok("muted".to_string()),
+                false => Ok("unmuted".to_string()),
             }
         }
-        //}
+        Control::OutletStatus(n) => match act.pwr_socket.get_status(n) {
+            Ok(true) => Ok("on".to_string()),
+            Ok(false) => Ok("off".to_string()),
+            Err(e) => Err(format!("outlet {}: {}", n, e)),
+        },
+        Control::Cec {
+            to,
+            opcode,
+            data,
+            reply,
+        } => {
+            let from = own_addr(act).ok_or("not connected")?;
+            info!(target: "cec", destination:? = to, opcode; "sending {:#x} {:x?}", opcode, data);
+            match reply {
+                None => act
+                    .cec()
+                    .transmit_raw(from, to, opcode, &data)
+                    .map(|_| String::new()),
+                Some((wait_for, timeout)) => act
+                    .cec()
+                    .request_raw(from, to, opcode, &data, wait_for, timeout)
+                    .map(|f| protocol::cec_reply(&f)),
+            }
+            .map_err(|e| e.to_string())
+        }
+        c => Err(format!("{:?} is up to the main loop", c)),
     }
 }
 
-fn set_volume(act: &Arc<Mutex<Actor>>, vol: u8) {
-    println!("Vol Requested: {}", vol);
-    let cec = &act.lock().expect("could not lock for ctrl sock").cec;
+/// needs what the main loop knows
+fn on_main_loop(c: &Control) -> bool {
+    matches!(
+        c,
+        Control::State
+            | Control::ActiveSource(_)
+            | Control::Input(_)
+            | Control::OutletTimer(..)
+            | Control::OutletCancel(_)
+    )
+}
 
-    let from = match cec
+/// our logical address. None if not connected or not registered
+fn own_addr(act: &Actor) -> Option<CecLogicalAddress> {
+    match act
+        .cec()
         .get_log()
         .ok()
-        .and_then(|l| l.addresses().first().copied())
+        .and_then(|l| l.first().copied())
         .unwrap_or(CecLogicalAddress::UnregisteredBroadcast)
     {
-        CecLogicalAddress::UnregisteredBroadcast => return,
-        a => a,
-    };
-    /*.map_or(0xff, |l| l.log_addr[0]) {
-        0xff => {
-            //not connected
-            return;
-        }
-        0xf => {
-            //not registered
-            return;
-        }
-        f @ 0..=0xe => CecLogicalAddress::try_from(f).unwrap(),
-        _ => unreachable!("no cec address"),
-    }; */
+        CecLogicalAddress::UnregisteredBroadcast => None,
+        a => Some(a),
+    }
+}
 
-    super::set_volume(cec, from, vol, None);
+/// Answers the volume reached
+fn set_volume(act: &Actor, vol: u8) -> Response {
+    info!(volume = vol; "Vol Requested: {}", vol);
+    let from = own_addr(act).ok_or("not connected")?;
+    match act.volume.set(&**act.cec(), from, vol, None) {
+        Ok(v) => Ok(v.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale() {
+        let dir = env::temp_dir().join(format!("cecremote-stale-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sock");
+        remove_stale(&path).unwrap();
+
+        fs::write(&path, "config").unwrap();
+        let e = remove_stale(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert!(path.exists(), "a file is kept");
+        fs::remove_file(&path).unwrap();
+
+        let l = UnixListener::bind(&path).unwrap();
+        let e = remove_stale(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        drop(l);
+        remove_stale(&path).unwrap();
+        assert!(!path.exists(), "a dead socket is removed");
+        fs::remove_dir(&dir).unwrap();
+    }
 }
@@ -0,0 +1,803 @@
+//! The decisions of the main loop.
+//!
+//! [step] only looks at its inputs and returns what should be done.
+//! Doing it is left to the caller.
+use crate::GState;
+use cec_linux::{CecLogicalAddress, CecPowerStatus};
+use log::Level;
+use std::time::Duration;
+
+/// repeat actions that had no effect yet
+pub const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+/// give up on a state that is not [stable](is_stable)
+pub const TIME_TO_SWITCH_OFF: Duration = Duration::from_secs(7);
+/// AVR did not turn on
+pub const LONG_WAIT: Duration = Duration::from_millis(5_500);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MediaState {
+    /// TV has audio
+    ///
+    /// Everything is on
+    Watching,
+    /// snapcast has audio
+    ///
+    /// Only AVR is on
+    Playing,
+    /// power down AVR socket
+    SwitchOff,
+    /// just do nothing
+    Off,
+    /// AVR has power, but not booted yet.
+    ///
+    /// Everything else is powered off.
+    ///
+    /// Ends once Audio sends ReportPhysicalAddr
+    WaitForAudio,
+    /// Initial State - Audio has power, standby is unknown
+    AVRHasPwr,
+}
+
+/// what snapcast is up to
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Snapcast {
+    /// snapclient has data
+    pub playing: bool,
+    /// snapclient volume changed since it was last set
+    pub vol_changed: bool,
+    /// snapclient is muted
+    pub muted: bool,
+    /// [Snapcast::muted] changed since it was last set
+    pub mute_changed: bool,
+}
+
+/// Timeouts of a state that is not [stable](is_stable)
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Timers {
+    /// [LONG_WAIT] just passed. Only set once per state
+    pub long_wait: bool,
+    /// [TIME_TO_SWITCH_OFF] passed
+    pub hang: bool,
+}
+
+/// states that can last forever
+pub fn is_stable(state: MediaState) -> bool {
+    matches!(
+        state,
+        MediaState::Off | MediaState::Watching | MediaState::Playing
+    )
+}
+
+/// states that need the AVR outlet on
+pub fn needs_avr(state: MediaState) -> bool {
+    !matches!(state, MediaState::Off | MediaState::SwitchOff)
+}
+
+/// Volume to set on the AVR
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Volume {
+    /// the one of snapclient
+    Snapclient,
+    /// the one of snapclient, but remember the current one first
+    SnapclientStoreOld,
+    /// the one remembered by [Volume::SnapclientStoreOld]
+    Old,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// print a line
+    Log(Level, String),
+    SwitchLight(bool),
+    /// also forgets everything known about the AVR
+    SwitchAvr(bool),
+    /// request audio focus
+    AudioModeOn(CecLogicalAddress),
+    /// end audio focus
+    AudioModeOff(CecLogicalAddress),
+    SetVolume(CecLogicalAddress, Volume),
+    /// clear [Snapcast::vol_changed]
+    ClearVolChanged,
+    /// mute or unmute the AVR
+    Mute(CecLogicalAddress, bool),
+    /// clear [Snapcast::mute_changed]
+    ClearMuteChanged,
+    /// broadcast ActiveSource for the TV's input
+    ResendActiveSource(CecLogicalAddress, u16),
+    TurnOnAvr(CecLogicalAddress),
+    StandbyAvr(CecLogicalAddress),
+    /// ask for the AVRs power status. The reply is caught by the monitor
+    RequestPwrState(CecLogicalAddress),
+    GivePhysAddr(CecLogicalAddress),
+    /// set [GState::avr_ready]
+    MarkAvrReady,
+}
+
+/// Information that has to be requested before a decision can be made
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Query {
+    /// power status of the AVR
+    AvrPower(CecLogicalAddress),
+    /// is the AVR in system audio mode
+    AudioMode(CecLogicalAddress),
+    /// has the AVR outlet power
+    AvrOutlet,
+}
+
+/// Answers to [Query]s. None if not asked yet
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Answers {
+    /// None inside if the AVR did not answer
+    pub avr_power: Option<Option<CecPowerStatus>>,
+    pub audio_mode: Option<bool>,
+    /// None inside if the outlet could not be read
+    pub avr_outlet: Option<Option<bool>>,
+}
+impl Answers {
+    fn is_empty(&self) -> bool {
+        self.avr_power.is_none() && self.audio_mode.is_none() && self.avr_outlet.is_none()
+    }
+}
+
+/// Result of [step]
+#[derive(Debug, Default, PartialEq)]
+pub struct Step {
+    /// None: stay in the current state
+    pub next: Option<MediaState>,
+    /// to be done in order
+    pub actions: Vec<Action>,
+    /// answer it and call [step] again with the same inputs
+    pub ask: Option<Query>,
+}
+impl Step {
+    fn stay(actions: Vec<Action>) -> Step {
+        Step {
+            actions,
+            ..Default::default()
+        }
+    }
+    fn to(next: MediaState, actions: Vec<Action>) -> Step {
+        Step {
+            next: Some(next),
+            actions,
+            ask: None,
+        }
+    }
+    fn ask(query: Query, actions: Vec<Action>) -> Step {
+        Step {
+            ask: Some(query),
+            actions,
+            next: None,
+        }
+    }
+}
+
+/// Decide what to do next.
+///
+/// If the returned [Step] has a query, its answer has to be added to `answers` and `step` called again.
+/// Actions done before a query are not repeated.
+pub fn step(
+    state: MediaState,
+    g: &GState,
+    snap: Snapcast,
+    timers: Timers,
+    answers: &Answers,
+) -> Step {
+    if !is_stable(state) && timers.hang {
+        let mut s = decide(MediaState::SwitchOff, g, snap, timers, answers);
+        if answers.is_empty() {
+            s.actions
+                .insert(0, Action::Log(Level::Error, format!("Hang in State {:?}", state)));
+        }
+        if s.ask.is_none() && s.next.is_none() {
+            s.next = Some(MediaState::SwitchOff);
+        }
+        return s;
+    }
+    decide(state, g, snap, timers, answers)
+}
+
+fn decide(
+    state: MediaState,
+    g: &GState,
+    snap: Snapcast,
+    timers: Timers,
+    answers: &Answers,
+) -> Step {
+    use Action::*;
+    let GState {
+        tv,
+        avr_ready,
+        avr_standby,
+        cec_addr,
+        active_source,
+        ..
+    } = *g;
+    let pulse = snap.playing;
+    match state {
+        MediaState::Watching if tv == Some(false) => {
+            // TV turned Off
+            let mut a = vec![Log(Level::Info, format!("Watching: {tv:?} {pulse}")), SwitchLight(false)];
+            if pulse {
+                if let Some(from) = cec_addr {
+                    a.push(AudioModeOn(from));
+                    a.push(SetVolume(from, Volume::SnapclientStoreOld));
+                    if snap.muted {
+                        a.push(Mute(from, true));
+                    }
+                }
+                Step::to(MediaState::Playing, a)
+            } else {
+                Step::to(MediaState::SwitchOff, a)
+            }
+        }
+        MediaState::Playing if snap.vol_changed => {
+            //snapcast vol changed
+            match cec_addr {
+                Some(from) => {
+                    Step::stay(vec![ClearVolChanged, SetVolume(from, Volume::Snapclient)])
+                }
+                None => Step::stay(vec![]),
+            }
+        }
+        MediaState::Playing if snap.mute_changed => match cec_addr {
+            Some(from) => Step::stay(vec![ClearMuteChanged, Mute(from, snap.muted)]),
+            None => Step::stay(vec![]),
+        },
+        MediaState::Playing if tv == Some(true) => {
+            // TV turned on while snapcast runns
+            let mut a = vec![Log(Level::Info, format!("Playing: {tv:?} {pulse}"))];
+            if let Some(from) = cec_addr {
+                a.push(AudioModeOff(from));
+                a.push(SetVolume(from, Volume::Old));
+                if snap.muted {
+                    a.push(Mute(from, false));
+                }
+            }
+            a.push(SwitchLight(true));
+            Step::to(MediaState::Watching, a)
+        }
+        MediaState::Playing if !pulse => {
+            // Audio turned Off
+            let log = Log(Level::Info, format!("Playing: {tv:?} {pulse}"));
+            match cec_addr {
+                Some(from) => {
+                    let mut a = vec![log, AudioModeOff(from), SetVolume(from, Volume::Old)];
+                    if snap.muted {
+                        a.push(Mute(from, false));
+                    }
+                    Step::to(MediaState::SwitchOff, a)
+                }
+                None => Step::stay(vec![log]),
+            }
+        }
+        MediaState::Off if (pulse || tv == Some(true)) && !g.avr_off_by_hand => {
+            // Turn On
+            Step::to(
+                MediaState::WaitForAudio,
+                vec![
+                    Log(Level::Info, format!("Off: tv={tv:?} pulse={pulse}")),
+                    SwitchAvr(true),
+                ],
+            )
+        }
+        MediaState::WaitForAudio if avr_ready => {
+            // ARV is now available (after activating its power socket)
+            let log = Log(Level::Info, format!("WaitForAudio+avr_ready: {tv:?} {pulse}"));
+
+            //FIXME Some(false) true -> cec cmd: Audiosystem -> Unregistered   RoutingChange: CecDatapacket([48, 0, 51, 0])
+            //but only once...
+            if tv == Some(true) {
+                let mut a = vec![log, SwitchLight(true)];
+                if let Some(from) = cec_addr {
+                    if active_source != 0xffff {
+                        // or just send to audio...
+                        a.push(ResendActiveSource(from, active_source));
+                    }
+                }
+                Step::to(MediaState::Watching, a)
+            } else if pulse {
+                let from = match cec_addr {
+                    Some(a) => a,
+                    None => return Step::stay(vec![log, Log(Level::Info, "no cec address".to_string())]),
+                };
+                match answers.avr_power {
+                    None => Step::ask(Query::AvrPower(from), vec![log, AudioModeOn(from)]),
+                    Some(Some(CecPowerStatus::On)) => {
+                        // store volume
+                        let mut a = vec![SetVolume(from, Volume::SnapclientStoreOld)];
+                        if snap.muted {
+                            a.push(Mute(from, true));
+                        }
+                        Step::to(MediaState::Playing, a)
+                    }
+                    Some(Some(CecPowerStatus::Standby)) => Step::stay(vec![TurnOnAvr(from)]),
+                    Some(_) => {
+                        //retry cec audio mode
+                        Step::stay(vec![])
+                    }
+                }
+            } else {
+                Step::to(
+                    MediaState::SwitchOff,
+                    vec![
+                        log,
+                        Log(Level::Error, "TV and Audio off. No need for AVR anymore".to_string()),
+                    ],
+                )
+            }
+        }
+        MediaState::WaitForAudio if timers.long_wait => {
+            //AVR wont turn on but has power
+            let log = Log(Level::Warn, "WaitForAudio takes too long".to_string());
+            let from = match cec_addr {
+                Some(a) => a,
+                None => {
+                    return Step::stay(vec![log, Log(Level::Warn, "no address to send from".to_string())])
+                }
+            };
+            let avr_pwr = match answers.avr_power {
+                None => return Step::ask(Query::AvrPower(from), vec![log]),
+                Some(p) => p,
+            };
+            let mut a = Vec::new();
+            if avr_pwr.is_some() && answers.audio_mode.is_none() {
+                a.push(MarkAvrReady);
+            }
+
+            if tv == Some(true) {
+                if let Some(CecPowerStatus::On) = avr_pwr {
+                    //all good
+                    a.push(Log(Level::Warn, "But AVR is already on".to_string()));
+                } else {
+                    a.push(TurnOnAvr(from));
+                }
+            } else if pulse {
+                if let Some(CecPowerStatus::On) = avr_pwr {
+                    //all good
+                    match answers.audio_mode {
+                        None => return Step::ask(Query::AudioMode(from), a),
+                        Some(true) => {
+                            a.push(Log(Level::Warn, "But AVR is already in SystemAudioMode".to_string()));
+                            return Step::stay(a);
+                        }
+                        Some(false) => {}
+                    }
+                }
+                a.push(AudioModeOn(from));
+            }
+            Step::stay(a)
+        }
+        MediaState::Watching if avr_standby != Some(false) => {
+            //TV is running but AVR is off
+            let log = Log(Level::Info, format!("Watching: avr standby: {avr_standby:?}"));
+            match cec_addr {
+                Some(from) => Step::stay(vec![log, TurnOnAvr(from), RequestPwrState(from)]),
+                None => Step::stay(vec![log]),
+            }
+        }
+        MediaState::Playing if avr_standby != Some(false) => {
+            //snapcast is running but AVR is off
+            // none -> ask for standby status
+            let log = Log(Level::Info, format!("Playing: avr standby: {avr_standby:?}")); //None -> is not the reason the TV turns on
+            match cec_addr {
+                Some(from) => Step::stay(vec![log, AudioModeOn(from), RequestPwrState(from)]),
+                None => Step::stay(vec![
+                    log,
+                    Log(Level::Info, "not connected to bus".to_string()),
+                ]),
+            }
+        }
+        MediaState::AVRHasPwr => {
+            let log = Log(Level::Info, format!("AVRHasPwr: {avr_standby:?}"));
+            match avr_standby {
+                None => {
+                    // Service started, dont know whats up
+                    let from = match cec_addr {
+                        Some(a) => a,
+                        None => return Step::stay(vec![log]),
+                    };
+                    match answers.avr_power {
+                        None => Step::ask(Query::AvrPower(from), vec![log]),
+                        Some(Some(CecPowerStatus::Standby)) => {
+                            Step::to(MediaState::SwitchOff, vec![])
+                        }
+                        Some(Some(CecPowerStatus::On)) => {
+                            Step::to(MediaState::WaitForAudio, vec![])
+                        }
+                        Some(_) => Step::stay(vec![]),
+                    }
+                }
+                Some(false) => {
+                    //AVR is on
+                    let mut a = vec![log];
+                    if let Some(from) = cec_addr {
+                        a.push(GivePhysAddr(from));
+                    }
+                    Step::to(MediaState::WaitForAudio, a)
+                }
+                Some(true) => {
+                    //AVR is in standby
+                    Step::to(MediaState::SwitchOff, vec![log])
+                }
+            }
+        }
+        MediaState::SwitchOff => {
+            // make sure AVR is in standby before cutting the power
+            match answers.avr_outlet {
+                None => Step::ask(Query::AvrOutlet, vec![]),
+                Some(None) => Step::stay(vec![]),
+                Some(Some(true)) => {
+                    let log = Log(Level::Info, format!("Off but AVR on. Standby: {avr_standby:?}"));
+                    if avr_standby == Some(true) {
+                        // stay off
+                        // this is enforcing a delay before cutting the AVR power
+                        Step::to(MediaState::Off, vec![log, SwitchAvr(false)])
+                    } else {
+                        //send AVR to standby first
+                        match cec_addr {
+                            Some(from) => {
+                                Step::stay(vec![log, StandbyAvr(from), RequestPwrState(from)])
+                            }
+                            None => Step::stay(vec![
+                                log,
+                                Log(Level::Info, "no cec address".to_string()),
+                            ]),
+                        }
+                    }
+                }
+                Some(Some(false)) => {
+                    //already off
+                    Step::to(MediaState::Off, vec![])
+                }
+            }
+        }
+        _ => Step::stay(vec![]), //stay in state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Action::*;
+    use CecPowerStatus::{On, Standby};
+    use MediaState::*;
+
+    const P1: CecLogicalAddress = CecLogicalAddress::Playback1;
+    const NOW: Timers = Timers {
+        long_wait: false,
+        hang: false,
+    };
+    const LONG: Timers = Timers {
+        long_wait: true,
+        hang: false,
+    };
+    const HANG: Timers = Timers {
+        long_wait: false,
+        hang: true,
+    };
+
+    fn g(
+        tv: Option<bool>,
+        avr_ready: bool,
+        avr_standby: Option<bool>,
+        cec_addr: Option<CecLogicalAddress>,
+    ) -> GState {
+        GState {
+            tv,
+            avr_ready,
+            avr_standby,
+            cec_addr,
+            active_source: 0xffff,
+            muted: None,
+            avr_off_by_hand: false,
+        }
+    }
+    fn snap(playing: bool) -> Snapcast {
+        Snapcast {
+            playing,
+            ..Default::default()
+        }
+    }
+    fn pwr(p: Option<CecPowerStatus>) -> Answers {
+        Answers {
+            avr_power: Some(p),
+            ..Default::default()
+        }
+    }
+    fn outlet(o: Option<bool>) -> Answers {
+        Answers {
+            avr_outlet: Some(o),
+            ..Default::default()
+        }
+    }
+    fn none() -> Answers {
+        Answers::default()
+    }
+
+    struct Case {
+        name: &'static str,
+        state: MediaState,
+        g: GState,
+        snap: Snapcast,
+        timers: Timers,
+        answers: Answers,
+        next: Option<MediaState>,
+        /// without logs
+        actions: Vec<Action>,
+        ask: Option<Query>,
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn case(
+        name: &'static str,
+        state: MediaState,
+        g: GState,
+        snap: Snapcast,
+        timers: Timers,
+        answers: Answers,
+        next: Option<MediaState>,
+        actions: Vec<Action>,
+        ask: Option<Query>,
+    ) -> Case {
+        Case {
+            name,
+            state,
+            g,
+            snap,
+            timers,
+            answers,
+            next,
+            actions,
+            ask,
+        }
+    }
+
+    fn without_logs(mut s: Step) -> Step {
+        s.actions.retain(|a| !matches!(a, Log(..)));
+        s
+    }
+
+    #[test]
+    fn table() {
+        let vol_changed = Snapcast {
+            playing: true,
+            vol_changed: true,
+            ..Default::default()
+        };
+        let muted = Snapcast {
+            playing: true,
+            muted: true,
+            ..Default::default()
+        };
+        let mute_changed = Snapcast {
+            mute_changed: true,
+            ..muted
+        };
+        let mut tv_src = g(Some(true), true, None, Some(P1));
+        tv_src.active_source = 0x1000;
+        let mut by_hand = g(Some(true), false, None, Some(P1));
+        by_hand.avr_off_by_hand = true;
+        let cases = [
+            // Watching
+            case("watching tv off", Watching, g(Some(false), true, Some(false), Some(P1)), snap(false), NOW, none(),
+                Some(SwitchOff), vec![SwitchLight(false)], None),
+            case("watching tv off while playing", Watching, g(Some(false), true, Some(false), Some(P1)), snap(true), NOW, none(),
+                Some(Playing), vec![SwitchLight(false), AudioModeOn(P1), SetVolume(P1, Volume::SnapclientStoreOld)], None),
+            case("watching tv off while playing no addr", Watching, g(Some(false), true, Some(false), None), snap(true), NOW, none(),
+                Some(Playing), vec![SwitchLight(false)], None),
+            case("watching avr unknown", Watching, g(Some(true), true, None, Some(P1)), snap(false), NOW, none(),
+                None, vec![TurnOnAvr(P1), RequestPwrState(P1)], None),
+            case("watching avr standby no addr", Watching, g(Some(true), true, Some(true), None), snap(false), NOW, none(),
+                None, vec![], None),
+            case("watching all good", Watching, g(Some(true), true, Some(false), Some(P1)), snap(true), NOW, none(),
+                None, vec![], None),
+            case("watching never hangs", Watching, g(Some(true), true, Some(false), Some(P1)), snap(false), HANG, none(),
+                None, vec![], None),
+            // Playing
+            case("playing vol changed", Playing, g(Some(false), true, Some(false), Some(P1)), vol_changed, NOW, none(),
+                None, vec![ClearVolChanged, SetVolume(P1, Volume::Snapclient)], None),
+            case("playing vol changed no addr", Playing, g(Some(false), true, Some(false), None), vol_changed, NOW, none(),
+                None, vec![], None),
+            case("playing vol changed before tv on", Playing, g(Some(true), true, Some(false), Some(P1)), vol_changed, NOW, none(),
+                None, vec![ClearVolChanged, SetVolume(P1, Volume::Snapclient)], None),
+            case("playing mute changed", Playing, g(Some(false), true, Some(false), Some(P1)), mute_changed, NOW, none(),
+                None, vec![ClearMuteChanged, Mute(P1, true)], None),
+            case("playing muted tv on", Playing, g(Some(true), true, Some(false), Some(P1)), muted, NOW, none(),
+                Some(Watching), vec![AudioModeOff(P1), SetVolume(P1, Volume::Old), Mute(P1, false), SwitchLight(true)], None),
+            case("watching tv off while playing muted", Watching, g(Some(false), true, Some(false), Some(P1)), muted, NOW, none(),
+                Some(Playing), vec![SwitchLight(false), AudioModeOn(P1), SetVolume(P1, Volume::SnapclientStoreOld), Mute(P1, true)], None),
+            case("playing tv on", Playing, g(Some(true), true, Some(false), Some(P1)), snap(true), NOW, none(),
+                Some(Watching), vec![AudioModeOff(P1), SetVolume(P1, Volume::Old), SwitchLight(true)], None),
+            case("playing tv on no addr", Playing, g(Some(true), true, Some(false), None), snap(true), NOW, none(),
+                Some(Watching), vec![SwitchLight(true)], None),
+            case("playing stopped", Playing, g(Some(false), true, Some(false), Some(P1)), snap(false), NOW, none(),
+                Some(SwitchOff), vec![AudioModeOff(P1), SetVolume(P1, Volume::Old)], None),
+            case("playing stopped no addr", Playing, g(Some(false), true, Some(false), None), snap(false), NOW, none(),
+                None, vec![], None),
+            case("playing avr unknown", Playing, g(Some(false), true, None, Some(P1)), snap(true), NOW, none(),
+                None, vec![AudioModeOn(P1), RequestPwrState(P1)], None),
+            case("playing avr standby no addr", Playing, g(None, true, Some(true), None), snap(true), NOW, none(),
+                None, vec![], None),
+            case("playing all good", Playing, g(Some(false), true, Some(false), Some(P1)), snap(true), NOW, none(),
+                None, vec![], None),
+            // Off
+            case("off tv on", Off, g(Some(true), false, None, Some(P1)), snap(false), NOW, none(),
+                Some(WaitForAudio), vec![SwitchAvr(true)], None),
+            case("off playing", Off, g(None, false, None, None), snap(true), NOW, none(),
+                Some(WaitForAudio), vec![SwitchAvr(true)], None),
+            case("off avr off by hand", Off, by_hand, snap(true), NOW, none(),
+                None, vec![], None),
+            case("off stays off", Off, g(Some(false), false, None, Some(P1)), snap(false), HANG, none(),
+                None, vec![], None),
+            // WaitForAudio
+            case("wait avr ready tv on", WaitForAudio, tv_src, snap(false), NOW, none(),
+                Some(Watching), vec![SwitchLight(true), ResendActiveSource(P1, 0x1000)], None),
+            case("wait avr ready tv on unknown source", WaitForAudio, g(Some(true), true, None, Some(P1)), snap(false), NOW, none(),
+                Some(Watching), vec![SwitchLight(true)], None),
+            case("wait avr ready playing no addr", WaitForAudio, g(Some(false), true, None, None), snap(true), NOW, none(),
+                None, vec![], None),
+            case("wait avr ready playing", WaitForAudio, g(Some(false), true, None, Some(P1)), snap(true), NOW, none(),
+                None, vec![AudioModeOn(P1)], Some(Query::AvrPower(P1))),
+            case("wait avr ready playing avr on", WaitForAudio, g(Some(false), true, None, Some(P1)), snap(true), NOW, pwr(Some(On)),
+                Some(Playing), vec![SetVolume(P1, Volume::SnapclientStoreOld)], None),
+            case("wait avr ready playing avr standby", WaitForAudio, g(Some(false), true, None, Some(P1)), snap(true), NOW, pwr(Some(Standby)),
+                None, vec![TurnOnAvr(P1)], None),
+            case("wait avr ready playing avr silent", WaitForAudio, g(Some(false), true, None, Some(P1)), snap(true), NOW, pwr(None),
+                None, vec![], None),
+            case("wait avr ready nothing to do", WaitForAudio, g(Some(false), true, None, Some(P1)), snap(false), NOW, none(),
+                Some(SwitchOff), vec![], None),
+            case("wait", WaitForAudio, g(Some(true), false, None, Some(P1)), snap(false), NOW, none(),
+                None, vec![], None),
+            case("wait long no addr", WaitForAudio, g(Some(true), false, None, None), snap(false), LONG, none(),
+                None, vec![], None),
+            case("wait long", WaitForAudio, g(Some(true), false, None, Some(P1)), snap(false), LONG, none(),
+                None, vec![], Some(Query::AvrPower(P1))),
+            case("wait long tv on avr on", WaitForAudio, g(Some(true), false, None, Some(P1)), snap(false), LONG, pwr(Some(On)),
+                None, vec![MarkAvrReady], None),
+            case("wait long tv on avr standby", WaitForAudio, g(Some(true), false, None, Some(P1)), snap(false), LONG, pwr(Some(Standby)),
+                None, vec![MarkAvrReady, TurnOnAvr(P1)], None),
+            case("wait long tv on avr silent", WaitForAudio, g(Some(true), false, None, Some(P1)), snap(false), LONG, pwr(None),
+                None, vec![TurnOnAvr(P1)], None),
+            case("wait long playing avr on", WaitForAudio, g(Some(false), false, None, Some(P1)), snap(true), LONG, pwr(Some(On)),
+                None, vec![MarkAvrReady], Some(Query::AudioMode(P1))),
+            case("wait long playing audio mode on", WaitForAudio, g(Some(false), false, None, Some(P1)), snap(true), LONG,
+                Answers { avr_power: Some(Some(On)), audio_mode: Some(true), avr_outlet: None },
+                None, vec![], None),
+            case("wait long playing audio mode off", WaitForAudio, g(Some(false), false, None, Some(P1)), snap(true), LONG,
+                Answers { avr_power: Some(Some(On)), audio_mode: Some(false), avr_outlet: None },
+                None, vec![AudioModeOn(P1)], None),
+            case("wait long playing avr standby", WaitForAudio, g(Some(false), false, None, Some(P1)), snap(true), LONG, pwr(Some(Standby)),
+                None, vec![MarkAvrReady, AudioModeOn(P1)], None),
+            case("wait long nothing", WaitForAudio, g(Some(false), false, None, Some(P1)), snap(false), LONG, pwr(Some(On)),
+                None, vec![MarkAvrReady], None),
+            // hang
+            case("wait hangs", WaitForAudio, g(Some(true), false, None, Some(P1)), snap(false), HANG, none(),
+                None, vec![], Some(Query::AvrOutlet)),
+            case("wait hangs outlet off", WaitForAudio, g(Some(true), false, None, Some(P1)), snap(false), HANG, outlet(Some(false)),
+                Some(Off), vec![], None),
+            case("wait hangs outlet on", WaitForAudio, g(Some(true), false, None, Some(P1)), snap(false), HANG, outlet(Some(true)),
+                Some(SwitchOff), vec![StandbyAvr(P1), RequestPwrState(P1)], None),
+            case("avr has pwr hangs outlet error", AVRHasPwr, g(None, false, None, None), snap(false), HANG, outlet(None),
+                Some(SwitchOff), vec![], None),
+            // AVRHasPwr
+            case("avr has pwr no addr", AVRHasPwr, g(None, false, None, None), snap(false), NOW, none(),
+                None, vec![], None),
+            case("avr has pwr unknown", AVRHasPwr, g(None, false, None, Some(P1)), snap(false), NOW, none(),
+                None, vec![], Some(Query::AvrPower(P1))),
+            case("avr has pwr standby", AVRHasPwr, g(None, false, None, Some(P1)), snap(false), NOW, pwr(Some(Standby)),
+                Some(SwitchOff), vec![], None),
+            case("avr has pwr on", AVRHasPwr, g(None, false, None, Some(P1)), snap(false), NOW, pwr(Some(On)),
+                Some(WaitForAudio), vec![], None),
+            case("avr has pwr silent", AVRHasPwr, g(None, false, None, Some(P1)), snap(false), NOW, pwr(None),
+                None, vec![], None),
+            case("avr has pwr known on", AVRHasPwr, g(None, false, Some(false), Some(P1)), snap(false), NOW, none(),
+                Some(WaitForAudio), vec![GivePhysAddr(P1)], None),
+            case("avr has pwr known on no addr", AVRHasPwr, g(None, false, Some(false), None), snap(false), NOW, none(),
+                Some(WaitForAudio), vec![], None),
+            case("avr has pwr known standby", AVRHasPwr, g(None, false, Some(true), None), snap(false), NOW, none(),
+                Some(SwitchOff), vec![], None),
+            // SwitchOff
+            case("switch off", SwitchOff, g(Some(false), true, None, Some(P1)), snap(false), NOW, none(),
+                None, vec![], Some(Query::AvrOutlet)),
+            case("switch off outlet error", SwitchOff, g(Some(false), true, None, Some(P1)), snap(false), NOW, outlet(None),
+                None, vec![], None),
+            case("switch off outlet off", SwitchOff, g(Some(false), true, None, Some(P1)), snap(false), NOW, outlet(Some(false)),
+                Some(Off), vec![], None),
+            case("switch off avr standby", SwitchOff, g(Some(false), true, Some(true), Some(P1)), snap(false), NOW, outlet(Some(true)),
+                Some(Off), vec![SwitchAvr(false)], None),
+            case("switch off avr on", SwitchOff, g(Some(false), true, Some(false), Some(P1)), snap(false), NOW, outlet(Some(true)),
+                None, vec![StandbyAvr(P1), RequestPwrState(P1)], None),
+            case("switch off avr on no addr", SwitchOff, g(Some(false), true, None, None), snap(false), NOW, outlet(Some(true)),
+                None, vec![], None),
+        ];
+        for c in cases {
+            let s = without_logs(step(
+                c.state,
+                &c.g,
+                c.snap,
+                c.timers,
+                &c.answers,
+            ));
+            assert_eq!(
+                s,
+                Step {
+                    next: c.next,
+                    actions: c.actions,
+                    ask: c.ask
+                },
+                "{}",
+                c.name
+            );
+        }
+    }
+
+    /// call step like the main loop would
+    fn resolve(
+        state: MediaState,
+        g: &GState,
+        snap: Snapcast,
+        timers: Timers,
+        mut reply: impl FnMut(Query, &mut Answers),
+    ) -> (Option<MediaState>, Vec<Action>) {
+        let mut answers = Answers::default();
+        let mut actions = Vec::new();
+        loop {
+            let s = step(
+                state,
+                g,
+                snap,
+                timers,
+                &answers,
+            );
+            actions.extend(s.actions);
+            match s.ask {
+                Some(q) => reply(q, &mut answers),
+                None => return (s.next, actions),
+            }
+        }
+    }
+
+    #[test]
+    fn queries_do_not_repeat_actions() {
+        let (next, actions) = resolve(
+            WaitForAudio,
+            &g(Some(false), false, None, Some(P1)),
+            snap(true),
+            LONG,
+            |q, a| match q {
+                Query::AvrPower(_) => a.avr_power = Some(Some(On)),
+                Query::AudioMode(_) => a.audio_mode = Some(false),
+                Query::AvrOutlet => unreachable!(),
+            },
+        );
+        assert_eq!(next, None);
+        assert_eq!(
+            actions,
+            vec![
+                Log(Level::Warn, "WaitForAudio takes too long".to_string()),
+                MarkAvrReady,
+                AudioModeOn(P1)
+            ]
+        );
+    }
+
+    #[test]
+    fn hang_is_logged_once() {
+        let (next, actions) = resolve(
+            AVRHasPwr,
+            &g(None, false, Some(true), Some(P1)),
+            snap(false),
+            HANG,
+            |_, a| a.avr_outlet = Some(Some(true)),
+        );
+        assert_eq!(next, Some(Off));
+        assert_eq!(
+            actions,
+            vec![
+                Log(Level::Error, "Hang in State AVRHasPwr".to_string()),
+                Log(Level::Info, "Off but AVR on. Standby: Some(true)".to_string()),
+                SwitchAvr(false)
+            ]
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! Ending the threads on shutdown.
+//!
+//! Threads check [Stop::is_stopped]. Those blocked in a syscall register a waker with [Stop::on_stop].
+use log::warn;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+type Waker = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+pub struct Stop {
+    stopped: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+    threads: Mutex<Vec<(&'static str, JoinHandle<()>)>>,
+}
+impl Stop {
+    pub fn new() -> Arc<Stop> {
+        Arc::default()
+    }
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+    /// call `f` on stop. Right away if already stopped
+    pub fn on_stop(&self, f: impl FnOnce() + Send + 'static) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if self.is_stopped() {
+            drop(wakers);
+            f();
+        } else {
+            wakers.push(Box::new(f));
+        }
+    }
+    /// spawn a thread that is joined by [Stop::join]
+    pub fn spawn(&self, name: &'static str, f: impl FnOnce() + Send + 'static) {
+        let t = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(f)
+            .expect("spawn thread");
+        self.threads.lock().unwrap().push((name, t));
+    }
+    /// tell all threads to end
+    pub fn stop(&self) {
+        let wakers = {
+            let mut w = self.wakers.lock().unwrap();
+            self.stopped.store(true, Ordering::Relaxed);
+            std::mem::take(&mut *w)
+        };
+        for w in wakers {
+            w();
+        }
+    }
+    /// wait for the threads to end. Gives up on the ones still running after `timeout`
+    pub fn join(&self, timeout: Duration) {
+        let end = Instant::now() + timeout;
+        let mut threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        while !threads.is_empty() && Instant::now() < end {
+            let (done, running) = threads.into_iter().partition(|(_, t)| t.is_finished());
+            threads = running;
+            let done: Vec<_> = done;
+            for (name, t) in done {
+                if t.join().is_err() {
+                    warn!("thread {} panicked", name);
+                }
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        for (name, _) in threads {
+            warn!("thread {} did not stop", name);
+        }
+    }
+}
@@ -0,0 +1,762 @@
+//! An in-process CEC bus.
+//!
+//! Each [VirtualAdapter] is a device on the bus with its own physical address.
+//! Like `/dev/cecX` an adapter can be opened multiple times, each [VirtualCec] handle has its own mode and queues.
+//! The core messages the kernel answers on its own are answered by the adapter.
+use crate::cec::{CecBus, Frame, Waker, REPLY_TIMEOUT};
+use cec_linux::{
+    CecEvent, CecEventStateChange, CecLogAddrMask, CecLogAddrType, CecLogAddrs, CecLogicalAddress,
+    CecModeFollower, CecModeInitiator, CecOpcode, CecPhysicalAddress, PollFlags, PollTimeout,
+    VendorID, Version,
+};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+struct Shared {
+    bus: Mutex<Bus>,
+    /// notified on every change
+    cond: Condvar,
+}
+struct Bus {
+    adapters: Vec<Adapter>,
+    handles: Vec<Handle>,
+    reply_timeout: Duration,
+}
+struct Adapter {
+    phys_addr: CecPhysicalAddress,
+    addr_type: CecLogAddrType,
+    /// what set_log requested
+    log: Option<Log>,
+    claimed: Option<CecLogicalAddress>,
+}
+struct Log {
+    osd_name: String,
+    vendor_id: u32,
+    version: Version,
+}
+struct Handle {
+    adapter: usize,
+    follower: CecModeFollower,
+    rx: VecDeque<Frame>,
+    events: VecDeque<CecEventStateChange>,
+    /// request_data in progress: (to, opcode, wait_for)
+    waiting: Option<(CecLogicalAddress, u8, u8)>,
+    reply: Option<Frame>,
+    open: bool,
+    /// poll returns empty, see [CecBus::waker]
+    woken: bool,
+}
+
+/// A CEC bus that only exists in memory
+#[derive(Clone)]
+pub struct VirtualBus(Arc<Shared>);
+
+/// A device on a [VirtualBus]
+#[derive(Clone)]
+pub struct VirtualAdapter {
+    bus: Arc<Shared>,
+    id: usize,
+}
+
+/// An open handle on a [VirtualAdapter]
+pub struct VirtualCec {
+    bus: Arc<Shared>,
+    id: usize,
+}
+
+impl Default for VirtualBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl VirtualBus {
+    pub fn new() -> VirtualBus {
+        VirtualBus(Arc::new(Shared {
+            bus: Mutex::new(Bus {
+                adapters: Vec::new(),
+                handles: Vec::new(),
+                reply_timeout: REPLY_TIMEOUT,
+            }),
+            cond: Condvar::new(),
+        }))
+    }
+    /// Plug in a new device. `addr_type` is what it claims on `set_log`
+    pub fn add_adapter(
+        &self,
+        phys_addr: CecPhysicalAddress,
+        addr_type: CecLogAddrType,
+    ) -> VirtualAdapter {
+        let mut bus = self.0.bus.lock().unwrap();
+        bus.adapters.push(Adapter {
+            phys_addr,
+            addr_type,
+            log: None,
+            claimed: None,
+        });
+        VirtualAdapter {
+            bus: Arc::clone(&self.0),
+            id: bus.adapters.len() - 1,
+        }
+    }
+}
+
+impl VirtualAdapter {
+    /// like opening `/dev/cecX`
+    pub fn open(&self) -> VirtualCec {
+        let mut bus = self.bus.bus.lock().unwrap();
+        bus.handles.push(Handle {
+            adapter: self.id,
+            follower: CecModeFollower::RepliesOnly,
+            rx: VecDeque::new(),
+            events: VecDeque::new(),
+            waiting: None,
+            reply: None,
+            open: true,
+            woken: false,
+        });
+        VirtualCec {
+            bus: Arc::clone(&self.bus),
+            id: bus.handles.len() - 1,
+        }
+    }
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn phys_addr(&self) -> CecPhysicalAddress {
+        self.bus.bus.lock().unwrap().adapters[self.id].phys_addr
+    }
+    /// Like a hotplug with a new EDID.
+    ///
+    /// As with the kernel, the claimed address is lost and claimed again with the new physical address.
+    pub fn set_phys(&self, phys_addr: CecPhysicalAddress) {
+        self.bus.bus.lock().unwrap().set_phys(self.id, phys_addr);
+        self.bus.cond.notify_all();
+    }
+}
+
+impl Bus {
+    fn set_phys(&mut self, id: usize, phys_addr: CecPhysicalAddress) {
+        let a = &mut self.adapters[id];
+        if a.phys_addr == phys_addr {
+            return;
+        }
+        if a.phys_addr != CecPhysicalAddress::INVALID || phys_addr == CecPhysicalAddress::INVALID {
+            a.phys_addr = CecPhysicalAddress::INVALID;
+            a.claimed = None;
+            self.state_change(id);
+        }
+        if phys_addr != CecPhysicalAddress::INVALID {
+            self.adapters[id].phys_addr = phys_addr;
+            self.state_change(id);
+            if self.adapters[id].log.is_some() {
+                self.claim(id);
+            }
+        }
+    }
+    /// claim the first free address for the adapters type
+    fn claim(&mut self, id: usize) {
+        use CecLogicalAddress::*;
+        let candidates: &[CecLogicalAddress] = match self.adapters[id].addr_type {
+            CecLogAddrType::TV => &[Tv],
+            CecLogAddrType::RECORD => &[Record1, Record2, Record3],
+            CecLogAddrType::TUNER => &[Tuner1, Tuner2, Tuner3, Tuner4],
+            CecLogAddrType::PLAYBACK => &[Playback1, Playback2, Playback3],
+            CecLogAddrType::AUDIOSYSTEM => &[Audiosystem],
+            CecLogAddrType::SPECIFIC => &[Specific],
+            CecLogAddrType::UNREGISTERED => &[],
+        };
+        let addr = candidates
+            .iter()
+            .copied()
+            .find(|&c| {
+                !self
+                    .adapters
+                    .iter()
+                    .enumerate()
+                    .any(|(i, a)| i != id && a.claimed == Some(c))
+            })
+            .unwrap_or(UnregisteredBroadcast);
+        self.adapters[id].claimed = Some(addr);
+        self.state_change(id);
+        if addr != UnregisteredBroadcast {
+            // the kernel announces the new address
+            let report = self.report_phys_addr(id);
+            self.send(id, report);
+        }
+    }
+    /// queue a state change event on all handles of the adapter
+    fn state_change(&mut self, id: usize) {
+        let a = &self.adapters[id];
+        let ev = CecEventStateChange {
+            phys_addr: a.phys_addr,
+            log_addr_mask: match a.claimed {
+                None => CecLogAddrMask::empty(),
+                Some(CecLogicalAddress::UnregisteredBroadcast) => CecLogAddrMask::Unregistered,
+                Some(l) => CecLogAddrMask::from_bits_truncate(1 << u8::from(l)),
+            },
+        };
+        for h in self
+            .handles
+            .iter_mut()
+            .filter(|h| h.open && h.adapter == id)
+        {
+            h.events.push_back(ev);
+        }
+    }
+    fn report_phys_addr(&self, id: usize) -> Frame {
+        let a = &self.adapters[id];
+        let [hi, lo] = a.phys_addr.to_bytes();
+        let prim = match a.addr_type {
+            CecLogAddrType::TV => 0,
+            CecLogAddrType::RECORD => 1,
+            CecLogAddrType::TUNER => 3,
+            CecLogAddrType::PLAYBACK => 4,
+            CecLogAddrType::AUDIOSYSTEM => 5,
+            CecLogAddrType::SPECIFIC => 7,
+            CecLogAddrType::UNREGISTERED => 6,
+        };
+        Frame::new(
+            a.claimed
+                .unwrap_or(CecLogicalAddress::UnregisteredBroadcast),
+            CecLogicalAddress::UnregisteredBroadcast,
+            CecOpcode::ReportPhysicalAddr.into(),
+            &[hi, lo, prim],
+        )
+    }
+    /// Put a frame on the bus. Returns false if it was not acknowledged
+    fn send(&mut self, src: usize, frame: Frame) -> bool {
+        let mut queue = VecDeque::from([(src, frame)]);
+        let mut first = None;
+        while let Some((src, frame)) = queue.pop_front() {
+            let to = frame.destination();
+            let acked = frame.is_broadcast()
+                || self
+                    .adapters
+                    .iter()
+                    .enumerate()
+                    .any(|(i, a)| i != src && a.claimed == Some(to));
+            first.get_or_insert(acked);
+            if !acked {
+                continue;
+            }
+            for h in self.handles.iter_mut().filter(|h| h.open) {
+                let recipient = h.adapter != src
+                    && (frame.is_broadcast() || self.adapters[h.adapter].claimed == Some(to));
+                let keep = match h.follower {
+                    CecModeFollower::MonitorAll => true,
+                    CecModeFollower::Monitor => h.adapter == src || recipient,
+                    CecModeFollower::RepliesOnly => false,
+                    _ => recipient,
+                };
+                // like the kernel, replies may be broadcast (e.g. DeviceVendorId)
+                if recipient {
+                    if let Some((from, opcode, wait_for)) = h.waiting {
+                        let is_reply = frame.initiator() == from
+                            && match frame.opcode() {
+                                Some(Ok(CecOpcode::FeatureAbort)) => {
+                                    frame.parameters().first() == Some(&opcode)
+                                }
+                                Some(o) => o.map_or_else(|e| e, u8::from) == wait_for,
+                                None => false,
+                            };
+                        if is_reply {
+                            h.waiting = None;
+                            h.reply = Some(frame.clone());
+                        }
+                    }
+                }
+                if keep {
+                    h.rx.push_back(frame.clone());
+                }
+            }
+            // core processing
+            for (i, a) in self.adapters.iter().enumerate() {
+                if i == src || frame.is_broadcast() || a.claimed != Some(to) {
+                    continue;
+                }
+                let log = match &a.log {
+                    Some(l) => l,
+                    None => continue,
+                };
+                let from = frame.initiator();
+                let reply = match frame.opcode() {
+                    Some(Ok(CecOpcode::GivePhysicalAddr)) => self.report_phys_addr(i),
+                    Some(Ok(CecOpcode::GiveOsdName)) => Frame::new(
+                        to,
+                        from,
+                        CecOpcode::SetOsdName.into(),
+                        log.osd_name.as_bytes(),
+                    ),
+                    Some(Ok(CecOpcode::GetCecVersion)) => Frame::new(
+                        to,
+                        from,
+                        CecOpcode::CecVersion.into(),
+                        &[log.version.into()],
+                    ),
+                    Some(Ok(CecOpcode::GiveDeviceVendorId)) if log.vendor_id != VendorID::NONE => {
+                        Frame::new(
+                            to,
+                            CecLogicalAddress::UnregisteredBroadcast,
+                            CecOpcode::DeviceVendorId.into(),
+                            &log.vendor_id.to_be_bytes()[1..],
+                        )
+                    }
+                    _ => continue,
+                };
+                queue.push_back((i, reply));
+            }
+        }
+        first.unwrap_or(false)
+    }
+}
+
+impl VirtualCec {
+    fn lock(&self) -> MutexGuard<'_, Bus> {
+        self.bus.bus.lock().unwrap()
+    }
+    /// put any frame on the bus, even with opcodes unknown to cec_linux
+    pub fn transmit_frame(&self, frame: Frame) -> std::io::Result<()> {
+        let mut bus = self.lock();
+        self.send(&mut bus, frame)
+    }
+    fn handle<'a>(&self, bus: &'a mut Bus) -> &'a mut Handle {
+        &mut bus.handles[self.id]
+    }
+    /// check `from` and put the frame on the bus
+    fn send(&self, bus: &mut Bus, frame: Frame) -> std::io::Result<()> {
+        let adapter = bus.handles[self.id].adapter;
+        let from = frame.initiator();
+        if from != CecLogicalAddress::UnregisteredBroadcast
+            && bus.adapters[adapter].claimed != Some(from)
+        {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+        if bus.send(adapter, frame) {
+            self.bus.cond.notify_all();
+            Ok(())
+        } else {
+            Err(std::io::Error::other("NACK"))
+        }
+    }
+    /// block until `ready` or the timeout elapsed
+    fn wait<'a>(
+        &'a self,
+        mut bus: MutexGuard<'a, Bus>,
+        timeout: Option<Duration>,
+        ready: impl Fn(&Handle) -> bool,
+    ) -> MutexGuard<'a, Bus> {
+        let end = timeout.map(|t| Instant::now() + t);
+        while !ready(&bus.handles[self.id]) {
+            bus = match end {
+                None => self.bus.cond.wait(bus).unwrap(),
+                Some(end) => {
+                    let now = Instant::now();
+                    if now >= end {
+                        break;
+                    }
+                    self.bus.cond.wait_timeout(bus, end - now).unwrap().0
+                }
+            };
+        }
+        bus
+    }
+}
+impl Drop for VirtualCec {
+    fn drop(&mut self) {
+        let mut bus = self.bus.bus.lock().unwrap_or_else(|e| e.into_inner());
+        let h = self.handle(&mut bus);
+        h.open = false;
+        h.rx.clear();
+        h.events.clear();
+    }
+}
+
+impl CecBus for VirtualCec {
+    fn set_mode(
+        &self,
+        _initiator: CecModeInitiator,
+        follower: CecModeFollower,
+    ) -> std::io::Result<()> {
+        self.handle(&mut self.lock()).follower = follower;
+        Ok(())
+    }
+    fn set_log(&self, log: CecLogAddrs) -> std::io::Result<()> {
+        let mut bus = self.lock();
+        let adapter = bus.handles[self.id].adapter;
+        // CecLogAddrs::default() requests no address, CecLogAddrs::new() one (unclaimed) address
+        if log.addresses().is_empty() {
+            bus.adapters[adapter].log = None;
+            if bus.adapters[adapter].claimed.take().is_some() {
+                bus.state_change(adapter);
+            }
+        } else {
+            if bus.adapters[adapter].log.is_some() {
+                return Err(std::io::Error::from_raw_os_error(16)); //EBUSY
+            }
+            bus.adapters[adapter].log = Some(Log {
+                osd_name: log.osd_name.to_string(),
+                vendor_id: log.vendor_id,
+                version: log.cec_version,
+            });
+            if bus.adapters[adapter].phys_addr != CecPhysicalAddress::INVALID {
+                bus.claim(adapter);
+            }
+        }
+        self.bus.cond.notify_all();
+        Ok(())
+    }
+    fn get_log(&self) -> std::io::Result<Vec<CecLogicalAddress>> {
+        let bus = self.lock();
+        Ok(bus.adapters[bus.handles[self.id].adapter]
+            .claimed
+            .into_iter()
+            .collect())
+    }
+    /// the virtual adapters have the `PHYS_ADDR` capability
+    fn set_phys(&self, phys_addr: CecPhysicalAddress) -> std::io::Result<()> {
+        let mut bus = self.lock();
+        let adapter = bus.handles[self.id].adapter;
+        bus.set_phys(adapter, phys_addr);
+        self.bus.cond.notify_all();
+        Ok(())
+    }
+    fn transmit_data(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: CecOpcode,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        self.transmit_raw(from, to, opcode.into(), data)
+    }
+    fn request_data(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: CecOpcode,
+        data: &[u8],
+        wait_for: CecOpcode,
+    ) -> std::io::Result<Vec<u8>> {
+        let timeout = self.lock().reply_timeout;
+        // like the kernel, a FeatureAbort is returned as data
+        self.request_raw(from, to, opcode.into(), data, wait_for.into(), timeout)
+            .map(|f| f.parameters().to_vec())
+    }
+    fn transmit_raw(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: u8,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let mut bus = self.lock();
+        self.send(&mut bus, Frame::new(from, to, opcode, data))
+    }
+    fn request_raw(
+        &self,
+        from: CecLogicalAddress,
+        to: CecLogicalAddress,
+        opcode: u8,
+        data: &[u8],
+        wait_for: u8,
+        timeout: Duration,
+    ) -> std::io::Result<Frame> {
+        let mut bus = self.lock();
+        let h = self.handle(&mut bus);
+        h.waiting = Some((to, opcode, wait_for));
+        h.reply = None;
+        if let Err(e) = self.send(&mut bus, Frame::new(from, to, opcode, data)) {
+            self.handle(&mut bus).waiting = None;
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, e));
+        }
+        let mut bus = self.wait(bus, Some(timeout), |h| h.reply.is_some());
+        let h = self.handle(&mut bus);
+        h.waiting = None;
+        h.reply
+            .take()
+            .ok_or_else(|| std::io::ErrorKind::TimedOut.into())
+    }
+    fn poll(&self, events: PollFlags, timeout: PollTimeout) -> std::io::Result<PollFlags> {
+        let ready = |h: &Handle| {
+            let mut r = PollFlags::empty();
+            if !h.rx.is_empty() {
+                r |= events & (PollFlags::POLLIN | PollFlags::POLLRDNORM);
+            }
+            if !h.events.is_empty() {
+                r |= events & PollFlags::POLLPRI;
+            }
+            r
+        };
+        // negative is infinite
+        let timeout = u64::try_from(i32::from(timeout))
+            .ok()
+            .map(Duration::from_millis);
+        let bus = self.wait(self.lock(), timeout, |h| h.woken || !ready(h).is_empty());
+        let h = &bus.handles[self.id];
+        Ok(if h.woken { PollFlags::empty() } else { ready(h) })
+    }
+    fn waker(&self) -> std::io::Result<Waker> {
+        let (bus, id) = (Arc::clone(&self.bus), self.id);
+        Ok(Box::new(move || {
+            bus.bus.lock().unwrap_or_else(|e| e.into_inner()).handles[id].woken = true;
+            bus.cond.notify_all();
+        }))
+    }
+    fn get_event(&self) -> std::io::Result<CecEvent> {
+        let mut bus = self.wait(self.lock(), None, |h| !h.events.is_empty());
+        let ev = self.handle(&mut bus).events.pop_front().unwrap();
+        Ok(CecEvent::StateChange(ev))
+    }
+    fn rec(&self) -> std::io::Result<Frame> {
+        let mut bus = self.wait(self.lock(), None, |h| !h.rx.is_empty());
+        Ok(self.handle(&mut bus).rx.pop_front().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cec_linux::CecPrimDevType;
+    use std::io::ErrorKind;
+    use CecLogicalAddress::*;
+
+    /// an adapter with a claimed address whose handle follows everything sent to it
+    fn device(
+        bus: &VirtualBus,
+        phys_addr: u16,
+        addr_type: CecLogAddrType,
+        prim: CecPrimDevType,
+        vendor_id: u32,
+        osd_name: &str,
+    ) -> (VirtualAdapter, VirtualCec) {
+        let adapter = bus.add_adapter(CecPhysicalAddress::from_num(phys_addr), addr_type);
+        let cec = adapter.open();
+        cec.set_mode(CecModeInitiator::Send, CecModeFollower::All)
+            .unwrap();
+        cec.set_log(CecLogAddrs::new(
+            vendor_id,
+            Version::V1_4,
+            osd_name.as_bytes().into(),
+            &[prim],
+            &[addr_type],
+        ))
+        .unwrap();
+        (adapter, cec)
+    }
+    fn tv(bus: &VirtualBus) -> VirtualCec {
+        let sony = 0x080046;
+        device(bus, 0, CecLogAddrType::TV, CecPrimDevType::TV, sony, "TV").1
+    }
+    fn player(bus: &VirtualBus, phys_addr: u16) -> VirtualCec {
+        let (addr_type, prim) = (CecLogAddrType::PLAYBACK, CecPrimDevType::PLAYBACK);
+        device(bus, phys_addr, addr_type, prim, VendorID::NONE, "pi").1
+    }
+    /// the frames that are queued right now
+    fn received(cec: &VirtualCec) -> Vec<Frame> {
+        std::mem::take(&mut cec.handle(&mut cec.lock()).rx).into()
+    }
+
+    #[test]
+    fn claim() {
+        let bus = VirtualBus::new();
+        let tv = tv(&bus);
+        let p1 = player(&bus, 0x3000);
+        let p2 = player(&bus, 0x3100);
+        assert_eq!(tv.get_log().unwrap(), [Tv]);
+        assert_eq!(p1.get_log().unwrap(), [Playback1]);
+        assert_eq!(p2.get_log().unwrap(), [Playback2]);
+        // the kernel announces each claimed address
+        assert_eq!(
+            received(&tv),
+            [
+                Frame::new(Playback1, UnregisteredBroadcast, 0x84, &[0x30, 0x00, 4]),
+                Frame::new(Playback2, UnregisteredBroadcast, 0x84, &[0x31, 0x00, 4]),
+            ]
+        );
+        let CecEvent::StateChange(ev) = p1.get_event().unwrap() else {
+            panic!("no state change");
+        };
+        assert_eq!(ev.phys_addr, CecPhysicalAddress::from_num(0x3000));
+    }
+
+    #[test]
+    fn delivery() {
+        let bus = VirtualBus::new();
+        let (tv_adapter, tv) = device(&bus, 0, CecLogAddrType::TV, CecPrimDevType::TV, 0, "TV");
+        let p1 = player(&bus, 0x3000);
+        let (p2_adapter, p2) = device(
+            &bus,
+            0x3100,
+            CecLogAddrType::PLAYBACK,
+            CecPrimDevType::PLAYBACK,
+            VendorID::NONE,
+            "pi",
+        );
+        for c in [&tv, &p1, &p2] {
+            received(c);
+        }
+        let replies = p2_adapter.open();
+        let monitor = tv_adapter.open();
+        monitor
+            .set_mode(CecModeInitiator::None, CecModeFollower::Monitor)
+            .unwrap();
+
+        p1.transmit_data(Playback1, Tv, CecOpcode::ImageViewOn, &[])
+            .unwrap();
+        let frame = Frame::new(Playback1, Tv, CecOpcode::ImageViewOn.into(), &[]);
+        assert_eq!(received(&tv), std::slice::from_ref(&frame));
+        assert_eq!(received(&monitor), [frame]);
+        assert!(received(&p1).is_empty(), "not to the sender");
+        assert!(received(&p2).is_empty(), "not to others");
+        assert!(received(&replies).is_empty(), "not in RepliesOnly mode");
+        // the monitor also sees what its adapter sends
+        tv.transmit_data(Tv, Playback1, CecOpcode::GiveOsdName, &[])
+            .unwrap();
+        assert_eq!(received(&monitor).len(), 2, "request and reply");
+        assert_eq!(received(&tv).len(), 1, "the reply");
+        assert_eq!(received(&p1).len(), 1, "the request");
+
+        // not acknowledged
+        let e = p1.transmit_data(Playback1, Audiosystem, CecOpcode::GiveAudioStatus, &[]);
+        assert_eq!(e.unwrap_err().to_string(), "NACK");
+        assert!(received(&monitor).is_empty());
+        // not our address
+        let e = p1.transmit_data(Playback2, Tv, CecOpcode::ImageViewOn, &[]);
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(received(&tv).is_empty());
+        // opcodes unknown to cec_linux
+        p2.transmit_frame(Frame::new(Playback2, Playback1, 0xfe, &[1]))
+            .unwrap();
+        assert_eq!(
+            received(&p1),
+            [Frame::new(Playback2, Playback1, 0xfe, &[1])]
+        );
+    }
+
+    #[test]
+    fn broadcast() {
+        let bus = VirtualBus::new();
+        let tv = tv(&bus);
+        let p1 = player(&bus, 0x3000);
+        let p2 = player(&bus, 0x3100);
+        for c in [&tv, &p1, &p2] {
+            received(c);
+        }
+        tv.transmit_data(Tv, UnregisteredBroadcast, CecOpcode::Standby, &[])
+            .unwrap();
+        let frame = Frame::new(Tv, UnregisteredBroadcast, CecOpcode::Standby.into(), &[]);
+        assert_eq!(received(&p1), std::slice::from_ref(&frame));
+        assert_eq!(received(&p2), [frame]);
+        assert!(received(&tv).is_empty(), "not to the sender");
+
+        // an unregistered device can broadcast, but is not answered directly
+        let u = bus.add_adapter(CecPhysicalAddress::INVALID, CecLogAddrType::UNREGISTERED);
+        let u = u.open();
+        u.transmit_data(
+            UnregisteredBroadcast,
+            UnregisteredBroadcast,
+            CecOpcode::GetMenuLanguage,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(received(&tv).len(), 1);
+    }
+
+    #[test]
+    fn core_replies() {
+        let bus = VirtualBus::new();
+        let tv = tv(&bus);
+        let p1 = player(&bus, 0x3000);
+        let ask = |to, opcode, wait_for| tv.request_data(Tv, to, opcode, &[], wait_for);
+        assert_eq!(
+            ask(
+                Playback1,
+                CecOpcode::GivePhysicalAddr,
+                CecOpcode::ReportPhysicalAddr
+            )
+            .unwrap(),
+            [0x30, 0x00, 4]
+        );
+        assert_eq!(
+            ask(Playback1, CecOpcode::GiveOsdName, CecOpcode::SetOsdName).unwrap(),
+            b"pi"
+        );
+        assert_eq!(
+            ask(Playback1, CecOpcode::GetCecVersion, CecOpcode::CecVersion).unwrap(),
+            [u8::from(Version::V1_4)]
+        );
+        // broadcast, so the player sees it too
+        received(&p1);
+        let vendor = p1.request_data(
+            Playback1,
+            Tv,
+            CecOpcode::GiveDeviceVendorId,
+            &[],
+            CecOpcode::DeviceVendorId,
+        );
+        assert_eq!(vendor.unwrap(), [0x08, 0x00, 0x46]);
+        assert_eq!(
+            received(&p1),
+            [Frame::new(
+                Tv,
+                UnregisteredBroadcast,
+                0x87,
+                &[0x08, 0x00, 0x46]
+            )]
+        );
+        // without a vendor id nothing is sent
+        let e = tv.request_raw(
+            Tv,
+            Playback1,
+            CecOpcode::GiveDeviceVendorId.into(),
+            &[],
+            CecOpcode::DeviceVendorId.into(),
+            Duration::from_millis(50),
+        );
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::TimedOut);
+        // the followers see the requests, not the replies from their own adapter
+        assert!(received(&p1)
+            .iter()
+            .all(|f| f.initiator() == Tv && f.destination() == Playback1));
+    }
+
+    #[test]
+    fn waker() {
+        let bus = VirtualBus::new();
+        let tv = tv(&bus);
+        let p1 = player(&bus, 0x3000);
+        received(&tv);
+        let timeout = PollTimeout::from(20u16);
+        assert_eq!(
+            tv.poll(PollFlags::POLLIN, timeout).unwrap(),
+            PollFlags::empty()
+        );
+        p1.transmit_data(Playback1, Tv, CecOpcode::ImageViewOn, &[])
+            .unwrap();
+        assert_eq!(
+            tv.poll(PollFlags::POLLIN, timeout).unwrap(),
+            PollFlags::POLLIN
+        );
+        received(&tv);
+
+        let wake = tv.waker().unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                wake();
+            });
+            let f = tv.poll(PollFlags::POLLIN, PollTimeout::NONE).unwrap();
+            assert_eq!(f, PollFlags::empty());
+        });
+        // for good, even with frames waiting
+        p1.transmit_data(Playback1, Tv, CecOpcode::ImageViewOn, &[])
+            .unwrap();
+        let f = tv.poll(PollFlags::POLLIN, PollTimeout::NONE).unwrap();
+        assert_eq!(f, PollFlags::empty());
+        assert_eq!(received(&tv).len(), 1);
+        // other handles are not woken
+        assert_eq!(
+            p1.poll(PollFlags::POLLIN, timeout).unwrap(),
+            PollFlags::empty()
+        );
+    }
+}
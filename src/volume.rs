@@ -0,0 +1,257 @@
+//! Absolute volume for the AVR.
+//!
+//! CEC 2.0 AVRs take `<Set Audio Volume Level>`. Whether this one does is asked with
+//! GiveFeatures. If it does not answer, the message is tried once and a FeatureAbort means no.
+//!
+//! Others only know VolumeUp and VolumeDown. Keys are pressed in batches. After each batch
+//! the volume is read back, so the target is reached even if the step per key press is not 0.5%.
+//! The step that was seen is kept for the next time.
+use crate::cec::{CecBus, REPLY_TIMEOUT};
+use cec_linux::{CecLogicalAddress, CecOpcode, CecUserControlCode};
+use log::{debug, info};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// close enough, in percent
+const TOLERANCE: u8 = 1;
+/// key presses before the volume is read again
+const MAX_BATCH: u32 = 16;
+/// give up after this long
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// batches without effect before giving up
+const MAX_STUCK: u8 = 3;
+/// percent per key press until one is seen
+const DEFAULT_STEP: f32 = 0.5;
+/// CEC 2.0 `<Set Audio Volume Level>`, unknown to cec_linux
+pub const SET_AUDIO_VOLUME_LEVEL: u8 = 0x73;
+/// in the first byte of the device features of `<Report Features>`
+pub const FEAT_SET_AUDIO_VOLUME_LEVEL: u8 = 0x01;
+
+/// Why the volume was not reached
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// the AVR does not answer GiveAudioStatus
+    NoStatus,
+    /// key presses failed
+    Cec(String),
+    /// stopped at this volume
+    GaveUp(u8),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoStatus => write!(f, "AVR does not report its audio status"),
+            Error::Cec(e) => write!(f, "key press failed: {}", e),
+            Error::GaveUp(v) => write!(f, "gave up at {}%", v),
+        }
+    }
+}
+
+/// Sets the volume and learns the step of a key press
+pub struct Controller {
+    /// percent per key press, as f32 bits
+    step: AtomicU32,
+    /// does the AVR take `<Set Audio Volume Level>`? None until known
+    level: Mutex<Option<bool>>,
+}
+impl Default for Controller {
+    fn default() -> Self {
+        Controller {
+            step: AtomicU32::new(DEFAULT_STEP.to_bits()),
+            level: Mutex::new(None),
+        }
+    }
+}
+impl Controller {
+    /// percent per key press
+    pub fn step(&self) -> f32 {
+        f32::from_bits(self.step.load(Ordering::Relaxed))
+    }
+    /// does the AVR take `<Set Audio Volume Level>`? None until known
+    pub fn has_level(&self) -> Option<bool> {
+        *self.level.lock().unwrap()
+    }
+    /// Bring the volume to `target` percent. Returns the volume reached.
+    ///
+    /// `old` gets the volume from before
+    pub fn set(
+        &self,
+        cec: &dyn CecBus,
+        from: CecLogicalAddress,
+        target: u8,
+        old: Option<&mut u8>,
+    ) -> Result<u8, Error> {
+        let (mut cur, muted) = audio_status(cec, from).ok_or(Error::NoStatus)?;
+        if let Some(o) = old {
+            *o = cur;
+        }
+        if muted {
+            // the AVR shows the volume it will restore. Changing it may unmute
+            debug!(target: "cec", "AVR is muted");
+        }
+        if cur.abs_diff(target) > TOLERANCE {
+            if let Some(now) = self.set_level(cec, from, target) {
+                if now.abs_diff(target) <= TOLERANCE {
+                    info!(target: "cec", volume = now; "volume {}% by Set Audio Volume Level", now);
+                    return Ok(now);
+                }
+                debug!(target: "cec", "Set Audio Volume Level reached {}%, pressing keys", now);
+                cur = now;
+            }
+        }
+        let end = Instant::now() + TIMEOUT;
+        let (mut presses, mut stuck) = (0u32, 0u8);
+        loop {
+            let diff = target as i16 - cur as i16;
+            if diff.unsigned_abs() <= TOLERANCE as u16 {
+                break;
+            }
+            if Instant::now() >= end || stuck >= MAX_STUCK {
+                return Err(Error::GaveUp(cur));
+            }
+            let key = if diff > 0 {
+                CecUserControlCode::VolumeUp
+            } else {
+                CecUserControlCode::VolumeDown
+            };
+            // rather too few than overshoot
+            let n = ((diff.unsigned_abs() as f32 / self.step()) as u32).clamp(1, MAX_BATCH);
+            for _ in 0..n {
+                cec.keypress(from, CecLogicalAddress::Audiosystem, key)
+                    .map_err(|e| Error::Cec(e.to_string()))?;
+            }
+            let now = audio_status(cec, from).ok_or(Error::NoStatus)?.0;
+            let d = now.abs_diff(cur) as u32;
+            if d == 0 {
+                stuck += 1;
+            } else {
+                stuck = 0;
+                self.learn(n, d);
+            }
+            presses += n;
+            let overshot = (target as i16 - now as i16).signum() == -diff.signum();
+            cur = now;
+            if overshot && n == 1 {
+                // as close as a key press gets
+                break;
+            }
+        }
+        info!(target: "cec", volume = cur, presses; "volume {}% after {} key presses", cur, presses);
+        Ok(cur)
+    }
+    /// Send `<Set Audio Volume Level>` if the AVR might take it. Returns the volume after it.
+    ///
+    /// While unknown, a FeatureAbort or a volume that did not move mean no
+    fn set_level(&self, cec: &dyn CecBus, from: CecLogicalAddress, target: u8) -> Option<u8> {
+        let mut level = self.level.lock().unwrap();
+        if level.is_none() {
+            *level = features(cec, from).map(|f| f & FEAT_SET_AUDIO_VOLUME_LEVEL != 0);
+            debug!(target: "cec", "AVR features: Set Audio Volume Level {:?}", *level);
+        }
+        let known = match *level {
+            Some(false) => return None,
+            Some(true) => true,
+            None => false,
+        };
+        let to = CecLogicalAddress::Audiosystem;
+        let sent = if known {
+            cec.transmit_raw(from, to, SET_AUDIO_VOLUME_LEVEL, &[target])
+        } else {
+            // an answer is not required, but a FeatureAbort is fast
+            match cec.request_raw(
+                from,
+                to,
+                SET_AUDIO_VOLUME_LEVEL,
+                &[target],
+                CecOpcode::ReportAudioStatus.into(),
+                REPLY_TIMEOUT,
+            ) {
+                Ok(f) if f.opcode() == Some(Ok(CecOpcode::FeatureAbort)) => {
+                    info!(target: "cec", "AVR does not take Set Audio Volume Level");
+                    *level = Some(false);
+                    return None;
+                }
+                Err(e) if e.kind() != std::io::ErrorKind::TimedOut => Err(e),
+                _ => Ok(()),
+            }
+        };
+        if let Err(e) = sent {
+            debug!(target: "cec", "Set Audio Volume Level failed: {}", e);
+            return None;
+        }
+        let now = audio_status(cec, from)?.0;
+        if !known {
+            let works = now.abs_diff(target) <= TOLERANCE;
+            info!(target: "cec", "AVR takes Set Audio Volume Level: {}", works);
+            *level = Some(works);
+        }
+        Some(now)
+    }
+    /// `presses` moved the volume by `moved` percent.
+    /// Single presses say little, as the volume is reported in whole percent
+    fn learn(&self, presses: u32, moved: u32) {
+        if presses < 2 {
+            return;
+        }
+        let seen = moved as f32 / presses as f32;
+        let step = (self.step() + seen) / 2.0;
+        debug!(target: "cec", "step per key press: {:.2}%", step);
+        self.step.store(step.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// The first byte of the device features from `<Report Features>`.
+/// None if the AVR does not answer, like CEC 1.4 devices
+fn features(cec: &dyn CecBus, from: CecLogicalAddress) -> Option<u8> {
+    let d = cec
+        .request_data(
+            from,
+            CecLogicalAddress::Audiosystem,
+            CecOpcode::GiveFeatures,
+            b"",
+            CecOpcode::ReportFeatures,
+        )
+        .ok()?;
+    parse_features(&d)
+}
+/// CEC version, all device types, RC profile and device features.
+/// The last two continue while bit 7 is set
+fn parse_features(d: &[u8]) -> Option<u8> {
+    let rc_profile = d.get(2..)?.iter().position(|b| b & 0x80 == 0)?;
+    d.get(3 + rc_profile).copied()
+}
+
+/// volume in percent and mute of the AVR
+pub fn audio_status(cec: &dyn CecBus, from: CecLogicalAddress) -> Option<(u8, bool)> {
+    let v = cec
+        .request_data(
+            from,
+            CecLogicalAddress::Audiosystem,
+            CecOpcode::GiveAudioStatus,
+            b"",
+            CecOpcode::ReportAudioStatus,
+        )
+        .ok()
+        .and_then(|d| d.first().copied())?;
+    debug!(target: "cec", "Vol is: Muted: {} Vol: {}%", v & 0x80 != 0, v & 0x7f);
+    Some((v & 0x7f, v & 0x80 != 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_features() {
+        assert_eq!(parse_features(&[6, 0x08, 0x00, 0x01]), Some(0x01));
+        assert_eq!(
+            parse_features(&[6, 0x08, 0x80, 0x00, 0x41, 0x00]),
+            Some(0x41)
+        );
+        // FeatureAbort of GiveFeatures
+        assert_eq!(parse_features(&[0xa5, 0x00]), None);
+        assert_eq!(parse_features(&[6, 0x08, 0x00]), None);
+    }
+}